@@ -0,0 +1,12 @@
+pub const EPERM: i32 = 1;
+pub const ENOENT: i32 = 2;
+pub const E2BIG: i32 = 7;
+pub const ENOMEM: i32 = 12;
+pub const EFAULT: i32 = 14;
+pub const EBUSY: i32 = 16;
+pub const EEXIST: i32 = 17;
+pub const EINVAL: i32 = 22;
+pub const ENOSPC: i32 = 28;
+pub const ERANGE: i32 = 34;
+pub const EDEADLK: i32 = 35;
+pub const EOPNOTSUPP: i32 = 95;
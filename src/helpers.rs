@@ -0,0 +1,460 @@
+//! Built-in helpers. Like their kernel counterparts they trust the pointers
+//! they are passed to be valid for the sizes their prototypes imply.
+#![allow(clippy::missing_safety_doc)]
+
+use crate::errno::*;
+use crate::kfunc::{KfuncProto, KfuncRegistry};
+use crate::map::StackTraceMap;
+use crate::vm::Vm;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::any::Any;
+use core::fmt;
+use core::slice;
+use proto::HelperProto;
+
+pub mod callback;
+pub mod map;
+pub mod printk;
+pub mod probe;
+pub mod proto;
+pub mod services;
+pub mod spin_lock;
+pub mod stack;
+pub mod string;
+pub mod task;
+pub mod timer;
+
+macro_rules! bpf_func {
+    ($($name:ident = $id:literal,)*) => {
+        /// Helper ids as listed by `__BPF_FUNC_MAPPER` in
+        /// include/uapi/linux/bpf.h.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub enum BpfFunc {
+            $($name = $id,)*
+        }
+
+        impl BpfFunc {
+            pub fn from_id(id: u32) -> Option<Self> {
+                match id {
+                    $($id => Some(BpfFunc::$name),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+bpf_func! {
+    Unspec = 0,
+    MapLookupElem = 1,
+    MapUpdateElem = 2,
+    MapDeleteElem = 3,
+    ProbeRead = 4,
+    KtimeGetNs = 5,
+    TracePrintk = 6,
+    GetPrandomU32 = 7,
+    GetSmpProcessorId = 8,
+    SkbStoreBytes = 9,
+    L3CsumReplace = 10,
+    L4CsumReplace = 11,
+    TailCall = 12,
+    CloneRedirect = 13,
+    GetCurrentPidTgid = 14,
+    GetCurrentUidGid = 15,
+    GetCurrentComm = 16,
+    GetCgroupClassid = 17,
+    SkbVlanPush = 18,
+    SkbVlanPop = 19,
+    SkbGetTunnelKey = 20,
+    SkbSetTunnelKey = 21,
+    PerfEventRead = 22,
+    Redirect = 23,
+    GetRouteRealm = 24,
+    PerfEventOutput = 25,
+    SkbLoadBytes = 26,
+    GetStackid = 27,
+    CsumDiff = 28,
+    SkbGetTunnelOpt = 29,
+    SkbSetTunnelOpt = 30,
+    SkbChangeProto = 31,
+    SkbChangeType = 32,
+    SkbUnderCgroup = 33,
+    GetHashRecalc = 34,
+    GetCurrentTask = 35,
+    ProbeWriteUser = 36,
+    CurrentTaskUnderCgroup = 37,
+    SkbChangeTail = 38,
+    SkbPullData = 39,
+    CsumUpdate = 40,
+    SetHashInvalid = 41,
+    GetNumaNodeId = 42,
+    SkbChangeHead = 43,
+    XdpAdjustHead = 44,
+    ProbeReadStr = 45,
+    GetSocketCookie = 46,
+    GetSocketUid = 47,
+    SetHash = 48,
+    Setsockopt = 49,
+    SkbAdjustRoom = 50,
+    RedirectMap = 51,
+    SkRedirectMap = 52,
+    SockMapUpdate = 53,
+    XdpAdjustMeta = 54,
+    PerfEventReadValue = 55,
+    PerfProgReadValue = 56,
+    Getsockopt = 57,
+    OverrideReturn = 58,
+    SockOpsCbFlagsSet = 59,
+    MsgRedirectMap = 60,
+    MsgApplyBytes = 61,
+    MsgCorkBytes = 62,
+    MsgPullData = 63,
+    Bind = 64,
+    XdpAdjustTail = 65,
+    SkbGetXfrmState = 66,
+    GetStack = 67,
+    SkbLoadBytesRelative = 68,
+    FibLookup = 69,
+    SockHashUpdate = 70,
+    MsgRedirectHash = 71,
+    SkRedirectHash = 72,
+    LwtPushEncap = 73,
+    LwtSeg6StoreBytes = 74,
+    LwtSeg6AdjustSrh = 75,
+    LwtSeg6Action = 76,
+    RcRepeat = 77,
+    RcKeydown = 78,
+    SkbCgroupId = 79,
+    GetCurrentCgroupId = 80,
+    GetLocalStorage = 81,
+    SkSelectReuseport = 82,
+    SkbAncestorCgroupId = 83,
+    SkLookupTcp = 84,
+    SkLookupUdp = 85,
+    SkRelease = 86,
+    MapPushElem = 87,
+    MapPopElem = 88,
+    MapPeekElem = 89,
+    MsgPushData = 90,
+    MsgPopData = 91,
+    RcPointerRel = 92,
+    SpinLock = 93,
+    SpinUnlock = 94,
+    SkFullsock = 95,
+    TcpSock = 96,
+    SkbEcnSetCe = 97,
+    GetListenerSock = 98,
+    SkcLookupTcp = 99,
+    TcpCheckSyncookie = 100,
+    SysctlGetName = 101,
+    SysctlGetCurrentValue = 102,
+    SysctlGetNewValue = 103,
+    SysctlSetNewValue = 104,
+    Strtol = 105,
+    Strtoul = 106,
+    SkStorageGet = 107,
+    SkStorageDelete = 108,
+    SendSignal = 109,
+    TcpGenSyncookie = 110,
+    SkbOutput = 111,
+    ProbeReadUser = 112,
+    ProbeReadKernel = 113,
+    ProbeReadUserStr = 114,
+    ProbeReadKernelStr = 115,
+    TcpSendAck = 116,
+    SendSignalThread = 117,
+    Jiffies64 = 118,
+    ReadBranchRecords = 119,
+    GetNsCurrentPidTgid = 120,
+    XdpOutput = 121,
+    GetNetnsCookie = 122,
+    GetCurrentAncestorCgroupId = 123,
+    SkAssign = 124,
+    KtimeGetBootNs = 125,
+    SeqPrintf = 126,
+    SeqWrite = 127,
+    SkCgroupId = 128,
+    SkAncestorCgroupId = 129,
+    RingbufOutput = 130,
+    RingbufReserve = 131,
+    RingbufSubmit = 132,
+    RingbufDiscard = 133,
+    RingbufQuery = 134,
+    CsumLevel = 135,
+    SkcToTcp6Sock = 136,
+    SkcToTcpSock = 137,
+    SkcToTcpTimewaitSock = 138,
+    SkcToTcpRequestSock = 139,
+    SkcToUdp6Sock = 140,
+    GetTaskStack = 141,
+    LoadHdrOpt = 142,
+    StoreHdrOpt = 143,
+    ReserveHdrOpt = 144,
+    InodeStorageGet = 145,
+    InodeStorageDelete = 146,
+    DPath = 147,
+    CopyFromUser = 148,
+    SnprintfBtf = 149,
+    SeqPrintfBtf = 150,
+    SkbCgroupClassid = 151,
+    RedirectNeigh = 152,
+    PerCpuPtr = 153,
+    ThisCpuPtr = 154,
+    RedirectPeer = 155,
+    TaskStorageGet = 156,
+    TaskStorageDelete = 157,
+    GetCurrentTaskBtf = 158,
+    BprmOptsSet = 159,
+    KtimeGetCoarseNs = 160,
+    ImaInodeHash = 161,
+    SockFromFile = 162,
+    CheckMtu = 163,
+    ForEachMapElem = 164,
+    Snprintf = 165,
+    SysBpf = 166,
+    BtfFindByNameKind = 167,
+    SysClose = 168,
+    TimerInit = 169,
+    TimerSetCallback = 170,
+    TimerStart = 171,
+    TimerCancel = 172,
+    GetFuncIp = 173,
+    GetAttachCookie = 174,
+    TaskPtRegs = 175,
+    GetBranchSnapshot = 176,
+    TraceVprintk = 177,
+    SkcToUnixSock = 178,
+    KallsymsLookupName = 179,
+    FindVma = 180,
+    Loop = 181,
+    Strncmp = 182,
+    GetFuncArg = 183,
+    GetFuncRet = 184,
+    GetFuncArgCnt = 185,
+    GetRetval = 186,
+    SetRetval = 187,
+    XdpGetBuffLen = 188,
+    XdpLoadBytes = 189,
+    XdpStoreBytes = 190,
+    CopyFromUserTask = 191,
+    SkbSetTstamp = 192,
+    ImaFileHash = 193,
+    KptrXchg = 194,
+    MapLookupPercpuElem = 195,
+    SkcToMptcpSock = 196,
+    DynptrFromMem = 197,
+    RingbufReserveDynptr = 198,
+    RingbufSubmitDynptr = 199,
+    RingbufDiscardDynptr = 200,
+    DynptrRead = 201,
+    DynptrWrite = 202,
+    DynptrData = 203,
+    TcpRawGenSyncookieIpv4 = 204,
+    TcpRawGenSyncookieIpv6 = 205,
+    TcpRawCheckSyncookieIpv4 = 206,
+    TcpRawCheckSyncookieIpv6 = 207,
+    KtimeGetTaiNs = 208,
+    UserRingbufDrain = 209,
+}
+
+/// A helper callable from programs, receiving the VM state and r1-r5.
+pub type Helper = dyn FnMut(&mut Vm, u64, u64, u64, u64, u64) -> u64;
+
+#[derive(Default)]
+pub struct HelperRegistry {
+    helpers: BTreeMap<u32, Box<Helper>>,
+    protos: BTreeMap<u32, HelperProto>,
+    kfuncs: KfuncRegistry,
+}
+
+impl fmt::Debug for HelperRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HelperRegistry")
+            .field("helpers", &self.helpers.keys())
+            .field("protos", &self.protos)
+            .finish_non_exhaustive()
+    }
+}
+
+impl HelperRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a registry populated with every built-in helper.
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::new();
+        registry.register(BpfFunc::MapLookupElem, |vm, r1, r2, r3, r4, r5| unsafe {
+            map::map_lookup_elem(vm, r1, r2, r3, r4, r5)
+        });
+        registry.register(BpfFunc::MapUpdateElem, |vm, r1, r2, r3, r4, r5| unsafe {
+            map::map_update_elem(vm, r1, r2, r3, r4, r5)
+        });
+        registry.register(BpfFunc::MapDeleteElem, |vm, r1, r2, r3, r4, r5| unsafe {
+            map::map_delete_elem(vm, r1, r2, r3, r4, r5)
+        });
+        registry.register(BpfFunc::MapPushElem, |vm, r1, r2, r3, r4, r5| unsafe {
+            map::map_push_elem(vm, r1, r2, r3, r4, r5)
+        });
+        registry.register(BpfFunc::MapPeekElem, |vm, r1, r2, r3, r4, r5| unsafe {
+            map::map_peek_elem(vm, r1, r2, r3, r4, r5)
+        });
+        registry.register(BpfFunc::SpinLock, |_, lock, _, _, _, _| unsafe {
+            spin_lock::spin_lock(lock as *mut u32);
+            0
+        });
+        registry.register(BpfFunc::SpinUnlock, |_, lock, _, _, _, _| unsafe {
+            spin_lock::spin_unlock(lock as *mut u32);
+            0
+        });
+        registry.register(BpfFunc::ProbeRead, |vm, r1, r2, r3, r4, r5| unsafe {
+            probe::probe_read(vm, r1, r2, r3, r4, r5)
+        });
+        registry.register(BpfFunc::ProbeReadKernel, |vm, r1, r2, r3, r4, r5| unsafe {
+            probe::probe_read_kernel(vm, r1, r2, r3, r4, r5)
+        });
+        registry.register(BpfFunc::ProbeReadUser, |vm, r1, r2, r3, r4, r5| unsafe {
+            probe::probe_read_user(vm, r1, r2, r3, r4, r5)
+        });
+        registry.register(BpfFunc::ProbeReadStr, |vm, r1, r2, r3, r4, r5| unsafe {
+            probe::probe_read_str(vm, r1, r2, r3, r4, r5)
+        });
+        registry.register(
+            BpfFunc::ProbeReadKernelStr,
+            |vm, r1, r2, r3, r4, r5| unsafe {
+                probe::probe_read_kernel_str(vm, r1, r2, r3, r4, r5)
+            },
+        );
+        registry.register(BpfFunc::ProbeReadUserStr, |vm, r1, r2, r3, r4, r5| unsafe {
+            probe::probe_read_user_str(vm, r1, r2, r3, r4, r5)
+        });
+        registry.register(BpfFunc::KtimeGetNs, services::ktime_get_ns);
+        registry.register(BpfFunc::KtimeGetBootNs, services::ktime_get_boot_ns);
+        registry.register(BpfFunc::GetPrandomU32, services::get_prandom_u32);
+        registry.register(BpfFunc::GetSmpProcessorId, services::get_smp_processor_id);
+        registry.register(BpfFunc::GetNumaNodeId, services::get_numa_node_id);
+        registry.register(BpfFunc::GetCurrentPidTgid, task::get_current_pid_tgid);
+        registry.register(BpfFunc::GetCurrentUidGid, task::get_current_uid_gid);
+        registry.register(BpfFunc::GetCurrentComm, |vm, r1, r2, r3, r4, r5| unsafe {
+            task::get_current_comm(vm, r1, r2, r3, r4, r5)
+        });
+        registry.register(BpfFunc::GetCurrentTask, task::get_current_task);
+        registry.register(BpfFunc::TracePrintk, |vm, r1, r2, r3, r4, r5| unsafe {
+            printk::trace_printk(vm, r1, r2, r3, r4, r5)
+        });
+        registry.register(BpfFunc::Snprintf, |vm, r1, r2, r3, r4, r5| unsafe {
+            printk::snprintf(vm, r1, r2, r3, r4, r5)
+        });
+        registry.register(BpfFunc::Strtol, |vm, r1, r2, r3, r4, r5| unsafe {
+            string::bpf_strtol(vm, r1, r2, r3, r4, r5)
+        });
+        registry.register(BpfFunc::Strtoul, |vm, r1, r2, r3, r4, r5| unsafe {
+            string::bpf_strtoul(vm, r1, r2, r3, r4, r5)
+        });
+        registry.register(BpfFunc::Strncmp, |vm, r1, r2, r3, r4, r5| unsafe {
+            string::bpf_strncmp(vm, r1, r2, r3, r4, r5)
+        });
+        registry.register(BpfFunc::TimerInit, |vm, r1, r2, r3, r4, r5| unsafe {
+            timer::timer_init(vm, r1, r2, r3, r4, r5)
+        });
+        registry.register(BpfFunc::TimerSetCallback, |vm, r1, r2, r3, r4, r5| unsafe {
+            timer::timer_set_callback(vm, r1, r2, r3, r4, r5)
+        });
+        registry.register(BpfFunc::TimerStart, |vm, r1, r2, r3, r4, r5| unsafe {
+            timer::timer_start(vm, r1, r2, r3, r4, r5)
+        });
+        registry.register(BpfFunc::TimerCancel, |vm, r1, r2, r3, r4, r5| unsafe {
+            timer::timer_cancel(vm, r1, r2, r3, r4, r5)
+        });
+        registry.register(BpfFunc::GetStackid, |vm, ctx, map, flags, _, _| {
+            let mut unwinder = match vm.unwinder.take() {
+                Some(unwinder) => unwinder,
+                None => return -EOPNOTSUPP as i64 as u64,
+            };
+            let ret = match vm
+                .map(map)
+                .and_then(|map| (map as &mut dyn Any).downcast_mut::<StackTraceMap>())
+            {
+                Some(map) => stack::get_stackid(&mut *unwinder, ctx, map, flags),
+                None => -EINVAL as i64,
+            };
+            vm.unwinder = Some(unwinder);
+            ret as u64
+        });
+        registry.register(BpfFunc::GetStack, |vm, ctx, buf, size, flags, _| {
+            let buf = unsafe { slice::from_raw_parts_mut(buf as *mut u8, size as u32 as usize) };
+            match &mut vm.unwinder {
+                Some(unwinder) => stack::get_stack(&mut **unwinder, ctx, buf, flags) as u64,
+                None => {
+                    buf.fill(0);
+                    -EOPNOTSUPP as i64 as u64
+                }
+            }
+        });
+        registry
+    }
+
+    /// Registers `helper` as `func` with the kernel's prototype for it,
+    /// replacing any previous one.
+    pub fn register<F>(&mut self, func: BpfFunc, helper: F)
+    where
+        F: FnMut(&mut Vm, u64, u64, u64, u64, u64) -> u64 + 'static,
+    {
+        self.register_id(func as u32, helper);
+        if let Some(proto) = proto::builtin(func) {
+            self.declare(func as u32, proto);
+        }
+    }
+
+    /// Registers `helper` under a raw id, which may lie outside `BpfFunc` for
+    /// helpers private to the host.
+    pub fn register_id<F>(&mut self, id: u32, helper: F)
+    where
+        F: FnMut(&mut Vm, u64, u64, u64, u64, u64) -> u64 + 'static,
+    {
+        self.helpers.insert(id, Box::new(helper));
+    }
+
+    /// Declares the prototype of helper `id`, for helpers registered with
+    /// `register_id` or ones differing from the kernel's.
+    pub fn declare(&mut self, id: u32, proto: HelperProto) {
+        self.protos.insert(id, proto);
+    }
+
+    /// Returns the prototype of helper `id`. `bpf_loop` and
+    /// `bpf_for_each_map_elem` always have one, as the interpreter provides
+    /// them.
+    pub fn proto(&self, id: u32) -> Option<HelperProto> {
+        match BpfFunc::from_id(id) {
+            Some(func @ (BpfFunc::Loop | BpfFunc::ForEachMapElem)) => proto::builtin(func),
+            _ => self.protos.get(&id).copied(),
+        }
+    }
+
+    pub fn unregister(&mut self, id: u32) -> bool {
+        self.protos.remove(&id);
+        self.helpers.remove(&id).is_some()
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        self.helpers.contains_key(&id)
+    }
+
+    /// Kernel functions, called with `BPF_PSEUDO_KFUNC_CALL` rather than by
+    /// helper id.
+    pub fn kfuncs(&mut self) -> &mut KfuncRegistry {
+        &mut self.kfuncs
+    }
+
+    /// Returns the prototype of kfunc `id`.
+    pub fn kfunc_proto(&self, id: u32) -> Option<&KfuncProto> {
+        self.kfuncs.proto(id)
+    }
+
+    /// Calls helper `id` with r1-r5, or returns `None` if none is registered.
+    pub fn call(&mut self, vm: &mut Vm, id: u32, args: [u64; 5]) -> Option<u64> {
+        let helper = self.helpers.get_mut(&id)?;
+        Some(helper(vm, args[0], args[1], args[2], args[3], args[4]))
+    }
+}
@@ -0,0 +1,87 @@
+//! Helpers taking a callback subprogram. Running the callback needs the
+//! interpreter, so they are dispatched by it rather than through the
+//! registry, with `call` re-entering the program at a function address
+//! loaded by `BPF_PSEUDO_FUNC`.
+
+use crate::errno::*;
+use crate::vm::Vm;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Runs the callback at a function address with r1-r5 and returns its r0.
+pub type Call<'a, E> = dyn FnMut(&mut Vm, u64, [u64; 5]) -> Result<u64, E> + 'a;
+
+/// Upper bound on `nr_loops`, as in kernel/bpf/bpf_iter.c.
+pub const BPF_MAX_LOOPS: u64 = 1 << 23;
+
+/// `long bpf_loop(u32 nr_loops, void *callback_fn, void *callback_ctx, u64 flags)`:
+/// calls `callback_fn(index, callback_ctx)` until it returns nonzero, and
+/// returns the number of iterations made.
+pub fn bpf_loop<E>(
+    vm: &mut Vm,
+    nr_loops: u64,
+    callback: u64,
+    ctx: u64,
+    flags: u64,
+    call: &mut Call<E>,
+) -> Result<u64, E> {
+    let nr_loops = nr_loops as u32 as u64;
+    if flags != 0 {
+        return Ok(-EINVAL as i64 as u64);
+    }
+    if nr_loops > BPF_MAX_LOOPS {
+        return Ok(-E2BIG as i64 as u64);
+    }
+    for i in 0..nr_loops {
+        if call(vm, callback, [i, ctx, 0, 0, 0])? != 0 {
+            return Ok(i + 1);
+        }
+    }
+    Ok(nr_loops)
+}
+
+/// `long bpf_for_each_map_elem(struct bpf_map *map, void *callback_fn, void *callback_ctx, u64 flags)`:
+/// calls `callback_fn(map, key, value, callback_ctx)` for each element until
+/// it returns nonzero, and returns the number of elements visited.
+///
+/// Keys are collected up front, so the callback may delete elements; ones
+/// deleted before being reached are skipped.
+pub fn for_each_map_elem<E>(
+    vm: &mut Vm,
+    handle: u64,
+    callback: u64,
+    ctx: u64,
+    flags: u64,
+    call: &mut Call<E>,
+) -> Result<u64, E> {
+    if flags != 0 {
+        return Ok(-EINVAL as i64 as u64);
+    }
+    let map = match vm.map(handle) {
+        Some(map) => map,
+        None => return Ok(-EINVAL as i64 as u64),
+    };
+    let key_size = map.def().key_size as usize;
+    let mut keys = Vec::new();
+    let mut key = vec![0; key_size];
+    loop {
+        match map.get_next_key(keys.last().map(Vec::as_slice), &mut key) {
+            Ok(()) => {}
+            Err(ENOENT) => break,
+            Err(err) => return Ok(-err as i64 as u64),
+        }
+        keys.push(key.clone());
+    }
+    let mut visited = 0;
+    for key in keys {
+        let value = match vm.map(handle).and_then(|map| map.lookup(&key)) {
+            Some(value) => value.as_mut_ptr() as u64,
+            None => continue,
+        };
+        visited += 1;
+        if call(vm, callback, [handle, key.as_ptr() as u64, value, ctx, 0])? != 0 {
+            break;
+        }
+    }
+    Ok(visited)
+}
@@ -0,0 +1,93 @@
+use crate::errno::*;
+use crate::map::Map;
+use crate::vm::Vm;
+use alloc::vec::Vec;
+use core::{ptr, slice};
+
+// Keys and values are copied in and out, since the program may pass
+// pointers into the storage of the map it operates on.
+
+unsafe fn key(map: &dyn Map, key: u64) -> Vec<u8> {
+    slice::from_raw_parts(key as *const u8, map.def().key_size as usize).to_vec()
+}
+
+unsafe fn value(map: &dyn Map, value: u64) -> Vec<u8> {
+    slice::from_raw_parts(value as *const u8, map.def().value_size as usize).to_vec()
+}
+
+fn ret(result: Result<(), i32>) -> u64 {
+    match result {
+        Ok(()) => 0,
+        Err(err) => -err as i64 as u64,
+    }
+}
+
+/// `void *bpf_map_lookup_elem(struct bpf_map *map, const void *key)`:
+/// returns a pointer into the value storage, or NULL.
+pub unsafe fn map_lookup_elem(vm: &mut Vm, map: u64, key_ptr: u64, _: u64, _: u64, _: u64) -> u64 {
+    let map = match vm.map(map) {
+        Some(map) => map,
+        None => return 0,
+    };
+    let key = key(map, key_ptr);
+    map.lookup(&key)
+        .map_or(0, |value| value.as_mut_ptr() as u64)
+}
+
+/// `long bpf_map_update_elem(struct bpf_map *map, const void *key, const void *value, u64 flags)`
+pub unsafe fn map_update_elem(
+    vm: &mut Vm,
+    map: u64,
+    key_ptr: u64,
+    value_ptr: u64,
+    flags: u64,
+    _: u64,
+) -> u64 {
+    let map = match vm.map(map) {
+        Some(map) => map,
+        None => return ret(Err(EINVAL)),
+    };
+    let (key, value) = (key(map, key_ptr), value(map, value_ptr));
+    ret(map.update(&key, &value, flags))
+}
+
+/// `long bpf_map_delete_elem(struct bpf_map *map, const void *key)`
+pub unsafe fn map_delete_elem(vm: &mut Vm, map: u64, key_ptr: u64, _: u64, _: u64, _: u64) -> u64 {
+    let map = match vm.map(map) {
+        Some(map) => map,
+        None => return ret(Err(EINVAL)),
+    };
+    let key = key(map, key_ptr);
+    ret(map.delete(&key))
+}
+
+/// `long bpf_map_push_elem(struct bpf_map *map, const void *value, u64 flags)`
+pub unsafe fn map_push_elem(
+    vm: &mut Vm,
+    map: u64,
+    value_ptr: u64,
+    flags: u64,
+    _: u64,
+    _: u64,
+) -> u64 {
+    let map = match vm.map(map) {
+        Some(map) => map,
+        None => return ret(Err(EINVAL)),
+    };
+    let value = value(map, value_ptr);
+    ret(map.push(&value, flags))
+}
+
+/// `long bpf_map_peek_elem(struct bpf_map *map, void *value)`
+pub unsafe fn map_peek_elem(vm: &mut Vm, map: u64, value_ptr: u64, _: u64, _: u64, _: u64) -> u64 {
+    let map = match vm.map(map) {
+        Some(map) => map,
+        None => return ret(Err(EINVAL)),
+    };
+    let mut value = value(map, value_ptr);
+    let result = map.peek(&mut value);
+    if result.is_ok() {
+        ptr::copy(value.as_ptr(), value_ptr as *mut u8, value.len());
+    }
+    ret(result)
+}
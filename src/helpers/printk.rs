@@ -0,0 +1,445 @@
+use super::probe::{self, MemoryProbe};
+use crate::errno::*;
+use crate::vm::Vm;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::slice;
+
+/// Longest message `bpf_trace_printk` emits, including the NUL.
+const TRACE_PRINTK_SIZE: usize = 1024;
+/// Bytes all `%s` arguments of one call may expand to together.
+const MAX_BPRINTF_BUF_LEN: usize = 512;
+const MAX_BPRINTF_VARARGS: usize = 12;
+/// Longest format string `bpf_snprintf` scans for its terminating NUL.
+const MAX_SNPRINTF_FMT_LEN: usize = 1024;
+/// Widest field a conversion may ask for, `FIELD_WIDTH_MAX` in
+/// lib/vsprintf.c.
+const FIELD_WIDTH_MAX: usize = (1 << 23) - 1;
+
+/// Receives each message written by `bpf_trace_printk`, the equivalent of
+/// the kernel's trace pipe.
+pub trait TraceSink {
+    fn write(&mut self, msg: &[u8]);
+}
+
+/// Where `format` writes: it keeps the first `limit` bytes and only counts
+/// the rest, so a long message costs no more than a short one.
+pub struct Output {
+    pub buf: Vec<u8>,
+    limit: usize,
+    /// Length of the whole message.
+    pub len: usize,
+}
+
+impl Output {
+    pub fn new(limit: usize) -> Self {
+        Output {
+            buf: Vec::new(),
+            limit,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, c: u8) {
+        self.extend(&[c]);
+    }
+
+    fn extend(&mut self, bytes: &[u8]) {
+        let room = self.limit - self.buf.len();
+        self.buf.extend_from_slice(&bytes[..bytes.len().min(room)]);
+        self.len += bytes.len();
+    }
+
+    fn fill(&mut self, c: u8, n: usize) {
+        let room = self.limit - self.buf.len();
+        self.buf.resize(self.buf.len() + n.min(room), c);
+        self.len += n;
+    }
+}
+
+#[derive(Default)]
+struct Spec {
+    left: bool,
+    zero: bool,
+    plus: bool,
+    space: bool,
+    width: usize,
+}
+
+impl Spec {
+    fn pad(&self, out: &mut Output, sign: &str, body: &[u8], numeric: bool) {
+        let fill = self.width.saturating_sub(sign.len() + body.len());
+        if self.left {
+            out.extend(sign.as_bytes());
+            out.extend(body);
+            out.fill(b' ', fill);
+        } else if self.zero && numeric {
+            out.extend(sign.as_bytes());
+            out.fill(b'0', fill);
+            out.extend(body);
+        } else {
+            out.fill(b' ', fill);
+            out.extend(sign.as_bytes());
+            out.extend(body);
+        }
+    }
+}
+
+struct Digits([u8; 24], usize);
+
+impl Write for Digits {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.0[self.1..self.1 + s.len()].copy_from_slice(s.as_bytes());
+        self.1 += s.len();
+        Ok(())
+    }
+}
+
+fn digits(args: core::fmt::Arguments) -> Digits {
+    let mut digits = Digits([0; 24], 0);
+    let _ = digits.write_fmt(args);
+    digits
+}
+
+/// Reads the NUL-terminated format string of at most `max` bytes the
+/// program passes, which the verifier proves to be in its memory.
+unsafe fn read_fmt(ptr: u64, max: usize, out: &mut Vec<u8>) {
+    if ptr == 0 {
+        return;
+    }
+    let ptr = ptr as *const u8;
+    for i in 0..max {
+        match *ptr.add(i) {
+            0 => break,
+            byte => out.push(byte),
+        }
+    }
+}
+
+/// Formats `fmt` (without its NUL) with `args` into `out`, accepting the
+/// kernel's printf subset: `%d %i %u %x %X` with optional `l`/`ll`, `%c`,
+/// `%s`, `%p %pK %px` and `%%`, each with an optional `[0 +-]*` flags and
+/// width prefix, the width at most `FIELD_WIDTH_MAX`. Pointers are printed
+/// as is rather than hashed.
+///
+/// `%s` arguments may point anywhere, so they are read through `probe`, and
+/// print as empty strings if unreadable or without a probe.
+pub fn format(
+    fmt: &[u8],
+    args: &[u64],
+    mut probe: Option<&mut (dyn MemoryProbe + 'static)>,
+    out: &mut Output,
+) -> Result<(), i32> {
+    let mut args = args.iter();
+    let mut str_budget = MAX_BPRINTF_BUF_LEN;
+    let mut i = 0;
+    while i < fmt.len() {
+        let c = fmt[i];
+        if !(c.is_ascii_graphic() || c.is_ascii_whitespace()) {
+            return Err(EINVAL);
+        }
+        i += 1;
+        if c != b'%' {
+            out.push(c);
+            continue;
+        }
+        if fmt.get(i) == Some(&b'%') {
+            out.push(b'%');
+            i += 1;
+            continue;
+        }
+        let mut spec = Spec::default();
+        while let Some(flag) = fmt.get(i) {
+            match flag {
+                b'-' => spec.left = true,
+                b'0' => spec.zero = true,
+                b'+' => spec.plus = true,
+                b' ' => spec.space = true,
+                _ => break,
+            }
+            i += 1;
+        }
+        while let Some(digit) = fmt.get(i).filter(|c| c.is_ascii_digit()) {
+            spec.width = (spec.width * 10 + (digit - b'0') as usize).min(FIELD_WIDTH_MAX + 1);
+            i += 1;
+        }
+        if spec.width > FIELD_WIDTH_MAX {
+            return Err(EINVAL);
+        }
+        let conv = *fmt.get(i).ok_or(EINVAL)?;
+        i += 1;
+        let arg = match conv {
+            b'c' | b's' | b'p' | b'l' | b'd' | b'i' | b'u' | b'x' | b'X' => {
+                *args.next().ok_or(EINVAL)?
+            }
+            _ => return Err(EINVAL),
+        };
+        match conv {
+            b'c' => spec.pad(out, "", &[arg as u8], false),
+            b's' => {
+                let mut s = Vec::new();
+                if let Some(probe) = probe.as_deref_mut() {
+                    s.resize(str_budget + 1, 0);
+                    let len = probe::read_str(probe, false, &mut s, arg).unwrap_or(1);
+                    s.truncate(len - 1);
+                }
+                str_budget -= s.len();
+                spec.pad(out, "", &s, false);
+            }
+            b'p' => {
+                if let Some(b'K' | b'x') = fmt.get(i) {
+                    i += 1;
+                } else if fmt.get(i).is_some_and(|c| c.is_ascii_alphanumeric()) {
+                    return Err(EINVAL);
+                }
+                let d = digits(format_args!("{:016x}", arg));
+                spec.pad(out, "", &d.0[..d.1], true);
+            }
+            _ => {
+                let mut conv = conv;
+                let long = conv == b'l';
+                if long {
+                    conv = *fmt.get(i).ok_or(EINVAL)?;
+                    i += 1;
+                    if conv == b'l' {
+                        conv = *fmt.get(i).ok_or(EINVAL)?;
+                        i += 1;
+                    }
+                }
+                let arg = if long { arg } else { arg as u32 as u64 };
+                let (sign, d) = match conv {
+                    b'd' | b'i' => {
+                        let arg = if long {
+                            arg as i64
+                        } else {
+                            arg as u32 as i32 as i64
+                        };
+                        let sign = if arg < 0 {
+                            "-"
+                        } else if spec.plus {
+                            "+"
+                        } else if spec.space {
+                            " "
+                        } else {
+                            ""
+                        };
+                        (sign, digits(format_args!("{}", arg.unsigned_abs())))
+                    }
+                    b'u' => ("", digits(format_args!("{}", arg))),
+                    b'x' => ("", digits(format_args!("{:x}", arg))),
+                    b'X' => ("", digits(format_args!("{:X}", arg))),
+                    _ => return Err(EINVAL),
+                };
+                spec.pad(out, sign, &d.0[..d.1], true);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `long bpf_trace_printk(const char *fmt, u32 fmt_size, ...)`: formats up to
+/// three arguments and writes the message to the VM's trace sink, returning
+/// its length.
+pub unsafe fn trace_printk(vm: &mut Vm, fmt: u64, fmt_size: u64, a1: u64, a2: u64, a3: u64) -> u64 {
+    let fmt = slice::from_raw_parts(fmt as *const u8, fmt_size as u32 as usize);
+    let fmt = match fmt.iter().position(|c| *c == 0) {
+        Some(len) => &fmt[..len],
+        None => return -EINVAL as i64 as u64,
+    };
+    let mut msg = Output::new(TRACE_PRINTK_SIZE - 1);
+    if let Err(err) = format(fmt, &[a1, a2, a3], vm.probe.as_deref_mut(), &mut msg) {
+        return -err as i64 as u64;
+    }
+    if let Some(sink) = &mut vm.trace {
+        sink.write(&msg.buf);
+    }
+    msg.buf.len() as u64
+}
+
+/// `long bpf_snprintf(char *str, u32 str_size, const char *fmt, u64 *data, u32 data_len)`:
+/// formats the `data_len / 8` arguments in `data` into `str`, truncating and
+/// NUL-terminating it, and returns the length of the full output plus one.
+pub unsafe fn snprintf(
+    vm: &mut Vm,
+    str: u64,
+    str_size: u64,
+    fmt: u64,
+    data: u64,
+    data_len: u64,
+) -> u64 {
+    let (str_size, data_len) = (str_size as u32 as usize, data_len as u32 as usize);
+    if !data_len.is_multiple_of(8)
+        || data_len > MAX_BPRINTF_VARARGS * 8
+        || data_len != 0 && data == 0
+    {
+        return -EINVAL as i64 as u64;
+    }
+    let mut fmt_buf = Vec::new();
+    read_fmt(fmt, MAX_SNPRINTF_FMT_LEN, &mut fmt_buf);
+    if fmt_buf.len() == MAX_SNPRINTF_FMT_LEN {
+        return -EINVAL as i64 as u64;
+    }
+    let args: Vec<u64> = (0..data_len / 8)
+        .map(|i| (data as *const u64).add(i).read_unaligned())
+        .collect();
+    let mut msg = Output::new(str_size.saturating_sub(1));
+    if let Err(err) = format(&fmt_buf, &args, vm.probe.as_deref_mut(), &mut msg) {
+        return -err as i64 as u64;
+    }
+    if str_size > 0 {
+        let str = slice::from_raw_parts_mut(str as *mut u8, str_size);
+        let len = msg.buf.len();
+        str[..len].copy_from_slice(&msg.buf);
+        str[len] = 0;
+    }
+    msg.len as u64 + 1
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
+    /// Reads host memory, which the tests' strings are in.
+    struct Host;
+
+    impl MemoryProbe for Host {
+        fn read_kernel(&mut self, dst: &mut [u8], src: u64) -> bool {
+            if src == 0 {
+                return false;
+            }
+            for (i, byte) in dst.iter_mut().enumerate() {
+                *byte = unsafe { *(src as *const u8).add(i) };
+                if *byte == 0 {
+                    break;
+                }
+            }
+            true
+        }
+
+        fn read_user(&mut self, dst: &mut [u8], src: u64) -> bool {
+            self.read_kernel(dst, src)
+        }
+    }
+
+    fn fmt(fmt: &str, args: &[u64]) -> Result<String, i32> {
+        let mut out = Output::new(usize::MAX);
+        format(fmt.as_bytes(), args, Some(&mut Host), &mut out)?;
+        Ok(String::from_utf8(out.buf).unwrap())
+    }
+
+    #[test]
+    fn conversions() {
+        let s = b"rcore\0";
+        let args = [
+            -5i64 as u64,
+            0xdead_beef_cafe,
+            s.as_ptr() as u64,
+            b'!' as u64,
+        ];
+        assert_eq!(
+            fmt("%d %llx %s%c", &args).unwrap(),
+            "-5 deadbeefcafe rcore!"
+        );
+        assert_eq!(
+            fmt("%u|%x|%X", &[u64::MAX; 3]).unwrap(),
+            "4294967295|ffffffff|FFFFFFFF"
+        );
+        assert_eq!(
+            fmt("%ld %lu %lli", &[-1i64 as u64, 1 << 40, 7]).unwrap(),
+            "-1 1099511627776 7"
+        );
+        assert_eq!(
+            fmt("[%5d|%-5d|%05d|%+d]", &[42, 42, 42, 42]).unwrap(),
+            "[   42|42   |00042|+42]"
+        );
+        assert_eq!(
+            fmt("%p %pK, %px", &[0x1000, 0x10, 1]).unwrap(),
+            "0000000000001000 0000000000000010, 0000000000000001"
+        );
+        assert_eq!(
+            fmt("100%% %6s|%s", &[s.as_ptr() as u64, 0]).unwrap(),
+            "100%  rcore|"
+        );
+        // strings are not read without a probe
+        let mut out = Output::new(usize::MAX);
+        format(b"[%s]", &[s.as_ptr() as u64], None, &mut out).unwrap();
+        assert_eq!(out.buf, b"[]");
+    }
+
+    #[test]
+    fn rejected() {
+        assert_eq!(fmt("%d %d", &[1]), Err(EINVAL));
+        assert_eq!(fmt("%n", &[1]), Err(EINVAL));
+        assert_eq!(fmt("%lllx", &[1]), Err(EINVAL));
+        assert_eq!(fmt("%.2d", &[1]), Err(EINVAL));
+        assert_eq!(fmt("%pZ", &[1]), Err(EINVAL));
+        assert_eq!(fmt("\x07", &[]), Err(EINVAL));
+        assert_eq!(fmt("%99999999999999999999d", &[1]), Err(EINVAL));
+        assert_eq!(fmt("%8388608d", &[1]), Err(EINVAL));
+    }
+
+    #[test]
+    fn truncated() {
+        // only what fits is kept, however wide the field
+        let mut out = Output::new(4);
+        format(b"%8388607d|", &[1], None, &mut out).unwrap();
+        assert_eq!((&out.buf[..], out.len), (&b"    "[..], 8388608));
+        let mut out = Output::new(4);
+        format(b"ab%-6c", &[b'c' as u64], None, &mut out).unwrap();
+        assert_eq!((&out.buf[..], out.len), (&b"abc "[..], 8));
+    }
+
+    struct Sink(Rc<RefCell<Vec<Vec<u8>>>>);
+
+    impl TraceSink for Sink {
+        fn write(&mut self, msg: &[u8]) {
+            self.0.borrow_mut().push(msg.to_vec());
+        }
+    }
+
+    #[test]
+    fn helpers() {
+        let msgs = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = Vm {
+            trace: Some(Box::new(Sink(msgs.clone()))),
+            probe: Some(Box::new(Host)),
+            ..Default::default()
+        };
+        let fmt = b"pid %d: %s\n\0";
+        let comm = b"init\0";
+        let ret = unsafe {
+            trace_printk(
+                &mut vm,
+                fmt.as_ptr() as u64,
+                fmt.len() as u64,
+                1,
+                comm.as_ptr() as u64,
+                0,
+            )
+        };
+        assert_eq!(ret, 12);
+        assert_eq!(msgs.borrow()[0], b"pid 1: init\n");
+        let ret = unsafe { trace_printk(&mut vm, fmt.as_ptr() as u64, 4, 0, 0, 0) };
+        assert_eq!(ret, -EINVAL as i64 as u64);
+
+        let mut buf = [0xffu8; 8];
+        let data = [12345678u64];
+        let fmt = b"%d\0";
+        let ret = unsafe {
+            snprintf(
+                &mut vm,
+                buf.as_mut_ptr() as u64,
+                8,
+                fmt.as_ptr() as u64,
+                data.as_ptr() as u64,
+                8,
+            )
+        };
+        assert_eq!(ret, 9);
+        assert_eq!(&buf, b"1234567\0");
+        let ret = unsafe { snprintf(&mut vm, 0, 0, fmt.as_ptr() as u64, data.as_ptr() as u64, 4) };
+        assert_eq!(ret, -EINVAL as i64 as u64);
+    }
+}
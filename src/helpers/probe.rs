@@ -0,0 +1,259 @@
+use crate::errno::*;
+use crate::vm::Vm;
+use core::slice;
+
+/// Copies memory on behalf of the `bpf_probe_read_*` helpers without
+/// faulting on bad addresses, e.g. by installing a fixup for the copy.
+pub trait MemoryProbe {
+    /// Fills `dst` from kernel address `src`, or returns false if any byte
+    /// is unreadable.
+    fn read_kernel(&mut self, dst: &mut [u8], src: u64) -> bool;
+    /// Fills `dst` from user address `src`, or returns false if any byte is
+    /// unreadable.
+    fn read_user(&mut self, dst: &mut [u8], src: u64) -> bool;
+    /// Decides which address space the legacy `bpf_probe_read` and
+    /// `bpf_probe_read_str` read from.
+    fn is_user_addr(&mut self, addr: u64) -> bool {
+        (addr as i64) >= 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Space {
+    Kernel,
+    User,
+    Compat,
+}
+
+fn read(probe: &mut dyn MemoryProbe, space: Space, dst: &mut [u8], src: u64) -> bool {
+    let user = match space {
+        Space::Kernel => false,
+        Space::User => true,
+        Space::Compat => probe.is_user_addr(src),
+    };
+    if user {
+        probe.read_user(dst, src)
+    } else {
+        probe.read_kernel(dst, src)
+    }
+}
+
+/// Copies a NUL-terminated string of at most `dst.len() - 1` bytes from
+/// `src`, always terminating `dst`, like `strncpy_from_kernel_nofault`.
+/// Returns the length copied including the NUL.
+pub fn read_str(
+    probe: &mut dyn MemoryProbe,
+    user: bool,
+    dst: &mut [u8],
+    src: u64,
+) -> Result<usize, i32> {
+    read_str_in(
+        probe,
+        if user { Space::User } else { Space::Kernel },
+        dst,
+        src,
+    )
+}
+
+fn read_str_in(
+    probe: &mut dyn MemoryProbe,
+    space: Space,
+    dst: &mut [u8],
+    src: u64,
+) -> Result<usize, i32> {
+    let last = match dst.len().checked_sub(1) {
+        Some(last) => last,
+        None => return Ok(0),
+    };
+    for i in 0..last {
+        if !read(probe, space, &mut dst[i..i + 1], src.wrapping_add(i as u64)) {
+            dst.fill(0);
+            return Err(EFAULT);
+        }
+        if dst[i] == 0 {
+            return Ok(i + 1);
+        }
+    }
+    dst[last] = 0;
+    Ok(dst.len())
+}
+
+unsafe fn probe_read_common(vm: &mut Vm, space: Space, dst: u64, size: u64, src: u64) -> u64 {
+    let dst = slice::from_raw_parts_mut(dst as *mut u8, size as u32 as usize);
+    let ok = match &mut vm.probe {
+        Some(probe) => read(&mut **probe, space, dst, src),
+        None => false,
+    };
+    if ok {
+        0
+    } else {
+        dst.fill(0);
+        -EFAULT as i64 as u64
+    }
+}
+
+unsafe fn probe_read_str_common(vm: &mut Vm, space: Space, dst: u64, size: u64, src: u64) -> u64 {
+    let dst = slice::from_raw_parts_mut(dst as *mut u8, size as u32 as usize);
+    let ret = match &mut vm.probe {
+        Some(probe) => read_str_in(&mut **probe, space, dst, src),
+        None => {
+            dst.fill(0);
+            Err(EFAULT)
+        }
+    };
+    match ret {
+        Ok(len) => len as u64,
+        Err(err) => -err as i64 as u64,
+    }
+}
+
+/// `long bpf_probe_read(void *dst, u32 size, const void *unsafe_ptr)`
+pub unsafe fn probe_read(vm: &mut Vm, dst: u64, size: u64, src: u64, _: u64, _: u64) -> u64 {
+    probe_read_common(vm, Space::Compat, dst, size, src)
+}
+
+/// `long bpf_probe_read_kernel(void *dst, u32 size, const void *unsafe_ptr)`:
+/// returns 0, or `-EFAULT` with `dst` zeroed.
+pub unsafe fn probe_read_kernel(vm: &mut Vm, dst: u64, size: u64, src: u64, _: u64, _: u64) -> u64 {
+    probe_read_common(vm, Space::Kernel, dst, size, src)
+}
+
+/// `long bpf_probe_read_user(void *dst, u32 size, const void *unsafe_ptr)`
+pub unsafe fn probe_read_user(vm: &mut Vm, dst: u64, size: u64, src: u64, _: u64, _: u64) -> u64 {
+    probe_read_common(vm, Space::User, dst, size, src)
+}
+
+/// `long bpf_probe_read_str(void *dst, u32 size, const void *unsafe_ptr)`
+pub unsafe fn probe_read_str(vm: &mut Vm, dst: u64, size: u64, src: u64, _: u64, _: u64) -> u64 {
+    probe_read_str_common(vm, Space::Compat, dst, size, src)
+}
+
+/// `long bpf_probe_read_kernel_str(void *dst, u32 size, const void *unsafe_ptr)`:
+/// returns the string length including the NUL, or `-EFAULT` with `dst`
+/// zeroed.
+pub unsafe fn probe_read_kernel_str(
+    vm: &mut Vm,
+    dst: u64,
+    size: u64,
+    src: u64,
+    _: u64,
+    _: u64,
+) -> u64 {
+    probe_read_str_common(vm, Space::Kernel, dst, size, src)
+}
+
+/// `long bpf_probe_read_user_str(void *dst, u32 size, const void *unsafe_ptr)`
+pub unsafe fn probe_read_user_str(
+    vm: &mut Vm,
+    dst: u64,
+    size: u64,
+    src: u64,
+    _: u64,
+    _: u64,
+) -> u64 {
+    probe_read_str_common(vm, Space::User, dst, size, src)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Kernel memory at 0xffff_0000_0000_1000 and user memory at 0x1000.
+    struct Fake {
+        kernel: Vec<u8>,
+        user: Vec<u8>,
+    }
+
+    fn copy(mem: &[u8], base: u64, dst: &mut [u8], src: u64) -> bool {
+        let start = match src.checked_sub(base) {
+            Some(start) => start as usize,
+            None => return false,
+        };
+        match mem.get(start..start + dst.len()) {
+            Some(src) => {
+                dst.copy_from_slice(src);
+                true
+            }
+            None => false,
+        }
+    }
+
+    impl MemoryProbe for Fake {
+        fn read_kernel(&mut self, dst: &mut [u8], src: u64) -> bool {
+            copy(&self.kernel, 0xffff_0000_0000_1000, dst, src)
+        }
+
+        fn read_user(&mut self, dst: &mut [u8], src: u64) -> bool {
+            copy(&self.user, 0x1000, dst, src)
+        }
+    }
+
+    fn vm() -> Vm {
+        Vm {
+            probe: Some(Box::new(Fake {
+                kernel: b"kernel\0".to_vec(),
+                user: b"user-space".to_vec(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn read() {
+        let mut vm = vm();
+        let mut buf = [0xff; 4];
+        let dst = buf.as_mut_ptr() as u64;
+        unsafe {
+            assert_eq!(
+                probe_read_kernel(&mut vm, dst, 4, 0xffff_0000_0000_1001, 0, 0),
+                0
+            );
+            assert_eq!(&buf, b"erne");
+            assert_eq!(
+                probe_read_user(&mut vm, dst, 4, 0x1008, 0, 0),
+                -EFAULT as i64 as u64
+            );
+            assert_eq!(buf, [0; 4]);
+            assert_eq!(
+                probe_read_kernel(&mut vm, dst, 4, 0x1000, 0, 0),
+                -EFAULT as i64 as u64
+            );
+            assert_eq!(probe_read(&mut vm, dst, 4, 0x1000, 0, 0), 0);
+            assert_eq!(&buf, b"user");
+            assert_eq!(probe_read(&mut vm, dst, 4, 0xffff_0000_0000_1000, 0, 0), 0);
+            assert_eq!(&buf, b"kern");
+            vm.probe = None;
+            assert_eq!(
+                probe_read(&mut vm, dst, 4, 0x1000, 0, 0),
+                -EFAULT as i64 as u64
+            );
+        }
+    }
+
+    #[test]
+    fn read_str() {
+        let mut vm = vm();
+        let mut buf = [0xff; 16];
+        let dst = buf.as_mut_ptr() as u64;
+        unsafe {
+            assert_eq!(
+                probe_read_kernel_str(&mut vm, dst, 16, 0xffff_0000_0000_1000, 0, 0),
+                7
+            );
+            assert_eq!(&buf[..7], b"kernel\0");
+            assert_eq!(
+                probe_read_kernel_str(&mut vm, dst, 4, 0xffff_0000_0000_1000, 0, 0),
+                4
+            );
+            assert_eq!(&buf[..4], b"ker\0");
+            assert_eq!(
+                probe_read_user_str(&mut vm, dst, 16, 0x1000, 0, 0),
+                -EFAULT as i64 as u64
+            );
+            assert_eq!(buf, [0; 16]);
+            assert_eq!(probe_read_str(&mut vm, dst, 5, 0x1005, 0, 0), 5);
+            assert_eq!(&buf[..5], b"spac\0");
+            assert_eq!(probe_read_user_str(&mut vm, dst, 0, 0x1000, 0, 0), 0);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! Helper prototypes, after `struct bpf_func_proto` in include/linux/bpf.h,
+//! for checking calls before they run.
+
+use super::BpfFunc;
+
+/// What a helper expects in an argument register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgType {
+    /// The argument is unused.
+    DontCare,
+    /// Any initialized value.
+    Anything,
+    /// A known constant, the size of the memory in the previous argument.
+    ConstSize,
+    /// As `ConstSize`, but may be zero.
+    ConstSizeOrZero,
+    /// A map loaded with `BPF_PSEUDO_MAP_FD`.
+    ConstMapPtr,
+    /// Initialized memory of the key size of the map in an earlier argument.
+    PtrToMapKey,
+    /// Initialized memory of the value size of the map in an earlier
+    /// argument.
+    PtrToMapValue,
+    /// Memory of the value size of the map in an earlier argument, which the
+    /// helper fills.
+    PtrToUninitMapValue,
+    /// Initialized memory, sized by the next argument.
+    PtrToMem,
+    /// As `PtrToMem`, or NULL.
+    PtrToMemOrNull,
+    /// Memory the helper fills, sized by the next argument.
+    PtrToUninitMem,
+    /// As `PtrToUninitMem`, or NULL.
+    PtrToUninitMemOrNull,
+    /// The program context.
+    PtrToCtx,
+    /// Stack memory, or NULL.
+    PtrToStackOrNull,
+    /// A callback loaded with `BPF_PSEUDO_FUNC`.
+    PtrToFunc,
+    /// A `struct bpf_spin_lock` in a map value.
+    PtrToSpinLock,
+    /// A `struct bpf_timer` in a map value.
+    PtrToTimer,
+    /// A NUL-terminated string in a read-only map.
+    PtrToConstStr,
+    /// An 8-byte slot the helper writes a `long` to.
+    PtrToLong,
+}
+
+/// What a helper leaves in r0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetType {
+    Integer,
+    Void,
+    /// A value of the map passed in r1, or NULL.
+    PtrToMapValueOrNull,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HelperProto {
+    pub ret: RetType,
+    pub args: [ArgType; 5],
+}
+
+impl HelperProto {
+    /// Builds a prototype whose arguments after `args` are unused.
+    pub fn new(ret: RetType, args: &[ArgType]) -> Self {
+        let mut proto = HelperProto {
+            ret,
+            args: [ArgType::DontCare; 5],
+        };
+        proto.args[..args.len()].copy_from_slice(args);
+        proto
+    }
+}
+
+/// Returns the prototype of a built-in helper.
+pub fn builtin(func: BpfFunc) -> Option<HelperProto> {
+    use ArgType::*;
+    use BpfFunc::*;
+    use RetType::*;
+    let proto = HelperProto::new;
+    Some(match func {
+        MapLookupElem => proto(PtrToMapValueOrNull, &[ConstMapPtr, PtrToMapKey]),
+        MapUpdateElem => proto(
+            Integer,
+            &[ConstMapPtr, PtrToMapKey, PtrToMapValue, Anything],
+        ),
+        MapDeleteElem => proto(Integer, &[ConstMapPtr, PtrToMapKey]),
+        MapPushElem => proto(Integer, &[ConstMapPtr, PtrToMapValue, Anything]),
+        MapPeekElem => proto(Integer, &[ConstMapPtr, PtrToUninitMapValue]),
+        SpinLock | SpinUnlock => proto(Void, &[PtrToSpinLock]),
+        ProbeRead | ProbeReadKernel | ProbeReadUser | ProbeReadStr | ProbeReadKernelStr
+        | ProbeReadUserStr => proto(Integer, &[PtrToUninitMem, ConstSizeOrZero, Anything]),
+        KtimeGetNs | KtimeGetBootNs | GetPrandomU32 | GetSmpProcessorId | GetNumaNodeId
+        | GetCurrentPidTgid | GetCurrentUidGid | GetCurrentTask => proto(Integer, &[]),
+        GetCurrentComm => proto(Integer, &[PtrToUninitMem, ConstSize]),
+        TracePrintk => proto(
+            Integer,
+            &[PtrToMem, ConstSize, Anything, Anything, Anything],
+        ),
+        Snprintf => proto(
+            Integer,
+            &[
+                PtrToUninitMemOrNull,
+                ConstSizeOrZero,
+                PtrToConstStr,
+                PtrToMemOrNull,
+                ConstSizeOrZero,
+            ],
+        ),
+        Strtol | Strtoul => proto(Integer, &[PtrToMem, ConstSize, Anything, PtrToLong]),
+        Strncmp => proto(Integer, &[PtrToMem, ConstSize, PtrToConstStr]),
+        TimerInit => proto(Integer, &[PtrToTimer, ConstMapPtr, Anything]),
+        TimerSetCallback => proto(Integer, &[PtrToTimer, PtrToFunc]),
+        TimerStart => proto(Integer, &[PtrToTimer, Anything, Anything]),
+        TimerCancel => proto(Integer, &[PtrToTimer]),
+        GetStackid => proto(Integer, &[PtrToCtx, ConstMapPtr, Anything]),
+        GetStack => proto(
+            Integer,
+            &[PtrToCtx, PtrToUninitMem, ConstSizeOrZero, Anything],
+        ),
+        Loop => proto(Integer, &[Anything, PtrToFunc, PtrToStackOrNull, Anything]),
+        ForEachMapElem => proto(
+            Integer,
+            &[ConstMapPtr, PtrToFunc, PtrToStackOrNull, Anything],
+        ),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::helpers::HelperRegistry;
+
+    #[test]
+    fn registry() {
+        let mut helpers = HelperRegistry::with_builtins();
+        for id in (0..256).filter(|&id| helpers.contains(id)) {
+            assert!(
+                helpers.proto(id).is_some(),
+                "helper {} has no prototype",
+                id
+            );
+        }
+        let lookup = helpers.proto(BpfFunc::MapLookupElem as u32).unwrap();
+        assert_eq!(lookup.ret, RetType::PtrToMapValueOrNull);
+        assert_eq!(
+            lookup.args,
+            [
+                ArgType::ConstMapPtr,
+                ArgType::PtrToMapKey,
+                ArgType::DontCare,
+                ArgType::DontCare,
+                ArgType::DontCare
+            ]
+        );
+        assert!(helpers.proto(BpfFunc::Loop as u32).is_some());
+        assert!(helpers.unregister(BpfFunc::MapLookupElem as u32));
+        assert_eq!(helpers.proto(BpfFunc::MapLookupElem as u32), None);
+
+        let proto = HelperProto::new(RetType::Integer, &[ArgType::PtrToCtx]);
+        helpers.register_id(0x1000, |_, _, _, _, _, _| 0);
+        assert_eq!(helpers.proto(0x1000), None);
+        helpers.declare(0x1000, proto);
+        assert_eq!(helpers.proto(0x1000), Some(proto));
+
+        let mut helpers = HelperRegistry::new();
+        helpers.register(BpfFunc::TracePrintk, |_, _, _, _, _, _| 0);
+        assert_eq!(
+            helpers.proto(BpfFunc::TracePrintk as u32),
+            builtin(BpfFunc::TracePrintk)
+        );
+    }
+}
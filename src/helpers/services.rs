@@ -0,0 +1,112 @@
+use crate::vm::Vm;
+
+/// Clock, randomness and CPU topology, provided by rCore or by a test
+/// harness with a fake clock and seeded PRNG.
+pub trait HostServices {
+    /// Monotonic time in nanoseconds, not counting suspend.
+    fn ktime_get_ns(&mut self) -> u64;
+    /// Monotonic time in nanoseconds, counting suspend.
+    fn ktime_get_boot_ns(&mut self) -> u64 {
+        self.ktime_get_ns()
+    }
+    fn prandom_u32(&mut self) -> u32;
+    fn smp_processor_id(&mut self) -> u32;
+    fn numa_node_id(&mut self) -> u32 {
+        0
+    }
+}
+
+fn with_services(vm: &mut Vm, f: impl FnOnce(&mut dyn HostServices) -> u64) -> u64 {
+    vm.services
+        .as_mut()
+        .map_or(0, |services| f(&mut **services))
+}
+
+/// `u64 bpf_ktime_get_ns(void)`
+pub fn ktime_get_ns(vm: &mut Vm, _: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    with_services(vm, |services| services.ktime_get_ns())
+}
+
+/// `u64 bpf_ktime_get_boot_ns(void)`
+pub fn ktime_get_boot_ns(vm: &mut Vm, _: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    with_services(vm, |services| services.ktime_get_boot_ns())
+}
+
+/// `u32 bpf_get_prandom_u32(void)`
+pub fn get_prandom_u32(vm: &mut Vm, _: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    with_services(vm, |services| services.prandom_u32() as u64)
+}
+
+/// `u32 bpf_get_smp_processor_id(void)`
+pub fn get_smp_processor_id(vm: &mut Vm, _: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    with_services(vm, |services| services.smp_processor_id() as u64)
+}
+
+/// `long bpf_get_numa_node_id(void)`
+pub fn get_numa_node_id(vm: &mut Vm, _: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    with_services(vm, |services| services.numa_node_id() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::helpers::{BpfFunc, HelperRegistry};
+
+    struct Fake {
+        now: u64,
+        state: u32,
+    }
+
+    impl HostServices for Fake {
+        fn ktime_get_ns(&mut self) -> u64 {
+            self.now += 1000;
+            self.now
+        }
+
+        fn prandom_u32(&mut self) -> u32 {
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 17;
+            self.state ^= self.state << 5;
+            self.state
+        }
+
+        fn smp_processor_id(&mut self) -> u32 {
+            3
+        }
+    }
+
+    fn run(vm: &mut Vm, helpers: &mut HelperRegistry, func: BpfFunc) -> u64 {
+        helpers.call(vm, func as u32, [0; 5]).unwrap()
+    }
+
+    #[test]
+    fn deterministic() {
+        let mut helpers = HelperRegistry::with_builtins();
+        let mut vms: Vec<Vm> = (0..2)
+            .map(|_| Vm {
+                services: Some(Box::new(Fake { now: 0, state: 42 })),
+                ..Default::default()
+            })
+            .collect();
+        let outputs: Vec<Vec<u64>> = vms
+            .iter_mut()
+            .map(|vm| {
+                [
+                    BpfFunc::KtimeGetNs,
+                    BpfFunc::KtimeGetBootNs,
+                    BpfFunc::GetPrandomU32,
+                    BpfFunc::GetPrandomU32,
+                    BpfFunc::GetSmpProcessorId,
+                    BpfFunc::GetNumaNodeId,
+                ]
+                .iter()
+                .map(|func| run(vm, &mut helpers, *func))
+                .collect()
+            })
+            .collect();
+        assert_eq!(outputs[0], outputs[1]);
+        assert_eq!(&outputs[0][..2], &[1000, 2000]);
+        assert_ne!(outputs[0][2], outputs[0][3]);
+        assert_eq!(&outputs[0][4..], &[3, 0]);
+    }
+}
@@ -0,0 +1,64 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// `bpf_spin_lock`: acquires the `struct bpf_spin_lock` at `lock`.
+///
+/// # Safety
+///
+/// `lock` must be a valid, 4-byte aligned pointer for as long as the lock
+/// is held, only ever accessed through `spin_lock` and `spin_unlock`.
+pub unsafe fn spin_lock(lock: *mut u32) {
+    let lock = AtomicU32::from_ptr(lock);
+    while lock
+        .compare_exchange_weak(0, 1, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+}
+
+/// `bpf_spin_unlock`: releases a lock taken by `spin_lock`.
+///
+/// # Safety
+///
+/// See `spin_lock`.
+pub unsafe fn spin_unlock(lock: *mut u32) {
+    AtomicU32::from_ptr(lock).store(0, Ordering::Release);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mutual_exclusion() {
+        #[repr(C)]
+        struct Value {
+            lock: u32,
+            a: u64,
+            b: u64,
+        }
+        let value = Box::into_raw(Box::new(Value {
+            lock: 0,
+            a: 0,
+            b: 0,
+        })) as usize;
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(move || unsafe {
+                    let value = value as *mut Value;
+                    for _ in 0..10000 {
+                        spin_lock(&mut (*value).lock);
+                        (*value).a += 1;
+                        (*value).b = (*value).a;
+                        spin_unlock(&mut (*value).lock);
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        let value = unsafe { Box::from_raw(value as *mut Value) };
+        assert_eq!((value.lock, value.a, value.b), (0, 40000, 40000));
+    }
+}
@@ -0,0 +1,153 @@
+use crate::consts::*;
+use crate::errno::*;
+use crate::map::StackTraceMap;
+use alloc::vec;
+
+/// Walks a call stack on behalf of `bpf_get_stackid` and `bpf_get_stack`,
+/// e.g. by following frame pointers from the registers saved in `ctx`.
+pub trait Unwinder {
+    /// Fills `ips` with return addresses, innermost frame first, of the user
+    /// stack if `user` is set and of the kernel stack otherwise. Returns the
+    /// number of frames written.
+    fn unwind(&mut self, ctx: u64, user: bool, ips: &mut [u64]) -> usize;
+}
+
+fn skip(flags: u64) -> usize {
+    (flags & BPF_F_SKIP_FIELD_MASK as u64) as usize
+}
+
+fn user(flags: u64) -> bool {
+    flags & BPF_F_USER_STACK as u64 != 0
+}
+
+pub fn get_stackid(
+    unwinder: &mut dyn Unwinder,
+    ctx: u64,
+    map: &mut StackTraceMap,
+    flags: u64,
+) -> i64 {
+    if flags
+        & !(BPF_F_SKIP_FIELD_MASK | BPF_F_USER_STACK | BPF_F_FAST_STACK_CMP | BPF_F_REUSE_STACKID)
+            as u64
+        != 0
+    {
+        return -EINVAL as i64;
+    }
+    let skip = skip(flags);
+    let depth = (skip + map.max_depth()).min(PERF_MAX_STACK_DEPTH as usize);
+    let mut ips = vec![0; depth];
+    let nr = unwinder.unwind(ctx, user(flags), &mut ips).min(depth);
+    if nr <= skip {
+        return -EFAULT as i64;
+    }
+    match map.get_id(&ips[skip..nr], flags) {
+        Ok(id) => id as i64,
+        Err(err) => -err as i64,
+    }
+}
+
+pub fn get_stack(unwinder: &mut dyn Unwinder, ctx: u64, buf: &mut [u8], flags: u64) -> i64 {
+    let err = if flags & !(BPF_F_SKIP_FIELD_MASK | BPF_F_USER_STACK) as u64 != 0
+        || !buf.len().is_multiple_of(8)
+    {
+        EINVAL
+    } else {
+        let skip = skip(flags);
+        let depth = (skip + buf.len() / 8).min(PERF_MAX_STACK_DEPTH as usize);
+        let mut ips = vec![0u64; depth];
+        let nr = unwinder.unwind(ctx, user(flags), &mut ips).min(depth);
+        if nr >= skip {
+            let len = (nr - skip) * 8;
+            for (dst, ip) in buf.chunks_exact_mut(8).zip(&ips[skip..nr]) {
+                dst.copy_from_slice(&ip.to_ne_bytes());
+            }
+            buf[len..].fill(0);
+            return len as i64;
+        }
+        EFAULT
+    };
+    buf.fill(0);
+    -err as i64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::map::{Map, MapDef};
+
+    struct Frames(&'static [u64], &'static [u64]);
+
+    impl Unwinder for Frames {
+        fn unwind(&mut self, _ctx: u64, user: bool, ips: &mut [u64]) -> usize {
+            let frames = if user { self.1 } else { self.0 };
+            let nr = frames.len().min(ips.len());
+            ips[..nr].copy_from_slice(&frames[..nr]);
+            nr
+        }
+    }
+
+    fn stack_map(max_entries: u32) -> StackTraceMap {
+        StackTraceMap::new(&MapDef {
+            map_type: BPF_MAP_TYPE_STACK_TRACE,
+            key_size: 4,
+            value_size: 8 * 4,
+            max_entries,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn stackid() {
+        let mut map = stack_map(1024);
+        let mut kernel = Frames(&[0x10, 0x20, 0x30], &[0x1000, 0x2000]);
+        let id = get_stackid(&mut kernel, 0, &mut map, 0);
+        assert!(id >= 0);
+        assert_eq!(get_stackid(&mut kernel, 0, &mut map, 0), id);
+        let key = (id as u32).to_ne_bytes();
+        let value = map.lookup(&key).unwrap();
+        assert_eq!(&value[..8], &0x10u64.to_ne_bytes());
+        assert_eq!(&value[24..], &[0; 8]);
+
+        let skipped = get_stackid(&mut kernel, 0, &mut map, 1);
+        assert_ne!(skipped, id);
+        let user = get_stackid(&mut kernel, 0, &mut map, BPF_F_USER_STACK as u64);
+        let value = map.lookup(&(user as u32).to_ne_bytes()).unwrap();
+        assert_eq!(&value[..8], &0x1000u64.to_ne_bytes());
+        assert_eq!(get_stackid(&mut kernel, 0, &mut map, 3), -EFAULT as i64);
+        assert_eq!(
+            get_stackid(&mut kernel, 0, &mut map, 1 << 16),
+            -EINVAL as i64
+        );
+    }
+
+    #[test]
+    fn stackid_collision() {
+        let mut map = stack_map(1);
+        let mut first = Frames(&[0x10], &[]);
+        let mut second = Frames(&[0x20], &[]);
+        assert_eq!(get_stackid(&mut first, 0, &mut map, 0), 0);
+        assert_eq!(get_stackid(&mut second, 0, &mut map, 0), -EEXIST as i64);
+        assert_eq!(
+            get_stackid(&mut second, 0, &mut map, BPF_F_REUSE_STACKID as u64),
+            0
+        );
+        let value = map.lookup(&0u32.to_ne_bytes()).unwrap();
+        assert_eq!(&value[..8], &0x20u64.to_ne_bytes());
+        map.delete(&0u32.to_ne_bytes()).unwrap();
+        let mut next = [0; 4];
+        assert_eq!(map.get_next_key(None, &mut next), Err(ENOENT));
+    }
+
+    #[test]
+    fn stack() {
+        let mut frames = Frames(&[0x10, 0x20, 0x30], &[]);
+        let mut buf = [0xff; 32];
+        assert_eq!(get_stack(&mut frames, 0, &mut buf, 1), 16);
+        assert_eq!(&buf[..8], &0x20u64.to_ne_bytes());
+        assert_eq!(&buf[16..], &[0; 16]);
+        let mut buf = [0xff; 12];
+        assert_eq!(get_stack(&mut frames, 0, &mut buf, 0), -EINVAL as i64);
+        assert_eq!(buf, [0; 12]);
+    }
+}
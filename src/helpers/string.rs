@@ -0,0 +1,202 @@
+use crate::errno::*;
+use crate::vm::Vm;
+use core::slice;
+
+/// Bits of the strtox `flags` holding the base.
+pub const BPF_STRTOX_BASE_MASK: u64 = 0x1f;
+
+/// Parses an unsigned integer like `__bpf_strtoull` in kernel/bpf/helpers.c:
+/// leading whitespace and a `-` are skipped, base 0 detects a `0x` or `0`
+/// prefix, and at most 63 bytes after the sign are looked at. Returns the
+/// number of bytes consumed, the magnitude and whether it was negated.
+pub fn strtoull(buf: &[u8], flags: u64) -> Result<(usize, u64, bool), i32> {
+    let mut base = (flags & BPF_STRTOX_BASE_MASK) as u32;
+    if buf.is_empty() || !matches!(base, 0 | 8 | 10 | 16) || flags & !BPF_STRTOX_BASE_MASK != 0 {
+        return Err(EINVAL);
+    }
+    let mut consumed = buf
+        .iter()
+        .take_while(|c| matches!(c, b' ' | b'\t' | b'\n' | 0x0b | 0x0c | b'\r'))
+        .count();
+    let negative = buf.get(consumed) == Some(&b'-');
+    if negative {
+        consumed += 1;
+    }
+    if consumed == buf.len() {
+        return Err(EINVAL);
+    }
+    let s = &buf[consumed..buf.len().min(consumed + 63)];
+    let hex_prefix = s.len() > 1 && s[0] == b'0' && s[1].eq_ignore_ascii_case(&b'x');
+    if base == 0 {
+        base = match s {
+            [b'0', _, c, ..] if hex_prefix && c.is_ascii_hexdigit() => 16,
+            [b'0', ..] => 8,
+            _ => 10,
+        };
+    }
+    let mut s = s;
+    if base == 16 && hex_prefix {
+        consumed += 2;
+        s = &s[2..];
+    }
+    let (mut res, mut overflow, mut len) = (0u64, false, 0);
+    for &c in s {
+        let digit = match (c as char).to_digit(base) {
+            Some(digit) => digit as u64,
+            None => break,
+        };
+        match res
+            .checked_mul(base as u64)
+            .and_then(|res| res.checked_add(digit))
+        {
+            Some(next) => res = next,
+            None => overflow = true,
+        }
+        len += 1;
+    }
+    if overflow {
+        return Err(ERANGE);
+    }
+    if len == 0 {
+        return Err(EINVAL);
+    }
+    Ok((consumed + len, res, negative))
+}
+
+/// Signed variant of `strtoull`, returning the bytes consumed and the value.
+pub fn strtol(buf: &[u8], flags: u64) -> Result<(usize, i64), i32> {
+    let (consumed, res, negative) = strtoull(buf, flags)?;
+    let res = if negative {
+        res.wrapping_neg() as i64
+    } else {
+        res as i64
+    };
+    if res != 0 && (res < 0) != negative {
+        return Err(ERANGE);
+    }
+    Ok((consumed, res))
+}
+
+/// Unsigned variant of `strtoull`, rejecting a `-` sign.
+pub fn strtoul(buf: &[u8], flags: u64) -> Result<(usize, u64), i32> {
+    match strtoull(buf, flags)? {
+        (_, _, true) => Err(EINVAL),
+        (consumed, res, false) => Ok((consumed, res)),
+    }
+}
+
+/// Compares like the kernel's `strncmp`, returning -1, 0 or 1.
+pub fn strncmp(s1: &[u8], s2: &[u8]) -> i32 {
+    for (i, &c1) in s1.iter().enumerate() {
+        let c2 = s2.get(i).copied().unwrap_or(0);
+        if c1 != c2 {
+            return if c1 < c2 { -1 } else { 1 };
+        }
+        if c1 == 0 {
+            break;
+        }
+    }
+    0
+}
+
+/// `long bpf_strtol(const char *buf, size_t buf_len, u64 flags, long *res)`
+pub unsafe fn bpf_strtol(_: &mut Vm, buf: u64, buf_len: u64, flags: u64, res: u64, _: u64) -> u64 {
+    let buf = slice::from_raw_parts(buf as *const u8, buf_len as u32 as usize);
+    let res = res as *mut i64;
+    res.write_unaligned(0);
+    match strtol(buf, flags) {
+        Ok((consumed, value)) => {
+            res.write_unaligned(value);
+            consumed as u64
+        }
+        Err(err) => -err as i64 as u64,
+    }
+}
+
+/// `long bpf_strtoul(const char *buf, size_t buf_len, u64 flags, unsigned long *res)`
+pub unsafe fn bpf_strtoul(_: &mut Vm, buf: u64, buf_len: u64, flags: u64, res: u64, _: u64) -> u64 {
+    let buf = slice::from_raw_parts(buf as *const u8, buf_len as u32 as usize);
+    let res = res as *mut u64;
+    res.write_unaligned(0);
+    match strtoul(buf, flags) {
+        Ok((consumed, value)) => {
+            res.write_unaligned(value);
+            consumed as u64
+        }
+        Err(err) => -err as i64 as u64,
+    }
+}
+
+/// `long bpf_strncmp(const char *s1, u32 s1_sz, const char *s2)`: `s2` is a
+/// NUL-terminated constant string.
+pub unsafe fn bpf_strncmp(_: &mut Vm, s1: u64, s1_sz: u64, s2: u64, _: u64, _: u64) -> u64 {
+    let s1 = slice::from_raw_parts(s1 as *const u8, s1_sz as u32 as usize);
+    let s2 = s2 as *const u8;
+    let mut len = 0;
+    while len < s1.len() && *s2.add(len) != 0 {
+        len += 1;
+    }
+    strncmp(s1, slice::from_raw_parts(s2, len)) as i64 as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn strtox() {
+        assert_eq!(strtol(b"  -42abc", 0), Ok((5, -42)));
+        assert_eq!(strtol(b"0x1F", 0), Ok((4, 31)));
+        assert_eq!(strtol(b"0x1F", 16), Ok((4, 31)));
+        assert_eq!(strtol(b"0x", 0), Ok((1, 0)));
+        assert_eq!(strtol(b"0755", 0), Ok((4, 493)));
+        assert_eq!(strtol(b"0755", 10), Ok((4, 755)));
+        assert_eq!(strtol(b"-9223372036854775808", 10), Ok((20, i64::MIN)));
+        assert_eq!(strtol(b"9223372036854775808", 10), Err(ERANGE));
+        assert_eq!(strtoul(b"18446744073709551615", 0), Ok((20, u64::MAX)));
+        assert_eq!(strtoul(b"18446744073709551616", 0), Err(ERANGE));
+        assert_eq!(strtoul(b"-1", 0), Err(EINVAL));
+        assert_eq!(strtoul(b"1", 2), Err(EINVAL));
+        assert_eq!(strtoul(b"1", 1 << 5), Err(EINVAL));
+        assert_eq!(strtoul(b"  -", 0), Err(EINVAL));
+        assert_eq!(strtoul(b"z", 0), Err(EINVAL));
+        assert_eq!(strtoul(b"", 0), Err(EINVAL));
+
+        let mut res = 7u64;
+        let buf = b"0x10\n";
+        let ret = unsafe {
+            bpf_strtoul(
+                &mut Vm::default(),
+                buf.as_ptr() as u64,
+                buf.len() as u64,
+                0,
+                &mut res as *mut u64 as u64,
+                0,
+            )
+        };
+        assert_eq!((ret, res), (4, 16));
+    }
+
+    #[test]
+    fn compare() {
+        assert_eq!(strncmp(b"abc", b"abc"), 0);
+        assert_eq!(strncmp(b"abc", b"abd"), -1);
+        assert_eq!(strncmp(b"abd", b"abc"), 1);
+        assert_eq!(strncmp(b"ab", b"abc"), 0);
+        assert_eq!(strncmp(b"abc", b"ab"), 1);
+        assert_eq!(strncmp(b"ab\0x", b"ab"), 0);
+        let ret = unsafe {
+            let s1 = b"kernel.";
+            let s2 = b"kernel\0";
+            bpf_strncmp(
+                &mut Vm::default(),
+                s1.as_ptr() as u64,
+                s1.len() as u64,
+                s2.as_ptr() as u64,
+                0,
+                0,
+            )
+        };
+        assert_eq!(ret as i64, 1);
+    }
+}
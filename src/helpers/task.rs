@@ -0,0 +1,121 @@
+use crate::errno::*;
+use crate::vm::Vm;
+use core::slice;
+
+pub const TASK_COMM_LEN: usize = 16;
+
+/// The parts of a task visible to programs. As in the kernel, `pid` is the
+/// thread id and `tgid` the process id.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Task {
+    pub pid: u32,
+    pub tgid: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub comm: [u8; TASK_COMM_LEN],
+    /// Address of the task structure, returned by `bpf_get_current_task`.
+    pub addr: u64,
+}
+
+/// Exposes the task a program runs on behalf of, e.g. from rCore's
+/// scheduler.
+pub trait CurrentTask {
+    /// Returns `None` when no task is current.
+    fn current_task(&mut self) -> Option<Task>;
+}
+
+fn current(vm: &mut Vm) -> Option<Task> {
+    vm.task.as_mut()?.current_task()
+}
+
+/// `u64 bpf_get_current_pid_tgid(void)`: `tgid << 32 | pid`.
+pub fn get_current_pid_tgid(vm: &mut Vm, _: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    match current(vm) {
+        Some(task) => (task.tgid as u64) << 32 | task.pid as u64,
+        None => -EINVAL as i64 as u64,
+    }
+}
+
+/// `u64 bpf_get_current_uid_gid(void)`: `gid << 32 | uid`.
+pub fn get_current_uid_gid(vm: &mut Vm, _: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    match current(vm) {
+        Some(task) => (task.gid as u64) << 32 | task.uid as u64,
+        None => -EINVAL as i64 as u64,
+    }
+}
+
+/// `long bpf_get_current_comm(void *buf, u32 size_of_buf)`: copies the
+/// command name into `buf`, truncated and always NUL-terminated.
+pub unsafe fn get_current_comm(vm: &mut Vm, buf: u64, size: u64, _: u64, _: u64, _: u64) -> u64 {
+    let buf = slice::from_raw_parts_mut(buf as *mut u8, size as u32 as usize);
+    buf.fill(0);
+    let task = match current(vm) {
+        Some(task) => task,
+        None => return -EINVAL as i64 as u64,
+    };
+    let len = task
+        .comm
+        .iter()
+        .position(|c| *c == 0)
+        .unwrap_or(TASK_COMM_LEN)
+        .min(buf.len().saturating_sub(1));
+    buf[..len].copy_from_slice(&task.comm[..len]);
+    0
+}
+
+/// `u64 bpf_get_current_task(void)`
+pub fn get_current_task(vm: &mut Vm, _: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    current(vm).map_or(0, |task| task.addr)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Mock(Option<Task>);
+
+    impl CurrentTask for Mock {
+        fn current_task(&mut self) -> Option<Task> {
+            self.0
+        }
+    }
+
+    #[test]
+    fn current_task() {
+        let mut comm = [0; TASK_COMM_LEN];
+        comm[..4].copy_from_slice(b"bash");
+        let mut vm = Vm {
+            task: Some(Box::new(Mock(Some(Task {
+                pid: 12,
+                tgid: 10,
+                uid: 1000,
+                gid: 100,
+                comm,
+                addr: 0xffff_0000_1234_0000,
+            })))),
+            ..Default::default()
+        };
+        assert_eq!(get_current_pid_tgid(&mut vm, 0, 0, 0, 0, 0), 10 << 32 | 12);
+        assert_eq!(
+            get_current_uid_gid(&mut vm, 0, 0, 0, 0, 0),
+            100 << 32 | 1000
+        );
+        assert_eq!(
+            get_current_task(&mut vm, 0, 0, 0, 0, 0),
+            0xffff_0000_1234_0000
+        );
+        let mut buf = [0xff; 16];
+        let ret = unsafe { get_current_comm(&mut vm, buf.as_mut_ptr() as u64, 3, 0, 0, 0) };
+        assert_eq!(ret, 0);
+        assert_eq!(&buf[..4], b"ba\0\xff");
+
+        vm.task = Some(Box::new(Mock(None)));
+        assert_eq!(
+            get_current_pid_tgid(&mut vm, 0, 0, 0, 0, 0),
+            -EINVAL as i64 as u64
+        );
+        let ret = unsafe { get_current_comm(&mut vm, buf.as_mut_ptr() as u64, 16, 0, 0, 0) };
+        assert_eq!((ret, buf), (-EINVAL as i64 as u64, [0; 16]));
+        assert_eq!(get_current_task(&mut vm, 0, 0, 0, 0, 0), 0);
+    }
+}
@@ -0,0 +1,316 @@
+//! `struct bpf_timer` support. The first word of a timer field holds the id
+//! of its state in `Vm::timers`; expiry is left to a `TimerHost`, which
+//! reports it back through `interpret::fire_timer`.
+
+use super::callback::Call;
+use crate::consts::*;
+use crate::errno::*;
+use crate::vm::Vm;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// Size of `struct bpf_timer`.
+pub const BPF_TIMER_SIZE: usize = 16;
+
+/// Timer hardware or a virtual clock, provided by rCore's timer interrupt or
+/// by a test harness.
+pub trait TimerHost {
+    /// Arms timer `id` on `clock` to expire `nsecs` from now, or at `nsecs`
+    /// with `BPF_F_TIMER_ABS`, replacing any pending expiry.
+    fn start(&mut self, id: u64, clock: u32, nsecs: u64, flags: u64);
+    /// Disarms timer `id`, returning whether it was pending.
+    fn cancel(&mut self, id: u64) -> bool;
+}
+
+struct Timer {
+    /// Address of the `struct bpf_timer` field.
+    addr: u64,
+    map: u64,
+    clock: u32,
+    callback: Option<u64>,
+}
+
+/// Timers initialized by programs, keyed by id.
+#[derive(Default)]
+pub struct Timers {
+    timers: BTreeMap<u64, Timer>,
+    next_id: u64,
+    /// Id of the timer whose callback is running.
+    running: Option<u64>,
+}
+
+impl Timers {
+    unsafe fn get(&mut self, addr: u64) -> Option<(u64, &mut Timer)> {
+        let id = (addr as *const u64).read_unaligned();
+        self.timers
+            .get_mut(&id)
+            .filter(|timer| timer.addr == addr)
+            .map(|timer| (id, timer))
+    }
+
+    /// Drops the timer at `addr` in a value being freed, disarming it, as
+    /// `bpf_obj_free_timer` does.
+    pub(crate) unsafe fn free(&mut self, addr: u64, host: Option<&mut (dyn TimerHost + 'static)>) {
+        if let Some((id, _)) = self.get(addr) {
+            self.timers.remove(&id);
+            if let Some(host) = host {
+                host.cancel(id);
+            }
+        }
+    }
+
+    /// Drops and disarms the timers of maps for which `live` is false.
+    pub(crate) fn retain(
+        &mut self,
+        mut host: Option<&mut (dyn TimerHost + 'static)>,
+        mut live: impl FnMut(u64) -> bool,
+    ) {
+        self.timers.retain(|&id, timer| {
+            let keep = live(timer.map);
+            if let (false, Some(host)) = (keep, host.as_deref_mut()) {
+                host.cancel(id);
+            }
+            keep
+        });
+    }
+}
+
+/// Returns the key and value address of the live element of `map` whose
+/// timer is at `addr`.
+fn element(vm: &mut Vm, map: u64, addr: u64) -> Option<(Vec<u8>, u64)> {
+    let map = vm.map(map)?;
+    let value = addr.checked_sub(map.def().timer_off? as u64)?;
+    Some((map.key_of(value)?, value))
+}
+
+/// Runs the callback of timer `id` as `callback_fn(map, key, value)`,
+/// returning `None` if the timer or its element is gone or it has no
+/// callback.
+pub fn fire<E>(vm: &mut Vm, id: u64, call: &mut Call<E>) -> Result<Option<u64>, E> {
+    let (addr, map, callback) = match vm.timers.timers.get(&id) {
+        Some(timer) => (timer.addr, timer.map, timer.callback),
+        None => return Ok(None),
+    };
+    let (key, value) = match element(vm, map, addr) {
+        Some(element) => element,
+        None => {
+            vm.timers.timers.remove(&id);
+            return Ok(None);
+        }
+    };
+    let callback = match callback {
+        Some(callback) => callback,
+        None => return Ok(None),
+    };
+    let running = vm.timers.running.replace(id);
+    let ret = call(vm, callback, [map, key.as_ptr() as u64, value, 0, 0]);
+    vm.timers.running = running;
+    ret.map(Some)
+}
+
+fn ret(result: Result<u64, i32>) -> u64 {
+    match result {
+        Ok(ret) => ret,
+        Err(err) => -err as i64 as u64,
+    }
+}
+
+/// `long bpf_timer_init(struct bpf_timer *timer, struct bpf_map *map, u64 flags)`:
+/// `flags` is the clock id.
+pub unsafe fn timer_init(vm: &mut Vm, addr: u64, map: u64, flags: u64, _: u64, _: u64) -> u64 {
+    if !matches!(
+        flags as u32,
+        CLOCK_REALTIME | CLOCK_MONOTONIC | CLOCK_BOOTTIME
+    ) || flags >> 32 != 0
+    {
+        return ret(Err(EINVAL));
+    }
+    if vm.timers.get(addr).is_some() {
+        return ret(Err(EBUSY));
+    }
+    if element(vm, map, addr).is_none() {
+        return ret(Err(EINVAL));
+    }
+    vm.timers.next_id += 1;
+    let id = vm.timers.next_id;
+    let timer = Timer {
+        addr,
+        map,
+        clock: flags as u32,
+        callback: None,
+    };
+    vm.timers.timers.insert(id, timer);
+    (addr as *mut u64).write_unaligned(id);
+    0
+}
+
+/// `long bpf_timer_set_callback(struct bpf_timer *timer, void *callback_fn)`
+pub unsafe fn timer_set_callback(
+    vm: &mut Vm,
+    addr: u64,
+    callback: u64,
+    _: u64,
+    _: u64,
+    _: u64,
+) -> u64 {
+    match vm.timers.get(addr) {
+        Some((_, timer)) => {
+            timer.callback = Some(callback);
+            0
+        }
+        None => ret(Err(EINVAL)),
+    }
+}
+
+/// `long bpf_timer_start(struct bpf_timer *timer, u64 nsecs, u64 flags)`
+pub unsafe fn timer_start(vm: &mut Vm, addr: u64, nsecs: u64, flags: u64, _: u64, _: u64) -> u64 {
+    if flags & !((BPF_F_TIMER_ABS | BPF_F_TIMER_CPU_PIN) as u64) != 0 {
+        return ret(Err(EINVAL));
+    }
+    let (id, clock) = match vm.timers.get(addr) {
+        Some((id, timer)) if timer.callback.is_some() => (id, timer.clock),
+        _ => return ret(Err(EINVAL)),
+    };
+    match &mut vm.timer_host {
+        Some(host) => {
+            host.start(id, clock, nsecs, flags);
+            0
+        }
+        None => ret(Err(EOPNOTSUPP)),
+    }
+}
+
+/// `long bpf_timer_cancel(struct bpf_timer *timer)`: returns 1 if the timer
+/// was pending.
+pub unsafe fn timer_cancel(vm: &mut Vm, addr: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    let id = match vm.timers.get(addr) {
+        Some((id, _)) => id,
+        None => return ret(Err(EINVAL)),
+    };
+    if vm.timers.running == Some(id) {
+        return ret(Err(EDEADLK));
+    }
+    let pending = vm.timer_host.as_mut().is_some_and(|host| host.cancel(id));
+    pending as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::map::{self, MapDef};
+    use alloc::boxed::Box;
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
+    struct Host(Rc<RefCell<BTreeMap<u64, u64>>>);
+
+    impl TimerHost for Host {
+        fn start(&mut self, id: u64, _: u32, nsecs: u64, _: u64) {
+            self.0.borrow_mut().insert(id, nsecs);
+        }
+
+        fn cancel(&mut self, id: u64) -> bool {
+            self.0.borrow_mut().remove(&id).is_some()
+        }
+    }
+
+    #[test]
+    fn helpers() {
+        let def = MapDef {
+            map_type: BPF_MAP_TYPE_HASH,
+            key_size: 4,
+            value_size: 24,
+            max_entries: 2,
+            timer_off: Some(8),
+            ..Default::default()
+        };
+        let pending = Rc::new(RefCell::new(BTreeMap::new()));
+        let mut vm = Vm {
+            timer_host: Some(Box::new(Host(pending.clone()))),
+            ..Default::default()
+        };
+        vm.maps.push(map::create(&def, 0).unwrap());
+        let handle = vm.map_handle(0).unwrap();
+        let map = vm.map(handle).unwrap();
+        map.update(&1u32.to_ne_bytes(), &[0xff; 24], BPF_ANY as u64)
+            .unwrap();
+        let addr = map.lookup(&1u32.to_ne_bytes()).unwrap()[8..].as_ptr() as u64;
+        let err = |err: i32| -err as i64 as u64;
+
+        unsafe {
+            assert_eq!(timer_set_callback(&mut vm, addr, 3, 0, 0, 0), err(EINVAL));
+            assert_eq!(timer_init(&mut vm, addr, handle, 2, 0, 0), err(EINVAL));
+            assert_eq!(timer_init(&mut vm, addr + 8, handle, 1, 0, 0), err(EINVAL));
+            assert_eq!(timer_init(&mut vm, addr, handle, 1, 0, 0), 0);
+            assert_eq!(timer_init(&mut vm, addr, handle, 1, 0, 0), err(EBUSY));
+            assert_eq!(timer_start(&mut vm, addr, 10, 0, 0, 0), err(EINVAL));
+            assert_eq!(timer_set_callback(&mut vm, addr, 3, 0, 0, 0), 0);
+            assert_eq!(timer_start(&mut vm, addr, 10, 4, 0, 0), err(EINVAL));
+            assert_eq!(timer_start(&mut vm, addr, 10, 0, 0, 0), 0);
+            assert_eq!(pending.borrow().get(&1), Some(&10));
+            assert_eq!(timer_cancel(&mut vm, addr, 0, 0, 0, 0), 1);
+            assert_eq!(timer_cancel(&mut vm, addr, 0, 0, 0, 0), 0);
+        }
+
+        let mut fired = Vec::new();
+        let mut call = |_: &mut Vm, func, args: [u64; 5]| {
+            fired.push((func, args[2] + 8 == addr));
+            Ok::<_, ()>(0)
+        };
+        assert_eq!(fire(&mut vm, 1, &mut call), Ok(Some(0)));
+
+        // lookups hide the timer, replacing the element frees it
+        let map = vm.map(handle).unwrap();
+        let mut value = [0; 24];
+        map.lookup_elem(&1u32.to_ne_bytes(), &mut value, 0).unwrap();
+        assert_eq!(value[..8], [0xff; 8]);
+        assert_eq!(value[8..], [0; 16]);
+        assert_eq!(unsafe { timer_start(&mut vm, addr, 10, 0, 0, 0) }, 0);
+        let map = vm.map(handle).unwrap();
+        map.update(&1u32.to_ne_bytes(), &[0; 24], BPF_EXIST as u64)
+            .unwrap();
+        vm.reclaim();
+        assert!(pending.borrow().is_empty());
+        assert!(vm.timers.timers.is_empty());
+        assert_eq!(fire(&mut vm, 1, &mut call), Ok(None));
+        vm.map(handle).unwrap().delete(&1u32.to_ne_bytes()).unwrap();
+
+        // deleting an element frees its timer, dropping the map frees the rest
+        let map = vm.map(handle).unwrap();
+        for key in [1u32, 2] {
+            map.update(&key.to_ne_bytes(), &[0; 24], BPF_ANY as u64)
+                .unwrap();
+        }
+        let addr = |vm: &mut Vm, key: u32| {
+            let map = vm.map(handle).unwrap();
+            map.lookup(&key.to_ne_bytes()).unwrap()[8..].as_ptr() as u64
+        };
+        for key in [1, 2] {
+            let addr = addr(&mut vm, key);
+            unsafe {
+                assert_eq!(timer_init(&mut vm, addr, handle, 1, 0, 0), 0);
+                assert_eq!(timer_set_callback(&mut vm, addr, 3, 0, 0, 0), 0);
+                assert_eq!(timer_start(&mut vm, addr, 10, 0, 0, 0), 0);
+            }
+        }
+        assert_eq!(pending.borrow().keys().collect::<Vec<_>>(), [&2, &3]);
+
+        // handles and timers survive the table growing and shifting
+        vm.maps.insert(0, map::create(&def, 0).unwrap());
+        for _ in 0..8 {
+            vm.maps.push(map::create(&def, 0).unwrap());
+        }
+        vm.reclaim();
+        assert_eq!(pending.borrow().keys().collect::<Vec<_>>(), [&2, &3]);
+        assert_eq!(vm.map_handle(1), Some(handle));
+        vm.map(handle).unwrap().delete(&1u32.to_ne_bytes()).unwrap();
+        vm.reclaim();
+        assert_eq!(pending.borrow().keys().collect::<Vec<_>>(), [&3]);
+        assert_eq!(fire(&mut vm, 2, &mut call), Ok(None));
+        vm.maps.clear();
+        vm.reclaim();
+        assert!(pending.borrow().is_empty());
+        assert!(vm.timers.timers.is_empty());
+        assert_eq!(fired, [(3, true)]);
+    }
+}
@@ -1,17 +1,66 @@
+use crate::consts::*;
+use crate::helpers::{callback, timer, BpfFunc, HelperRegistry};
 use crate::types::*;
+use crate::vm::Vm;
 
 const STACK_SIZE: usize = 512;
+/// Frame limit for nested callbacks, as `MAX_CALL_FRAMES` in the kernel.
+const MAX_CALL_FRAMES: usize = 8;
 
-pub type Helper = unsafe fn(u64, u64, u64, u64, u64) -> u64;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The program called a helper id missing from the registry.
+    UnknownHelper { pc: usize, id: u32 },
+    /// The program called a kfunc id missing from the registry.
+    UnknownKfunc { pc: usize, id: u32 },
+    /// A callback was entered with `MAX_CALL_FRAMES` frames already active.
+    CallDepth { pc: usize },
+    /// A `BPF_PSEUDO_MAP_FD` load names a fd with no map.
+    InvalidMapFd { pc: usize, fd: u32 },
+}
+
+pub fn interpret(
+    insts: &[u64],
+    helpers: &mut HelperRegistry,
+    vm: &mut Vm,
+    ctx: u64,
+) -> Result<u64, Error> {
+    let ret = run(insts, helpers, vm, 0, [ctx, 0, 0, 0, 0], 1);
+    vm.reclaim();
+    ret
+}
+
+/// Runs the callback of timer `id` once its `TimerHost` expiry is due,
+/// returning `None` if the timer no longer exists.
+pub fn fire_timer(
+    insts: &[u64],
+    helpers: &mut HelperRegistry,
+    vm: &mut Vm,
+    id: u64,
+) -> Result<Option<u64>, Error> {
+    let ret = timer::fire(vm, id, &mut |vm, func, args| {
+        run(insts, helpers, vm, func, args, 1)
+    });
+    vm.reclaim();
+    ret
+}
 
-pub fn interpret(insts: &[u64], helpers: &[Helper], ctx: u64) -> u64 {
-    let mut pc: u16 = 0;
+/// Runs the subprogram at `entry` on a fresh frame with r1-r5 set to `args`
+/// until it exits. Function addresses loaded by `BPF_PSEUDO_FUNC` are
+/// instruction indices.
+fn run(
+    insts: &[u64],
+    helpers: &mut HelperRegistry,
+    vm: &mut Vm,
+    entry: u64,
+    args: [u64; 5],
+    depth: usize,
+) -> Result<u64, Error> {
+    let mut pc = entry as u16;
     let mut reg: [u64; 16] = [0; 16];
-    let stack: [u8; STACK_SIZE] = [0; STACK_SIZE];
-    reg[1] = ctx;
-    unsafe {
-        reg[10] = stack.as_ptr().add(STACK_SIZE) as u64;
-    }
+    let mut stack: [u64; STACK_SIZE / 8] = [0; STACK_SIZE / 8];
+    reg[1..6].copy_from_slice(&args);
+    reg[10] = stack.as_mut_ptr() as u64 + STACK_SIZE as u64;
     loop {
         let inst = insts[pc as usize];
         pc += 1;
@@ -21,71 +70,54 @@ pub fn interpret(insts: &[u64], helpers: &[Helper], ctx: u64) -> u64 {
         let dst: usize = ((inst >> 8) & 0x0f) as usize;
         let op: u8 = (inst & u8::MAX as u64) as u8;
         match op {
-            ALU_K_ADD => reg[dst] = (reg[dst] as i32).wrapping_add(imm) as u64,
-            ALU_X_ADD => reg[dst] = (reg[dst] as i32).wrapping_add(reg[src] as i32) as u64,
-            ALU_K_SUB => reg[dst] = (reg[dst] as i32).wrapping_sub(imm) as u64,
-            ALU_X_SUB => reg[dst] = (reg[dst] as i32).wrapping_sub(reg[src] as i32) as u64,
-            ALU_K_MUL => reg[dst] = (reg[dst] as i32).wrapping_mul(imm) as u64,
-            ALU_X_MUL => reg[dst] = (reg[dst] as i32).wrapping_mul(reg[src] as i32) as u64,
-            ALU_K_DIV => {
-                reg[dst] = match (reg[dst] as u32).checked_div(imm as u32) {
-                    Some(res) => res as u64,
-                    None => return 0,
-                };
-            }
+            // 32-bit results are zero-extended, as the verifier assumes
+            ALU_K_ADD => reg[dst] = (reg[dst] as u32).wrapping_add(imm as u32) as u64,
+            ALU_X_ADD => reg[dst] = (reg[dst] as u32).wrapping_add(reg[src] as u32) as u64,
+            ALU_K_SUB => reg[dst] = (reg[dst] as u32).wrapping_sub(imm as u32) as u64,
+            ALU_X_SUB => reg[dst] = (reg[dst] as u32).wrapping_sub(reg[src] as u32) as u64,
+            ALU_K_MUL => reg[dst] = (reg[dst] as u32).wrapping_mul(imm as u32) as u64,
+            ALU_X_MUL => reg[dst] = (reg[dst] as u32).wrapping_mul(reg[src] as u32) as u64,
+            // dividing by zero gives 0 and the remainder is the dividend, as
+            // the kernel defines
+            ALU_K_DIV => reg[dst] = (reg[dst] as u32).checked_div(imm as u32).unwrap_or(0) as u64,
             ALU_X_DIV => {
-                reg[dst] = match (reg[dst] as u32).checked_div(reg[src] as u32) {
-                    Some(res) => res as u64,
-                    None => return 0,
-                };
+                reg[dst] = (reg[dst] as u32).checked_div(reg[src] as u32).unwrap_or(0) as u64
             }
             ALU_K_OR => reg[dst] = (reg[dst] as u32 | imm as u32) as u64,
             ALU_X_OR => reg[dst] = (reg[dst] as u32 | reg[src] as u32) as u64,
             ALU_K_AND => reg[dst] = (reg[dst] as u32 & imm as u32) as u64,
             ALU_X_AND => reg[dst] = (reg[dst] as u32 & reg[src] as u32) as u64,
+            // shifts take the amount modulo the width, as the kernel does
             ALU_K_LSH => reg[dst] = (reg[dst] as u32).wrapping_shl(imm as u32) as u64,
             ALU_X_LSH => reg[dst] = (reg[dst] as u32).wrapping_shl(reg[src] as u32) as u64,
             ALU_K_RSH => reg[dst] = (reg[dst] as u32).wrapping_shr(imm as u32) as u64,
             ALU_X_RSH => reg[dst] = (reg[dst] as u32).wrapping_shr(reg[src] as u32) as u64,
-            ALU_K_NEG => {
-                reg[dst] = (reg[dst] as i32).wrapping_neg() as u64;
-                reg[dst] &= u32::MAX as u64;
-            }
+            ALU_K_NEG => reg[dst] = (reg[dst] as i32).wrapping_neg() as u32 as u64,
             ALU_K_MOD => {
-                reg[dst] = match (reg[dst] as u32).checked_rem(imm as u32) {
-                    Some(res) => res as u64,
-                    None => return 0,
-                };
+                let res = (reg[dst] as u32).checked_rem(imm as u32);
+                reg[dst] = res.unwrap_or(reg[dst] as u32) as u64;
             }
             ALU_X_MOD => {
-                reg[dst] = match (reg[dst] as u32).checked_rem(reg[src] as u32) {
-                    Some(res) => res as u64,
-                    None => return 0,
-                };
+                let res = (reg[dst] as u32).checked_rem(reg[src] as u32);
+                reg[dst] = res.unwrap_or(reg[dst] as u32) as u64;
             }
             ALU_K_XOR => reg[dst] = (reg[dst] as u32 ^ imm as u32) as u64,
             ALU_X_XOR => reg[dst] = (reg[dst] as u32 ^ reg[src] as u32) as u64,
             ALU_K_MOV => reg[dst] = imm as u32 as u64,
             ALU_X_MOV => reg[dst] = reg[src] as u32 as u64,
-            ALU_K_ARSH => {
-                reg[dst] = (reg[dst] as i32).wrapping_shr(imm as u32) as u64;
-                reg[dst] &= u32::MAX as u64;
-            }
-            ALU_X_ARSH => {
-                reg[dst] = (reg[dst] as i32).wrapping_shr(reg[src] as u32) as u64;
-                reg[dst] &= u32::MAX as u64;
-            }
+            ALU_K_ARSH => reg[dst] = (reg[dst] as i32).wrapping_shr(imm as u32) as u32 as u64,
+            ALU_X_ARSH => reg[dst] = (reg[dst] as i32).wrapping_shr(reg[src] as u32) as u32 as u64,
             ALU_K_END => match imm {
                 16 => reg[dst] = (reg[dst] as u16).to_le() as u64,
                 32 => reg[dst] = (reg[dst] as u32).to_le() as u64,
-                64 => reg[dst] = (reg[dst] as u64).to_le() as u64,
-                _ => return 0,
+                64 => reg[dst] = reg[dst].to_le(),
+                _ => return Ok(0),
             },
             ALU_X_END => match imm {
                 16 => reg[dst] = (reg[dst] as u16).to_be() as u64,
                 32 => reg[dst] = (reg[dst] as u32).to_be() as u64,
-                64 => reg[dst] = (reg[dst] as u64).to_be() as u64,
-                _ => return 0,
+                64 => reg[dst] = reg[dst].to_be(),
+                _ => return Ok(0),
             },
 
             ALU64_K_ADD => reg[dst] = reg[dst].wrapping_add(imm as u64),
@@ -94,45 +126,25 @@ pub fn interpret(insts: &[u64], helpers: &[Helper], ctx: u64) -> u64 {
             ALU64_X_SUB => reg[dst] = reg[dst].wrapping_sub(reg[src]),
             ALU64_K_MUL => reg[dst] = reg[dst].wrapping_mul(imm as u64),
             ALU64_X_MUL => reg[dst] = reg[dst].wrapping_mul(reg[src]),
-            ALU64_K_DIV => {
-                reg[dst] = match reg[dst].checked_div(imm as u64) {
-                    Some(res) => res,
-                    None => return 0,
-                };
-            }
-            ALU64_X_DIV => {
-                reg[dst] = match reg[dst].checked_div(reg[src]) {
-                    Some(res) => res,
-                    None => return 0,
-                };
-            }
+            ALU64_K_DIV => reg[dst] = reg[dst].checked_div(imm as u64).unwrap_or(0),
+            ALU64_X_DIV => reg[dst] = reg[dst].checked_div(reg[src]).unwrap_or(0),
             ALU64_K_OR => reg[dst] |= imm as u64,
             ALU64_X_OR => reg[dst] |= reg[src],
             ALU64_K_AND => reg[dst] &= imm as u64,
             ALU64_X_AND => reg[dst] &= reg[src],
-            ALU64_K_LSH => reg[dst] <<= imm as u64,
-            ALU64_X_LSH => reg[dst] <<= reg[src],
-            ALU64_K_RSH => reg[dst] >>= imm as u64,
-            ALU64_X_RSH => reg[dst] >>= reg[src],
+            ALU64_K_LSH => reg[dst] = reg[dst].wrapping_shl(imm as u32),
+            ALU64_X_LSH => reg[dst] = reg[dst].wrapping_shl(reg[src] as u32),
+            ALU64_K_RSH => reg[dst] = reg[dst].wrapping_shr(imm as u32),
+            ALU64_X_RSH => reg[dst] = reg[dst].wrapping_shr(reg[src] as u32),
             ALU64_K_NEG => reg[dst] = (-(reg[dst] as i64)) as u64,
-            ALU64_K_MOD => {
-                reg[dst] = match reg[dst].checked_rem(imm as u64) {
-                    Some(res) => res,
-                    None => return 0,
-                };
-            }
-            ALU64_X_MOD => {
-                reg[dst] = match reg[dst].checked_rem(reg[src]) {
-                    Some(res) => res,
-                    None => return 0,
-                };
-            }
+            ALU64_K_MOD => reg[dst] = reg[dst].checked_rem(imm as u64).unwrap_or(reg[dst]),
+            ALU64_X_MOD => reg[dst] = reg[dst].checked_rem(reg[src]).unwrap_or(reg[dst]),
             ALU64_K_XOR => reg[dst] ^= imm as u64,
             ALU64_X_XOR => reg[dst] ^= reg[src],
             ALU64_K_MOV => reg[dst] = imm as u64,
             ALU64_X_MOV => reg[dst] = reg[src],
-            ALU64_K_ARSH => reg[dst] = (reg[dst] as i64 >> imm as u64) as u64,
-            ALU64_X_ARSH => reg[dst] = (reg[dst] as i64 >> reg[src]) as u64,
+            ALU64_K_ARSH => reg[dst] = (reg[dst] as i64).wrapping_shr(imm as u32) as u64,
+            ALU64_X_ARSH => reg[dst] = (reg[dst] as i64).wrapping_shr(reg[src] as u32) as u64,
 
             JMP_K_JA => {
                 pc = (pc as i16 + off) as u16;
@@ -207,11 +219,44 @@ pub fn interpret(insts: &[u64], helpers: &[Helper], ctx: u64) -> u64 {
                     pc = (pc as i16 + off) as u16;
                 }
             }
-            JMP_K_CALL => unsafe {
-                reg[0] = helpers[imm as usize](reg[1], reg[2], reg[3], reg[4], reg[5]);
-            },
+            JMP_K_CALL => {
+                let call_pc = pc as usize - 1;
+                let args = [reg[1], reg[2], reg[3], reg[4], reg[5]];
+                if src as u32 == BPF_PSEUDO_KFUNC_CALL {
+                    // module BTF (a nonzero off) is not supported
+                    let ret = match off {
+                        0 => helpers.kfuncs().call(vm, imm as u32, args),
+                        _ => None,
+                    };
+                    reg[0] = ret.ok_or(Error::UnknownKfunc {
+                        pc: call_pc,
+                        id: imm as u32,
+                    })?;
+                    continue;
+                }
+                let mut call = |vm: &mut Vm, func: u64, args: [u64; 5]| {
+                    if depth == MAX_CALL_FRAMES {
+                        return Err(Error::CallDepth { pc: call_pc });
+                    }
+                    run(insts, helpers, vm, func, args, depth + 1)
+                };
+                reg[0] = match BpfFunc::from_id(imm as u32) {
+                    Some(BpfFunc::Loop) => {
+                        callback::bpf_loop(vm, reg[1], reg[2], reg[3], reg[4], &mut call)?
+                    }
+                    Some(BpfFunc::ForEachMapElem) => {
+                        callback::for_each_map_elem(vm, reg[1], reg[2], reg[3], reg[4], &mut call)?
+                    }
+                    _ => helpers
+                        .call(vm, imm as u32, args)
+                        .ok_or(Error::UnknownHelper {
+                            pc: call_pc,
+                            id: imm as u32,
+                        })?,
+                };
+            }
             JMP_K_EXIT => {
-                return reg[0];
+                return Ok(reg[0]);
             }
             JMP_K_JLT => {
                 if reg[dst] < imm as u64 {
@@ -253,10 +298,130 @@ pub fn interpret(insts: &[u64], helpers: &[Helper], ctx: u64) -> u64 {
                     pc = (pc as i16 + off) as u16;
                 }
             }
+            JMP32_K_JEQ => {
+                if reg[dst] as u32 == imm as u32 {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
+            JMP32_X_JEQ => {
+                if reg[dst] as u32 == reg[src] as u32 {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
+            JMP32_K_JNE => {
+                if reg[dst] as u32 != imm as u32 {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
+            JMP32_X_JNE => {
+                if reg[dst] as u32 != reg[src] as u32 {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
+            JMP32_K_JGT => {
+                if reg[dst] as u32 > imm as u32 {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
+            JMP32_X_JGT => {
+                if reg[dst] as u32 > reg[src] as u32 {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
+            JMP32_K_JGE => {
+                if reg[dst] as u32 >= imm as u32 {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
+            JMP32_X_JGE => {
+                if reg[dst] as u32 >= reg[src] as u32 {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
+            JMP32_K_JLT => {
+                if (reg[dst] as u32) < imm as u32 {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
+            JMP32_X_JLT => {
+                if (reg[dst] as u32) < reg[src] as u32 {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
+            JMP32_K_JLE => {
+                if (reg[dst] as u32) <= imm as u32 {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
+            JMP32_X_JLE => {
+                if (reg[dst] as u32) <= reg[src] as u32 {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
+            JMP32_K_JSGT => {
+                if (reg[dst] as i32) > imm {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
+            JMP32_X_JSGT => {
+                if (reg[dst] as i32) > reg[src] as i32 {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
+            JMP32_K_JSGE => {
+                if (reg[dst] as i32) >= imm {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
+            JMP32_X_JSGE => {
+                if (reg[dst] as i32) >= reg[src] as i32 {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
+            JMP32_K_JSLT => {
+                if (reg[dst] as i32) < imm {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
+            JMP32_X_JSLT => {
+                if (reg[dst] as i32) < reg[src] as i32 {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
+            JMP32_K_JSLE => {
+                if (reg[dst] as i32) <= imm {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
+            JMP32_X_JSLE => {
+                if (reg[dst] as i32) <= reg[src] as i32 {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
+            JMP32_K_JSET => {
+                if reg[dst] as u32 & imm as u32 != 0 {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
+            JMP32_X_JSET => {
+                if reg[dst] as u32 & reg[src] as u32 != 0 {
+                    pc = (pc as i16 + off) as u16;
+                }
+            }
             LD_IMM_DW => {
                 let next = insts[pc as usize];
                 pc += 1;
-                reg[dst] = (imm as u64 & u32::MAX as u64) + ((next >> 32) << 32);
+                reg[dst] = match src as u32 {
+                    BPF_PSEUDO_MAP_FD => match vm.map_handle(imm as u32) {
+                        Some(handle) => handle,
+                        None => {
+                            let (pc, fd) = (pc as usize - 2, imm as u32);
+                            return Err(Error::InvalidMapFd { pc, fd });
+                        }
+                    },
+                    BPF_PSEUDO_FUNC => (pc as i64 - 1 + imm as i64) as u64,
+                    _ => (imm as u64 & u32::MAX as u64) + ((next >> 32) << 32),
+                };
             }
             /*
             TODO: non generic inst
@@ -270,40 +435,54 @@ pub fn interpret(insts: &[u64], helpers: &[Helper], ctx: u64) -> u64 {
             LD_IND_DW => {}
             */
             LDX_MEM_B => unsafe {
-                reg[dst] = *((reg[src] as *mut u8).offset(off as isize) as *mut u8) as u64;
+                reg[dst] = (reg[src] as *const u8)
+                    .offset(off as isize)
+                    .read_unaligned() as u64;
             },
             LDX_MEM_H => unsafe {
-                reg[dst] = *((reg[src] as *mut u8).offset(off as isize) as *mut u16) as u64;
+                reg[dst] = ((reg[src] as *const u8).offset(off as isize) as *const u16)
+                    .read_unaligned() as u64;
             },
             LDX_MEM_W => unsafe {
-                reg[dst] = *((reg[src] as *mut u8).offset(off as isize) as *mut u32) as u64;
+                reg[dst] = ((reg[src] as *const u8).offset(off as isize) as *const u32)
+                    .read_unaligned() as u64;
             },
             LDX_MEM_DW => unsafe {
-                reg[dst] = *((reg[src] as *mut u8).offset(off as isize) as *mut u64) as u64;
+                reg[dst] =
+                    ((reg[src] as *const u8).offset(off as isize) as *const u64).read_unaligned();
             },
             ST_MEM_B => unsafe {
-                *((reg[dst] as *mut u8).offset(off as isize) as *mut u8) = imm as u8;
+                (reg[dst] as *mut u8)
+                    .offset(off as isize)
+                    .write_unaligned(imm as u8);
             },
             ST_MEM_H => unsafe {
-                *((reg[dst] as *mut u8).offset(off as isize) as *mut u16) = imm as u16;
+                ((reg[dst] as *mut u8).offset(off as isize) as *mut u16)
+                    .write_unaligned(imm as u16);
             },
             ST_MEM_W => unsafe {
-                *((reg[dst] as *mut u8).offset(off as isize) as *mut u32) = imm as u32;
+                ((reg[dst] as *mut u8).offset(off as isize) as *mut u32)
+                    .write_unaligned(imm as u32);
             },
             ST_MEM_DW => unsafe {
-                *((reg[dst] as *mut u8).offset(off as isize) as *mut u64) = imm as u64;
+                ((reg[dst] as *mut u8).offset(off as isize) as *mut u64)
+                    .write_unaligned(imm as u64);
             },
             STX_MEM_B => unsafe {
-                *((reg[dst] as *mut u8).offset(off as isize) as *mut u8) = reg[src] as u8;
+                (reg[dst] as *mut u8)
+                    .offset(off as isize)
+                    .write_unaligned(reg[src] as u8);
             },
             STX_MEM_H => unsafe {
-                *((reg[dst] as *mut u8).offset(off as isize) as *mut u16) = reg[src] as u16;
+                ((reg[dst] as *mut u8).offset(off as isize) as *mut u16)
+                    .write_unaligned(reg[src] as u16);
             },
             STX_MEM_W => unsafe {
-                *((reg[dst] as *mut u8).offset(off as isize) as *mut u32) = reg[src] as u32;
+                ((reg[dst] as *mut u8).offset(off as isize) as *mut u32)
+                    .write_unaligned(reg[src] as u32);
             },
             STX_MEM_DW => unsafe {
-                *((reg[dst] as *mut u8).offset(off as isize) as *mut u64) = reg[src] as u64;
+                ((reg[dst] as *mut u8).offset(off as isize) as *mut u64).write_unaligned(reg[src]);
             },
             _ => {
                 unimplemented!("op: {:x}, pc: {:x}", op, pc);
@@ -314,7 +493,17 @@ pub fn interpret(insts: &[u64], helpers: &[Helper], ctx: u64) -> u64 {
 
 #[cfg(test)]
 mod test {
-    use crate::interpret::{interpret, Helper};
+    use crate::consts::*;
+    use crate::helpers::timer::TimerHost;
+    use crate::helpers::{BpfFunc, HelperRegistry};
+    use crate::interpret::{fire_timer, interpret, Error};
+    use crate::kfunc::{KfuncProto, KfuncType};
+    use crate::map::{self, MapDef};
+    use crate::types::*;
+    use crate::vm::Vm;
+    use std::cell::{Cell, RefCell};
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
 
     unsafe fn bpf_trace_printk(fmt: u64, fmt_size: u64, p1: u64, p2: u64, p3: u64) -> u64 {
         let fmt = core::slice::from_raw_parts(fmt as *const u8, fmt_size as u32 as usize);
@@ -328,8 +517,10 @@ mod test {
     #[test]
     fn gauss() {
         let prog = include_bytes!("tests/gauss.bin");
-        let mut helpers: [Helper; 16] = [|_, _, _, _, _| 0; 16];
-        helpers[6] = bpf_trace_printk;
+        let mut helpers = HelperRegistry::new();
+        helpers.register(BpfFunc::TracePrintk, |_, r1, r2, r3, r4, r5| unsafe {
+            bpf_trace_printk(r1, r2, r3, r4, r5)
+        });
         let ret = interpret(
             &prog
                 .chunks_exact(8)
@@ -341,9 +532,431 @@ mod test {
                     })
                 })
                 .collect::<Vec<u64>>(),
-            &helpers,
+            &mut helpers,
+            &mut Vm::default(),
             0,
         );
-        assert_eq!(ret, 5050);
+        assert_eq!(ret, Ok(5050));
+    }
+
+    fn inst(op: u8, dst: u64, src: u64, off: i16, imm: i32) -> u64 {
+        op as u64 | dst << 8 | src << 12 | (off as u16 as u64) << 16 | (imm as u32 as u64) << 32
+    }
+
+    #[test]
+    fn map_helpers() {
+        // u32 key = 0; u64 *value = bpf_map_lookup_elem(&map, &key);
+        // if (value) *value += 1; else bpf_map_update_elem(&map, &key, &key, BPF_ANY);
+        let prog = [
+            inst(LD_IMM_DW, 1, BPF_PSEUDO_MAP_FD as u64, 0, 0),
+            inst(0, 0, 0, 0, 0),
+            inst(ST_MEM_DW, 10, 0, -8, 0),
+            inst(ALU64_X_MOV, 2, 10, 0, 0),
+            inst(ALU64_K_ADD, 2, 0, 0, -8),
+            inst(JMP_K_CALL, 0, 0, 0, 1),
+            inst(JMP_K_JEQ, 0, 0, 4, 0),
+            inst(LDX_MEM_DW, 1, 0, 0, 0),
+            inst(ALU64_K_ADD, 1, 0, 0, 1),
+            inst(STX_MEM_DW, 0, 1, 0, 0),
+            inst(JMP_K_EXIT, 0, 0, 0, 0),
+            inst(LD_IMM_DW, 1, BPF_PSEUDO_MAP_FD as u64, 0, 0),
+            inst(0, 0, 0, 0, 0),
+            inst(ALU64_X_MOV, 2, 10, 0, 0),
+            inst(ALU64_K_ADD, 2, 0, 0, -8),
+            inst(ALU64_X_MOV, 3, 2, 0, 0),
+            inst(ALU64_K_MOV, 4, 0, 0, BPF_ANY as i32),
+            inst(JMP_K_CALL, 0, 0, 0, 2),
+            inst(JMP_K_EXIT, 0, 0, 0, 0),
+        ];
+        let mut helpers = HelperRegistry::with_builtins();
+        let def = MapDef {
+            map_type: BPF_MAP_TYPE_HASH,
+            key_size: 8,
+            value_size: 8,
+            max_entries: 1,
+            ..Default::default()
+        };
+        let mut vm = Vm::default();
+        vm.maps.push(map::create(&def, 0).unwrap());
+        assert_eq!(interpret(&prog, &mut helpers, &mut vm, 0), Ok(0));
+        for _ in 0..3 {
+            interpret(&prog, &mut helpers, &mut vm, 0).unwrap();
+        }
+        let handle = vm.map_handle(0).unwrap();
+        let value = vm.map(handle).unwrap().lookup(&[0; 8]).unwrap();
+        assert_eq!(value, 3u64.to_ne_bytes());
+        assert_eq!(vm.map_handle(1), None);
+        assert!(vm.map(handle + 1).is_none());
+
+        // u64 *value = bpf_map_lookup_elem(&map, &key);
+        // bpf_map_update_elem(&map, &key, value, BPF_ANY);
+        // bpf_map_delete_elem(&map, &key); *value = 7; return *value;
+        let map = |dst| {
+            [
+                inst(LD_IMM_DW, dst, BPF_PSEUDO_MAP_FD as u64, 0, 0),
+                inst(0, 0, 0, 0, 0),
+                inst(ALU64_X_MOV, 2, 10, 0, 0),
+                inst(ALU64_K_ADD, 2, 0, 0, -8),
+            ]
+        };
+        let prog = [
+            &[inst(ST_MEM_DW, 10, 0, -8, 0)][..],
+            &map(1),
+            &[
+                inst(JMP_K_CALL, 0, 0, 0, 1),
+                inst(JMP_K_JEQ, 0, 0, 15, 0),
+                inst(ALU64_X_MOV, 6, 0, 0, 0),
+            ],
+            &map(1),
+            &[
+                inst(ALU64_X_MOV, 3, 6, 0, 0),
+                inst(ALU64_K_MOV, 4, 0, 0, BPF_ANY as i32),
+                inst(JMP_K_CALL, 0, 0, 0, 2),
+            ],
+            &map(1),
+            &[
+                inst(JMP_K_CALL, 0, 0, 0, 3),
+                // the deleted value lives until the program returns
+                inst(ST_MEM_DW, 6, 0, 0, 7),
+                inst(LDX_MEM_DW, 0, 6, 0, 0),
+                inst(JMP_K_EXIT, 0, 0, 0, 0),
+            ],
+        ]
+        .concat();
+        assert_eq!(interpret(&prog, &mut helpers, &mut vm, 0), Ok(7));
+        assert!(vm.map(handle).unwrap().lookup(&[0; 8]).is_none());
+
+        let prog = [
+            inst(LD_IMM_DW, 1, BPF_PSEUDO_MAP_FD as u64, 0, 1),
+            inst(0, 0, 0, 0, 0),
+            inst(JMP_K_EXIT, 0, 0, 0, 0),
+        ];
+        assert_eq!(
+            interpret(&prog, &mut helpers, &mut vm, 0),
+            Err(Error::InvalidMapFd { pc: 0, fd: 1 })
+        );
+    }
+
+    #[test]
+    fn stateful_helpers() {
+        let prog = [
+            inst(JMP_K_CALL, 0, 0, 0, 0x1000),
+            inst(JMP_K_CALL, 0, 0, 0, BpfFunc::GetPrandomU32 as i32),
+            inst(JMP_K_EXIT, 0, 0, 0, 0),
+        ];
+        let mut helpers = HelperRegistry::new();
+        let mut calls = 0;
+        helpers.register_id(0x1000, move |vm, _, _, _, _, _| {
+            calls += 1;
+            *vm.host_mut::<u64>().unwrap() += calls;
+            0
+        });
+        let mut vm = Vm {
+            host: Some(Box::new(10u64)),
+            ..Default::default()
+        };
+        assert_eq!(
+            interpret(&prog, &mut helpers, &mut vm, 0),
+            Err(Error::UnknownHelper { pc: 1, id: 7 })
+        );
+        helpers.register(BpfFunc::GetPrandomU32, |vm, _, _, _, _, _| {
+            *vm.host_mut::<u64>().unwrap()
+        });
+        assert_eq!(interpret(&prog, &mut helpers, &mut vm, 0), Ok(13));
+        assert_eq!(BpfFunc::from_id(7), Some(BpfFunc::GetPrandomU32));
+        assert_eq!(BpfFunc::from_id(0x1000), None);
+    }
+
+    #[test]
+    fn callbacks() {
+        let func = BPF_PSEUDO_FUNC as u64;
+        // u64 sum = 0; long n = bpf_loop(10, add, &sum, 0); return sum * 1000 + n;
+        // where add stops after index 4
+        let prog = [
+            inst(ST_MEM_DW, 10, 0, -8, 0),
+            inst(ALU64_K_MOV, 1, 0, 0, 10),
+            inst(LD_IMM_DW, 2, func, 0, 10),
+            inst(0, 0, 0, 0, 0),
+            inst(ALU64_X_MOV, 3, 10, 0, 0),
+            inst(ALU64_K_ADD, 3, 0, 0, -8),
+            inst(ALU64_K_MOV, 4, 0, 0, 0),
+            inst(JMP_K_CALL, 0, 0, 0, BpfFunc::Loop as i32),
+            inst(ALU64_X_MOV, 6, 0, 0, 0),
+            inst(LDX_MEM_DW, 0, 10, -8, 0),
+            inst(ALU64_K_MUL, 0, 0, 0, 1000),
+            inst(ALU64_X_ADD, 0, 6, 0, 0),
+            inst(JMP_K_EXIT, 0, 0, 0, 0),
+            inst(LDX_MEM_DW, 3, 2, 0, 0),
+            inst(ALU64_X_ADD, 3, 1, 0, 0),
+            inst(STX_MEM_DW, 2, 3, 0, 0),
+            inst(ALU64_K_MOV, 0, 0, 0, 0),
+            inst(JMP_K_JNE, 1, 0, 1, 4),
+            inst(ALU64_K_MOV, 0, 0, 0, 1),
+            inst(JMP_K_EXIT, 0, 0, 0, 0),
+        ];
+        let mut helpers = HelperRegistry::new();
+        let mut vm = Vm::default();
+        assert_eq!(interpret(&prog, &mut helpers, &mut vm, 0), Ok(10005));
+
+        // u64 sum = 0; long n = bpf_for_each_map_elem(&map, add, &sum, 0);
+        // return sum * 100 + n;
+        let prog = [
+            inst(ST_MEM_DW, 10, 0, -8, 0),
+            inst(LD_IMM_DW, 1, BPF_PSEUDO_MAP_FD as u64, 0, 0),
+            inst(0, 0, 0, 0, 0),
+            inst(LD_IMM_DW, 2, func, 0, 10),
+            inst(0, 0, 0, 0, 0),
+            inst(ALU64_X_MOV, 3, 10, 0, 0),
+            inst(ALU64_K_ADD, 3, 0, 0, -8),
+            inst(ALU64_K_MOV, 4, 0, 0, 0),
+            inst(JMP_K_CALL, 0, 0, 0, BpfFunc::ForEachMapElem as i32),
+            inst(ALU64_X_MOV, 6, 0, 0, 0),
+            inst(LDX_MEM_DW, 0, 10, -8, 0),
+            inst(ALU64_K_MUL, 0, 0, 0, 100),
+            inst(ALU64_X_ADD, 0, 6, 0, 0),
+            inst(JMP_K_EXIT, 0, 0, 0, 0),
+            inst(LDX_MEM_DW, 5, 3, 0, 0),
+            inst(LDX_MEM_DW, 0, 4, 0, 0),
+            inst(ALU64_X_ADD, 0, 5, 0, 0),
+            inst(STX_MEM_DW, 4, 0, 0, 0),
+            inst(ALU64_K_MOV, 0, 0, 0, 0),
+            inst(JMP_K_EXIT, 0, 0, 0, 0),
+        ];
+        let def = MapDef {
+            map_type: BPF_MAP_TYPE_ARRAY,
+            key_size: 4,
+            value_size: 8,
+            max_entries: 4,
+            ..Default::default()
+        };
+        let mut array = map::create(&def, 0).unwrap();
+        for i in 0..4u32 {
+            let value = (i as u64 + 1).to_ne_bytes();
+            array
+                .update(&i.to_ne_bytes(), &value, BPF_ANY as u64)
+                .unwrap();
+        }
+        vm.maps.push(array);
+        assert_eq!(interpret(&prog, &mut helpers, &mut vm, 0), Ok(1004));
+
+        // a callback that loops over itself runs out of frames
+        let prog = [
+            inst(ALU64_K_MOV, 1, 0, 0, 1),
+            inst(LD_IMM_DW, 2, func, 0, -2),
+            inst(0, 0, 0, 0, 0),
+            inst(ALU64_K_MOV, 3, 0, 0, 0),
+            inst(ALU64_K_MOV, 4, 0, 0, 0),
+            inst(JMP_K_CALL, 0, 0, 0, BpfFunc::Loop as i32),
+            inst(JMP_K_EXIT, 0, 0, 0, 0),
+        ];
+        assert_eq!(
+            interpret(&prog, &mut helpers, &mut vm, 0),
+            Err(Error::CallDepth { pc: 5 })
+        );
+    }
+
+    #[test]
+    fn timers() {
+        struct Clock(Rc<RefCell<BTreeMap<u64, u64>>>, Rc<Cell<u64>>);
+
+        impl TimerHost for Clock {
+            fn start(&mut self, id: u64, _: u32, nsecs: u64, flags: u64) {
+                let abs = flags & BPF_F_TIMER_ABS as u64 != 0;
+                let expires = if abs { nsecs } else { self.1.get() + nsecs };
+                self.0.borrow_mut().insert(id, expires);
+            }
+
+            fn cancel(&mut self, id: u64) -> bool {
+                self.0.borrow_mut().remove(&id).is_some()
+            }
+        }
+
+        let map = BPF_PSEUDO_MAP_FD as u64;
+        // struct { u64 count; struct bpf_timer timer; } *value = lookup(&map, &0);
+        // bpf_timer_init(&value->timer, &map, CLOCK_MONOTONIC);
+        // bpf_timer_set_callback(&value->timer, tick);
+        // return bpf_timer_start(&value->timer, 100, 0);
+        // where tick increments count and rearms the timer until it reaches 3
+        let prog = [
+            inst(ST_MEM_DW, 10, 0, -8, 0),
+            inst(LD_IMM_DW, 1, map, 0, 0),
+            inst(0, 0, 0, 0, 0),
+            inst(ALU64_X_MOV, 2, 10, 0, 0),
+            inst(ALU64_K_ADD, 2, 0, 0, -8),
+            inst(JMP_K_CALL, 0, 0, 0, BpfFunc::MapLookupElem as i32),
+            inst(JMP_K_JNE, 0, 0, 2, 0),
+            inst(ALU64_K_MOV, 0, 0, 0, 1),
+            inst(JMP_K_EXIT, 0, 0, 0, 0),
+            inst(ALU64_X_MOV, 6, 0, 0, 0),
+            inst(ALU64_K_ADD, 6, 0, 0, 8),
+            inst(ALU64_X_MOV, 1, 6, 0, 0),
+            inst(LD_IMM_DW, 2, map, 0, 0),
+            inst(0, 0, 0, 0, 0),
+            inst(ALU64_K_MOV, 3, 0, 0, CLOCK_MONOTONIC as i32),
+            inst(JMP_K_CALL, 0, 0, 0, BpfFunc::TimerInit as i32),
+            inst(ALU64_X_MOV, 1, 6, 0, 0),
+            inst(LD_IMM_DW, 2, BPF_PSEUDO_FUNC as u64, 0, 7),
+            inst(0, 0, 0, 0, 0),
+            inst(JMP_K_CALL, 0, 0, 0, BpfFunc::TimerSetCallback as i32),
+            inst(ALU64_X_MOV, 1, 6, 0, 0),
+            inst(ALU64_K_MOV, 2, 0, 0, 100),
+            inst(ALU64_K_MOV, 3, 0, 0, 0),
+            inst(JMP_K_CALL, 0, 0, 0, BpfFunc::TimerStart as i32),
+            inst(JMP_K_EXIT, 0, 0, 0, 0),
+            inst(LDX_MEM_DW, 1, 3, 0, 0),
+            inst(ALU64_K_ADD, 1, 0, 0, 1),
+            inst(STX_MEM_DW, 3, 1, 0, 0),
+            inst(ALU64_K_MOV, 0, 0, 0, 0),
+            inst(JMP_K_JGE, 1, 0, 5, 3),
+            inst(ALU64_X_MOV, 1, 3, 0, 0),
+            inst(ALU64_K_ADD, 1, 0, 0, 8),
+            inst(ALU64_K_MOV, 2, 0, 0, 100),
+            inst(ALU64_K_MOV, 3, 0, 0, 0),
+            inst(JMP_K_CALL, 0, 0, 0, BpfFunc::TimerStart as i32),
+            inst(JMP_K_EXIT, 0, 0, 0, 0),
+        ];
+        let def = MapDef {
+            map_type: BPF_MAP_TYPE_ARRAY,
+            key_size: 4,
+            value_size: 24,
+            max_entries: 1,
+            timer_off: Some(8),
+            ..Default::default()
+        };
+        let pending = Rc::new(RefCell::new(BTreeMap::new()));
+        let now = Rc::new(Cell::new(0));
+        let mut helpers = HelperRegistry::with_builtins();
+        let mut vm = Vm {
+            timer_host: Some(Box::new(Clock(pending.clone(), now.clone()))),
+            ..Default::default()
+        };
+        vm.maps.push(map::create(&def, 0).unwrap());
+        assert_eq!(interpret(&prog, &mut helpers, &mut vm, 0), Ok(0));
+        assert_eq!(
+            interpret(&prog, &mut helpers, &mut vm, 0),
+            Ok(0),
+            "restarting an initialized timer"
+        );
+
+        let mut fired = 0;
+        loop {
+            let next = pending.borrow().iter().map(|(&id, &at)| (at, id)).min();
+            let (at, id) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            pending.borrow_mut().remove(&id);
+            now.set(at);
+            assert_eq!(fire_timer(&prog, &mut helpers, &mut vm, id), Ok(Some(0)));
+            fired += 1;
+        }
+        assert_eq!((fired, now.get()), (3, 300));
+        let handle = vm.map_handle(0).unwrap();
+        let value = vm.map(handle).unwrap().lookup(&[0; 4]).unwrap();
+        assert_eq!(value[..8], 3u64.to_ne_bytes());
+        assert_eq!(fire_timer(&prog, &mut helpers, &mut vm, 2), Ok(None));
+    }
+
+    #[test]
+    fn kfuncs() {
+        let kfunc = BPF_PSEUDO_KFUNC_CALL as u64;
+        let prog = [
+            inst(ALU64_K_MOV, 1, 0, 0, -3),
+            inst(JMP_K_CALL, 0, kfunc, 0, 100),
+            inst(JMP_K_EXIT, 0, 0, 0, 0),
+        ];
+        let mut helpers = HelperRegistry::new();
+        let mut vm = Vm::default();
+        assert_eq!(
+            interpret(&prog, &mut helpers, &mut vm, 0),
+            Err(Error::UnknownKfunc { pc: 1, id: 100 })
+        );
+        let proto = KfuncProto {
+            args: vec![KfuncType::U32],
+            ret: KfuncType::U64,
+        };
+        helpers
+            .kfuncs()
+            .register(100, "bpf_double", proto, |_, args| args[0] * 2)
+            .unwrap();
+        assert!(!helpers.contains(100));
+        assert_eq!(
+            interpret(&prog, &mut helpers, &mut vm, 0),
+            Ok(0xffff_fffd * 2)
+        );
+    }
+
+    #[test]
+    fn jmp32() {
+        // r0 = 0x1_ffff_ffff; if ((s32)w0 < 0 && w0 == -1) return 1; return r0;
+        let prog = [
+            inst(LD_IMM_DW, 0, 0, 0, -1),
+            inst(0, 0, 0, 0, 1),
+            inst(JMP32_K_JSGE, 0, 0, 3, 0),
+            inst(JMP32_K_JNE, 0, 0, 2, -1),
+            inst(ALU64_K_MOV, 0, 0, 0, 1),
+            inst(JMP_K_EXIT, 0, 0, 0, 0),
+            inst(JMP_K_EXIT, 0, 0, 0, 0),
+        ];
+        let mut helpers = HelperRegistry::new();
+        assert_eq!(interpret(&prog, &mut helpers, &mut Vm::default(), 0), Ok(1));
+    }
+
+    #[test]
+    fn division() {
+        let run = |op, dst: i32, src: i32| {
+            let prog = [
+                inst(ALU64_K_MOV, 0, 0, 0, dst),
+                inst(ALU64_K_MOV, 1, 0, 0, src),
+                inst(op, 0, 1, 0, 0),
+                inst(JMP_K_EXIT, 0, 0, 0, 0),
+            ];
+            let mut helpers = HelperRegistry::new();
+            interpret(&prog, &mut helpers, &mut Vm::default(), 0).unwrap()
+        };
+        assert_eq!(run(ALU64_X_DIV, 7, 2), 3);
+        assert_eq!(run(ALU64_X_DIV, 7, 0), 0);
+        assert_eq!(run(ALU_X_DIV, 7, 0), 0);
+        assert_eq!(run(ALU64_X_MOD, 7, 2), 1);
+        assert_eq!(run(ALU64_X_MOD, -7, 0), -7i64 as u64);
+        // a 32-bit result is zero-extended
+        assert_eq!(run(ALU_X_MOD, -7, 0), -7i32 as u32 as u64);
+        assert_eq!(run(ALU64_X_LSH, 1, 65), 2);
+        assert_eq!(run(ALU64_X_RSH, 4, 66), 1);
+        assert_eq!(run(ALU64_X_ARSH, -4, 65), -2i64 as u64);
+        assert_eq!(run(ALU_X_LSH, 1, 33), 2);
+    }
+
+    #[test]
+    fn alu32() {
+        let run = |op, dst: i32, src: i32| {
+            let prog = [
+                inst(ALU64_K_MOV, 0, 0, 0, dst),
+                inst(ALU64_K_MOV, 1, 0, 0, src),
+                inst(op, 0, 1, 0, 0),
+                inst(JMP_K_EXIT, 0, 0, 0, 0),
+            ];
+            let mut helpers = HelperRegistry::new();
+            interpret(&prog, &mut helpers, &mut Vm::default(), 0).unwrap()
+        };
+        // every 32-bit result is zero-extended
+        let max = u32::MAX as u64;
+        assert_eq!(run(ALU_X_ADD, -1, 0), max);
+        assert_eq!(run(ALU_X_SUB, 0, 1), max);
+        assert_eq!(run(ALU_X_MUL, -1, 1), max);
+        assert_eq!(run(ALU_X_OR, -1, 0), max);
+        assert_eq!(run(ALU_X_ARSH, -2, 1), max);
+        assert_eq!(run(ALU_X_MOV, 0, -1), max);
+        let neg = inst(ALU_K_NEG, 0, 0, 0, 0);
+        let prog = [
+            inst(ALU64_K_MOV, 0, 0, 0, 1),
+            neg,
+            inst(JMP_K_EXIT, 0, 0, 0, 0),
+        ];
+        let mut helpers = HelperRegistry::new();
+        assert_eq!(
+            interpret(&prog, &mut helpers, &mut Vm::default(), 0),
+            Ok(max)
+        );
     }
 }
@@ -0,0 +1,209 @@
+//! Kernel functions called with `BPF_PSEUDO_KFUNC_CALL`. Unlike helpers they
+//! have no fixed ids: the kernel identifies them by the BTF id of their
+//! prototype, and programs refer to them by name until relocated.
+
+use crate::errno::*;
+use crate::vm::Vm;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Argument or return type of a kfunc, as its BTF prototype describes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KfuncType {
+    /// No value, only valid as the return type.
+    Void,
+    /// An integer of `size` bytes.
+    Int { size: u8, signed: bool },
+    /// A pointer, passed as is.
+    Ptr,
+}
+
+impl KfuncType {
+    pub const U32: KfuncType = KfuncType::Int {
+        size: 4,
+        signed: false,
+    };
+    pub const U64: KfuncType = KfuncType::Int {
+        size: 8,
+        signed: false,
+    };
+    pub const I32: KfuncType = KfuncType::Int {
+        size: 4,
+        signed: true,
+    };
+    pub const I64: KfuncType = KfuncType::Int {
+        size: 8,
+        signed: true,
+    };
+
+    /// Truncates and extends a register to this type, as passing it to or
+    /// returning it from a C function would.
+    pub fn cast(self, value: u64) -> u64 {
+        match self {
+            KfuncType::Void => 0,
+            KfuncType::Int { size: 8, .. } | KfuncType::Ptr => value,
+            KfuncType::Int { size, signed } => {
+                let shift = 64 - size as u32 * 8;
+                if signed {
+                    ((value << shift) as i64 >> shift) as u64
+                } else {
+                    value << shift >> shift
+                }
+            }
+        }
+    }
+
+    fn valid(self, ret: bool) -> bool {
+        match self {
+            KfuncType::Void => ret,
+            KfuncType::Int { size, .. } => matches!(size, 1 | 2 | 4 | 8),
+            KfuncType::Ptr => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KfuncProto {
+    pub args: Vec<KfuncType>,
+    pub ret: KfuncType,
+}
+
+/// A kfunc receives exactly the arguments its prototype declares, cast to
+/// their types.
+pub type Kfunc = dyn FnMut(&mut Vm, &[u64]) -> u64;
+
+struct Entry {
+    name: String,
+    proto: KfuncProto,
+    func: Box<Kfunc>,
+}
+
+#[derive(Default)]
+pub struct KfuncRegistry {
+    kfuncs: BTreeMap<u32, Entry>,
+    ids: BTreeMap<String, u32>,
+}
+
+impl KfuncRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `func` as kfunc `name` with BTF id `id`, replacing any
+    /// previous kfunc with that id. Fails with `EINVAL` for a prototype
+    /// with more than five arguments or invalid types, and `EEXIST` if
+    /// `name` is taken by another id.
+    pub fn register<F>(
+        &mut self,
+        id: u32,
+        name: &str,
+        proto: KfuncProto,
+        func: F,
+    ) -> Result<(), i32>
+    where
+        F: FnMut(&mut Vm, &[u64]) -> u64 + 'static,
+    {
+        if proto.args.len() > 5
+            || !proto.ret.valid(true)
+            || !proto.args.iter().all(|arg| arg.valid(false))
+        {
+            return Err(EINVAL);
+        }
+        if self.ids.get(name).is_some_and(|&other| other != id) {
+            return Err(EEXIST);
+        }
+        self.unregister(id);
+        self.ids.insert(name.into(), id);
+        let entry = Entry {
+            name: name.into(),
+            proto,
+            func: Box::new(func),
+        };
+        self.kfuncs.insert(id, entry);
+        Ok(())
+    }
+
+    pub fn unregister(&mut self, id: u32) -> bool {
+        match self.kfuncs.remove(&id) {
+            Some(entry) => {
+                self.ids.remove(&entry.name);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the id of kfunc `name`, for relocating calls by name.
+    pub fn resolve(&self, name: &str) -> Option<u32> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn proto(&self, id: u32) -> Option<&KfuncProto> {
+        self.kfuncs.get(&id).map(|entry| &entry.proto)
+    }
+
+    /// Calls kfunc `id` with r1-r5, or returns `None` if none is registered.
+    pub fn call(&mut self, vm: &mut Vm, id: u32, regs: [u64; 5]) -> Option<u64> {
+        let entry = self.kfuncs.get_mut(&id)?;
+        let mut args = [0; 5];
+        for (arg, (ty, reg)) in args.iter_mut().zip(entry.proto.args.iter().zip(regs)) {
+            *arg = ty.cast(reg);
+        }
+        let ret = (entry.func)(vm, &args[..entry.proto.args.len()]);
+        Some(entry.proto.ret.cast(ret))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn registry() {
+        let mut kfuncs = KfuncRegistry::new();
+        let proto = KfuncProto {
+            args: vec![KfuncType::I32, KfuncType::Ptr],
+            ret: KfuncType::I64,
+        };
+        kfuncs
+            .register(100, "bpf_add", proto.clone(), |_, args| {
+                assert_eq!(args.len(), 2);
+                args[0].wrapping_add(args[1])
+            })
+            .unwrap();
+        assert_eq!(kfuncs.resolve("bpf_add"), Some(100));
+        assert_eq!(kfuncs.proto(100), Some(&proto));
+        let ret = kfuncs.call(&mut Vm::default(), 100, [-2i32 as u32 as u64, 1, 7, 7, 7]);
+        assert_eq!(ret, Some(-1i64 as u64));
+        assert_eq!(kfuncs.call(&mut Vm::default(), 101, [0; 5]), None);
+
+        assert_eq!(
+            kfuncs.register(101, "bpf_add", proto.clone(), |_, _| 0),
+            Err(EEXIST)
+        );
+        let bad = KfuncProto {
+            args: vec![KfuncType::Void],
+            ret: KfuncType::Void,
+        };
+        assert_eq!(kfuncs.register(101, "bpf_nop", bad, |_, _| 0), Err(EINVAL));
+        let nop = KfuncProto {
+            args: vec![],
+            ret: KfuncType::Void,
+        };
+        kfuncs.register(100, "bpf_nop", nop, |_, _| 1).unwrap();
+        assert_eq!(kfuncs.resolve("bpf_add"), None);
+        assert_eq!(kfuncs.call(&mut Vm::default(), 100, [0; 5]), Some(0));
+        assert!(kfuncs.unregister(100));
+        assert_eq!(kfuncs.resolve("bpf_nop"), None);
+
+        let u8 = KfuncType::Int {
+            size: 1,
+            signed: false,
+        };
+        assert_eq!(u8.cast(0x1ff), 0xff);
+        assert_eq!(KfuncType::I32.cast(0xffff_ffff), u64::MAX);
+        assert_eq!(KfuncType::U32.cast(u64::MAX), 0xffff_ffff);
+    }
+}
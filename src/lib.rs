@@ -1,4 +1,12 @@
 #![cfg_attr(not(test), no_std)]
+extern crate alloc;
+
 pub mod consts;
+pub mod errno;
+pub mod helpers;
 pub mod interpret;
+pub mod kfunc;
+pub mod map;
 pub mod types;
+pub mod verifier;
+pub mod vm;
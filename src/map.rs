@@ -0,0 +1,472 @@
+use crate::consts::*;
+use crate::errno::*;
+use crate::helpers::spin_lock::{spin_lock, spin_unlock};
+use crate::helpers::timer::BPF_TIMER_SIZE;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::ops::{Deref, DerefMut};
+
+mod array;
+mod bloom_filter;
+mod hash;
+mod jhash;
+mod stack_trace;
+
+pub use array::ArrayMap;
+pub use bloom_filter::BloomFilterMap;
+pub use hash::HashMap;
+pub use stack_trace::StackTraceMap;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MapDef {
+    pub map_type: u32,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+    pub map_flags: u32,
+    pub map_extra: u64,
+    /// Offset of the `struct bpf_spin_lock` in the value, which the kernel
+    /// learns from BTF.
+    pub spin_lock_off: Option<u32>,
+    /// Offset of the `struct bpf_timer` in the value, likewise from BTF.
+    pub timer_off: Option<u32>,
+}
+
+impl MapDef {
+    /// Spin lock and timer fields as sorted `(offset, size)` pairs.
+    pub(crate) fn special_fields(&self) -> impl Iterator<Item = (usize, usize)> {
+        let lock = self.spin_lock_off.map(|off| (off as usize, 4));
+        let timer = self.timer_off.map(|off| (off as usize, BPF_TIMER_SIZE));
+        let mut fields = [lock, timer];
+        fields.sort();
+        IntoIterator::into_iter(fields).flatten()
+    }
+
+    /// Rejects misplaced or overlapping spin lock and timer fields, or any
+    /// such field if `allowed` is unset.
+    pub(crate) fn check_fields(&self, allowed: bool) -> Result<(), i32> {
+        let mut end = 0;
+        for (off, size) in self.special_fields() {
+            if !allowed {
+                return Err(EOPNOTSUPP);
+            }
+            if off % size.min(8) != 0 || off < end || off + size > self.value_size as usize {
+                return Err(EINVAL);
+            }
+            end = off + size;
+        }
+        Ok(())
+    }
+
+    /// Rejects `flags` beyond `BPF_F_LOCK` plus a mode up to `max`, and
+    /// `BPF_F_LOCK` on values without a spin lock.
+    pub(crate) fn check_flags(&self, flags: u64, max: u32) -> Result<(), i32> {
+        if flags & !(BPF_F_LOCK as u64) > max as u64
+            || flags & BPF_F_LOCK as u64 != 0 && self.spin_lock_off.is_none()
+        {
+            return Err(EINVAL);
+        }
+        Ok(())
+    }
+}
+
+/// Zeroed bytes aligned to 8, as the kernel's allocations are, so that spin
+/// locks and timers in values can be accessed atomically.
+#[derive(Debug, Clone)]
+pub(crate) struct AlignedBytes {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl AlignedBytes {
+    pub fn zeroed(len: usize) -> Self {
+        AlignedBytes {
+            words: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+}
+
+impl Deref for AlignedBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.words.as_ptr() as *const u8, self.len) }
+    }
+}
+
+impl DerefMut for AlignedBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.words.as_mut_ptr() as *mut u8, self.len) }
+    }
+}
+
+/// Copies `src` into `dst` apart from the spin lock and timer fields,
+/// holding the lock of `dst` while doing so if `flags` has `BPF_F_LOCK`.
+pub(crate) fn copy_value(def: &MapDef, dst: &mut [u8], src: &[u8], flags: u64) {
+    let locked = flags & BPF_F_LOCK as u64 != 0;
+    let lock = def.spin_lock_off.map_or(core::ptr::null_mut(), |off| {
+        dst[off as usize..].as_mut_ptr() as *mut u32
+    });
+    unsafe {
+        if locked {
+            spin_lock(lock);
+        }
+        let mut start = 0;
+        for (off, size) in def.special_fields() {
+            dst[start..off].copy_from_slice(&src[start..off]);
+            start = off + size;
+        }
+        dst[start..].copy_from_slice(&src[start..]);
+        if locked {
+            spin_unlock(lock);
+        }
+    }
+}
+
+/// Operations shared by every map type, with keys and values passed as raw
+/// bytes of `key_size` and `value_size`. Errors are positive errno values.
+pub trait Map: Any {
+    fn def(&self) -> &MapDef;
+    /// Returns the value storage for `key`, which programs access in place.
+    fn lookup(&mut self, key: &[u8]) -> Option<&mut [u8]>;
+    fn update(&mut self, key: &[u8], value: &[u8], flags: u64) -> Result<(), i32>;
+    fn delete(&mut self, key: &[u8]) -> Result<(), i32>;
+    /// Writes the key following `key` into `next_key`, or the first key when
+    /// `key` is `None` or no longer present.
+    fn get_next_key(&self, key: Option<&[u8]>, next_key: &mut [u8]) -> Result<(), i32>;
+    /// Frees the storage of deleted elements, passing each value to `free`
+    /// first to release its special fields. Programs may still hold
+    /// pointers to it until their run returns, which RCU covers in the
+    /// kernel, so it is kept until then.
+    fn reclaim(&mut self, _free: &mut dyn FnMut(&mut [u8])) {}
+    /// Returns the key of the live element whose value starts at `value`,
+    /// which the kernel finds from the element's address. Only maps whose
+    /// values can hold a timer need it.
+    fn key_of(&self, _value: u64) -> Option<Vec<u8>> {
+        None
+    }
+    fn push(&mut self, _value: &[u8], _flags: u64) -> Result<(), i32> {
+        Err(EOPNOTSUPP)
+    }
+    /// Reads the head element into `value`; for bloom filters, `value` is
+    /// the element whose membership is tested instead.
+    fn peek(&mut self, _value: &mut [u8]) -> Result<(), i32> {
+        Err(EOPNOTSUPP)
+    }
+
+    /// Copies the value of `key` out the way `BPF_MAP_LOOKUP_ELEM` does, with
+    /// the spin lock and timer fields zeroed and the lock held during the
+    /// copy with `BPF_F_LOCK`.
+    fn lookup_elem(&mut self, key: &[u8], value: &mut [u8], flags: u64) -> Result<(), i32> {
+        let def = *self.def();
+        def.check_flags(flags, BPF_ANY)?;
+        let src = self.lookup(key).ok_or(ENOENT)?;
+        match def.spin_lock_off {
+            Some(off) => {
+                let off = off as usize;
+                let lock = src[off..].as_mut_ptr() as *mut u32;
+                let locked = flags & BPF_F_LOCK as u64 != 0;
+                unsafe {
+                    if locked {
+                        spin_lock(lock);
+                    }
+                    value.copy_from_slice(src);
+                    if locked {
+                        spin_unlock(lock);
+                    }
+                }
+            }
+            None => value.copy_from_slice(src),
+        }
+        for (off, size) in def.special_fields() {
+            value[off..off + size].fill(0);
+        }
+        Ok(())
+    }
+
+    /// Copies up to `*count` elements following the key `in_batch` (or from
+    /// the start when `None`) into `keys` and `values`, sets `*count` to the
+    /// number copied and `out_batch` to the key to resume from. Fails with
+    /// `ENOENT` once the map is exhausted, possibly after a partial batch.
+    fn lookup_batch(
+        &mut self,
+        in_batch: Option<&[u8]>,
+        out_batch: &mut [u8],
+        keys: &mut [u8],
+        values: &mut [u8],
+        count: &mut u32,
+        elem_flags: u64,
+    ) -> Result<(), i32> {
+        check_batch(self.def(), keys, Some(values), *count, elem_flags)?;
+        let (key_size, value_size) = (self.def().key_size as usize, self.def().value_size as usize);
+        let max_count = core::mem::take(count) as usize;
+        let mut prev_key = in_batch.map(|key| key.to_vec());
+        let mut key = vec![0; key_size];
+        let mut result = Ok(());
+        while (*count as usize) < max_count {
+            if let Err(err) = self.get_next_key(prev_key.as_deref(), &mut key) {
+                result = Err(err);
+                break;
+            }
+            let cp = *count as usize;
+            let value = &mut values[cp * value_size..(cp + 1) * value_size];
+            self.lookup_elem(&key, value, elem_flags)?;
+            keys[cp * key_size..(cp + 1) * key_size].copy_from_slice(&key);
+            prev_key = Some(key.clone());
+            *count += 1;
+        }
+        if let Some(prev_key) = prev_key.filter(|_| *count > 0) {
+            out_batch.copy_from_slice(&prev_key);
+        }
+        result
+    }
+
+    /// Like `lookup_batch`, but deletes the copied elements afterwards.
+    fn lookup_and_delete_batch(
+        &mut self,
+        in_batch: Option<&[u8]>,
+        out_batch: &mut [u8],
+        keys: &mut [u8],
+        values: &mut [u8],
+        count: &mut u32,
+        elem_flags: u64,
+    ) -> Result<(), i32> {
+        let result = self.lookup_batch(in_batch, out_batch, keys, values, count, elem_flags);
+        let key_size = self.def().key_size as usize;
+        for key in keys.chunks_exact(key_size).take(*count as usize) {
+            self.delete(key)?;
+        }
+        result
+    }
+
+    /// Updates `*count` elements from `keys` and `values` in order, stopping
+    /// at the first failure with `*count` set to the number updated.
+    fn update_batch(
+        &mut self,
+        keys: &[u8],
+        values: &[u8],
+        count: &mut u32,
+        elem_flags: u64,
+    ) -> Result<(), i32> {
+        check_batch(self.def(), keys, Some(values), *count, elem_flags)?;
+        let (key_size, value_size) = (self.def().key_size as usize, self.def().value_size as usize);
+        let max_count = core::mem::take(count) as usize;
+        for (key, value) in keys
+            .chunks_exact(key_size)
+            .zip(values.chunks_exact(value_size))
+            .take(max_count)
+        {
+            self.update(key, value, elem_flags)?;
+            *count += 1;
+        }
+        Ok(())
+    }
+
+    /// Deletes `*count` elements from `keys` in order, stopping at the first
+    /// failure with `*count` set to the number deleted.
+    fn delete_batch(&mut self, keys: &[u8], count: &mut u32, elem_flags: u64) -> Result<(), i32> {
+        check_batch(self.def(), keys, None, *count, elem_flags)?;
+        let key_size = self.def().key_size as usize;
+        let max_count = core::mem::take(count) as usize;
+        for key in keys.chunks_exact(key_size).take(max_count) {
+            self.delete(key)?;
+            *count += 1;
+        }
+        Ok(())
+    }
+}
+
+fn check_batch(
+    def: &MapDef,
+    keys: &[u8],
+    values: Option<&[u8]>,
+    count: u32,
+    elem_flags: u64,
+) -> Result<(), i32> {
+    def.check_flags(elem_flags, BPF_ANY)?;
+    let count = count as usize;
+    if def.key_size == 0
+        || keys.len() < count * def.key_size as usize
+        || values.is_some_and(|values| values.len() < count * def.value_size as usize)
+    {
+        return Err(EINVAL);
+    }
+    Ok(())
+}
+
+/// Creates a map of `def.map_type`. `seed` randomizes the hash functions of
+/// maps that use one unless `BPF_F_ZERO_SEED` is set, so hosts should pass a
+/// random value.
+pub fn create(def: &MapDef, seed: u32) -> Result<Box<dyn Map>, i32> {
+    match def.map_type {
+        BPF_MAP_TYPE_HASH => Ok(Box::new(HashMap::new(def)?)),
+        BPF_MAP_TYPE_ARRAY => Ok(Box::new(ArrayMap::new(def)?)),
+        BPF_MAP_TYPE_STACK_TRACE => Ok(Box::new(StackTraceMap::new(def)?)),
+        BPF_MAP_TYPE_BLOOM_FILTER => Ok(Box::new(BloomFilterMap::new(def, seed)?)),
+        _ => Err(EINVAL),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hash_map(max_entries: u32) -> Box<dyn Map> {
+        let def = MapDef {
+            map_type: BPF_MAP_TYPE_HASH,
+            key_size: 4,
+            value_size: 8,
+            max_entries,
+            ..Default::default()
+        };
+        create(&def, 0).unwrap()
+    }
+
+    #[test]
+    fn drain_in_batches() {
+        let mut map = hash_map(1000);
+        let keys: Vec<u8> = (0..1000u32).flat_map(|i| i.to_ne_bytes()).collect();
+        let values: Vec<u8> = (0..1000u64).flat_map(|i| (i * i).to_ne_bytes()).collect();
+        let mut count = 1000;
+        map.update_batch(&keys, &values, &mut count, 0).unwrap();
+        assert_eq!(count, 1000);
+
+        let mut cursor = None;
+        let mut seen = 0;
+        loop {
+            let (mut out_batch, mut keys, mut values) = ([0; 4], [0; 4 * 64], [0; 8 * 64]);
+            let mut count = 64;
+            let result = map.lookup_and_delete_batch(
+                cursor.as_ref().map(|key: &[u8; 4]| &key[..]),
+                &mut out_batch,
+                &mut keys,
+                &mut values,
+                &mut count,
+                0,
+            );
+            for (key, value) in keys.chunks_exact(4).zip(values.chunks_exact(8)) {
+                let key = u32::from_ne_bytes([key[0], key[1], key[2], key[3]]) as u64;
+                assert_eq!(value, (key * key).to_ne_bytes());
+            }
+            seen += count;
+            cursor = Some(out_batch);
+            match result {
+                Ok(()) => assert_eq!(count, 64),
+                Err(err) => {
+                    assert_eq!(err, ENOENT);
+                    break;
+                }
+            }
+        }
+        assert_eq!(seen, 1000);
+        assert_eq!(map.get_next_key(None, &mut [0; 4]), Err(ENOENT));
+    }
+
+    #[test]
+    fn partial_batches() {
+        let mut map = hash_map(4);
+        let keys: Vec<u8> = (0..6u32).flat_map(|i| i.to_ne_bytes()).collect();
+        let values = [0; 8 * 6];
+        let mut count = 6;
+        assert_eq!(map.update_batch(&keys, &values, &mut count, 0), Err(E2BIG));
+        assert_eq!(count, 4);
+
+        let (mut out_batch, mut keys_out, mut values_out) = ([0; 4], [0; 4 * 3], [0; 8 * 3]);
+        let mut count = 3;
+        map.lookup_batch(
+            None,
+            &mut out_batch,
+            &mut keys_out,
+            &mut values_out,
+            &mut count,
+            0,
+        )
+        .unwrap();
+        assert_eq!(out_batch, 2u32.to_ne_bytes());
+        let mut count = 3;
+        let result = map.lookup_batch(
+            Some(&out_batch.clone()),
+            &mut out_batch,
+            &mut keys_out,
+            &mut values_out,
+            &mut count,
+            0,
+        );
+        assert_eq!((result, count), (Err(ENOENT), 1));
+        assert_eq!(&keys_out[..4], &3u32.to_ne_bytes());
+
+        let mut count = 6;
+        assert_eq!(map.delete_batch(&keys, &mut count, 0), Err(ENOENT));
+        assert_eq!(count, 4);
+        assert_eq!(map.delete_batch(&keys, &mut 1, 1), Err(EINVAL));
+    }
+
+    #[test]
+    fn spin_lock_field() {
+        let def = MapDef {
+            map_type: BPF_MAP_TYPE_ARRAY,
+            key_size: 4,
+            value_size: 16,
+            max_entries: 1,
+            spin_lock_off: Some(4),
+            ..Default::default()
+        };
+        let mut map = create(&def, 0).unwrap();
+        let key = 0u32.to_ne_bytes();
+        let value = [0xaa; 16];
+        map.update(&key, &value, BPF_F_LOCK as u64).unwrap();
+        assert_eq!(&map.lookup(&key).unwrap()[4..8], &[0; 4]);
+        map.lookup(&key).unwrap()[4..8].copy_from_slice(&1u32.to_ne_bytes());
+        map.update(&key, &value, BPF_ANY as u64).unwrap();
+        assert_eq!(&map.lookup(&key).unwrap()[4..8], &1u32.to_ne_bytes());
+        map.lookup(&key).unwrap()[4..8].fill(0);
+
+        let mut out = [0; 16];
+        map.lookup_elem(&key, &mut out, BPF_F_LOCK as u64).unwrap();
+        assert_eq!(out, [[0xaa; 4], [0; 4], [0xaa; 4], [0xaa; 4]].concat()[..]);
+        assert_eq!(
+            map.lookup_elem(&key, &mut out, BPF_EXIST as u64),
+            Err(EINVAL)
+        );
+
+        // locks stay aligned whatever the size of the values around them
+        for map_type in [BPF_MAP_TYPE_ARRAY, BPF_MAP_TYPE_HASH] {
+            let def = MapDef {
+                map_type,
+                value_size: 6,
+                max_entries: 2,
+                spin_lock_off: Some(0),
+                ..def
+            };
+            let mut map = create(&def, 0).unwrap();
+            for i in 0..2u32 {
+                let key = i.to_ne_bytes();
+                map.update(&key, &[0; 6], BPF_ANY as u64).unwrap();
+                let value = map.lookup(&key).unwrap();
+                assert_eq!(value.len(), 6);
+                assert_eq!(value.as_ptr() as usize % 8, 0);
+            }
+        }
+
+        let def = MapDef {
+            spin_lock_off: None,
+            ..def
+        };
+        let mut map = create(&def, 0).unwrap();
+        assert_eq!(map.update(&key, &value, BPF_F_LOCK as u64), Err(EINVAL));
+        let def = MapDef {
+            spin_lock_off: Some(14),
+            ..def
+        };
+        assert_eq!(create(&def, 0).err(), Some(EINVAL));
+        let def = MapDef {
+            spin_lock_off: Some(4),
+            timer_off: Some(0),
+            ..def
+        };
+        assert_eq!(create(&def, 0).err(), Some(EINVAL));
+    }
+}
@@ -0,0 +1,97 @@
+use super::{copy_value, AlignedBytes, Map, MapDef};
+use crate::consts::*;
+use crate::errno::*;
+use alloc::vec::Vec;
+
+/// `BPF_MAP_TYPE_ARRAY`: `max_entries` preallocated, zero-initialized values
+/// indexed by a `u32` key. Elements can be overwritten but never deleted.
+/// With `BPF_F_RDONLY_PROG` only the host writes them.
+pub struct ArrayMap {
+    def: MapDef,
+    /// Values at a stride of `value_size` rounded up to 8, so each one is
+    /// aligned like the kernel's.
+    values: AlignedBytes,
+}
+
+impl ArrayMap {
+    pub fn new(def: &MapDef) -> Result<Self, i32> {
+        if def.key_size != 4
+            || def.value_size == 0
+            || def.max_entries == 0
+            || def.map_flags & !BPF_F_RDONLY_PROG != 0
+        {
+            return Err(EINVAL);
+        }
+        def.check_fields(true)?;
+        let size = stride(def)
+            .checked_mul(def.max_entries as usize)
+            .ok_or(E2BIG)?;
+        Ok(ArrayMap {
+            def: *def,
+            values: AlignedBytes::zeroed(size),
+        })
+    }
+
+    fn value(&mut self, index: usize) -> &mut [u8] {
+        let start = index * stride(&self.def);
+        &mut self.values[start..start + self.def.value_size as usize]
+    }
+
+    fn index(&self, key: &[u8]) -> Option<usize> {
+        let index = u32::from_ne_bytes([key[0], key[1], key[2], key[3]]);
+        if index < self.def.max_entries {
+            Some(index as usize)
+        } else {
+            None
+        }
+    }
+}
+
+impl Map for ArrayMap {
+    fn def(&self) -> &MapDef {
+        &self.def
+    }
+
+    fn lookup(&mut self, key: &[u8]) -> Option<&mut [u8]> {
+        let index = self.index(key)?;
+        Some(self.value(index))
+    }
+
+    fn update(&mut self, key: &[u8], value: &[u8], flags: u64) -> Result<(), i32> {
+        self.def.check_flags(flags, BPF_EXIST)?;
+        let index = self.index(key).ok_or(E2BIG)?;
+        if flags & !(BPF_F_LOCK as u64) == BPF_NOEXIST as u64 {
+            return Err(EEXIST);
+        }
+        let def = self.def;
+        copy_value(&def, self.value(index), value, flags);
+        Ok(())
+    }
+
+    fn delete(&mut self, _key: &[u8]) -> Result<(), i32> {
+        Err(EINVAL)
+    }
+
+    fn key_of(&self, value: u64) -> Option<Vec<u8>> {
+        let offset = value.checked_sub(self.values.as_ptr() as u64)? as usize;
+        let index = offset / stride(&self.def);
+        if !offset.is_multiple_of(stride(&self.def)) || index >= self.def.max_entries as usize {
+            return None;
+        }
+        Some((index as u32).to_ne_bytes().to_vec())
+    }
+
+    fn get_next_key(&self, key: Option<&[u8]>, next_key: &mut [u8]) -> Result<(), i32> {
+        let next = match key.and_then(|key| self.index(key)) {
+            Some(index) if index as u32 == self.def.max_entries - 1 => return Err(ENOENT),
+            Some(index) => index as u32 + 1,
+            None => 0,
+        };
+        next_key.copy_from_slice(&next.to_ne_bytes());
+        Ok(())
+    }
+}
+
+fn stride(def: &MapDef) -> usize {
+    (def.value_size as usize + 7) & !7
+}
@@ -0,0 +1,173 @@
+use super::jhash::{jhash, jhash2};
+use super::{Map, MapDef};
+use crate::consts::*;
+use crate::errno::*;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const DEFAULT_NR_HASH_FUNCS: u32 = 5;
+
+/// `BPF_MAP_TYPE_BLOOM_FILTER`: a keyless set supporting only push (insert)
+/// and peek (membership test). The low 4 bits of `map_extra` select the
+/// number of hash functions, 5 when left zero.
+pub struct BloomFilterMap {
+    def: MapDef,
+    bitset: Vec<u64>,
+    mask: u32,
+    nr_hash_funcs: u32,
+    seed: u32,
+}
+
+impl BloomFilterMap {
+    /// `seed` is ignored in favour of 0 if `BPF_F_ZERO_SEED` is set.
+    pub fn new(def: &MapDef, seed: u32) -> Result<Self, i32> {
+        if def.key_size != 0
+            || def.value_size == 0
+            || def.max_entries == 0
+            || def.map_flags & !BPF_F_ZERO_SEED != 0
+            || def.map_extra & !0xf != 0
+        {
+            return Err(EINVAL);
+        }
+        def.check_fields(false)?;
+        let nr_hash_funcs = match def.map_extra as u32 {
+            0 => DEFAULT_NR_HASH_FUNCS,
+            n => n,
+        };
+        // n * k / ln(2) bits minimize the false positive rate, with 7 / 5
+        // approximating 1 / ln(2).
+        let mask = match def
+            .max_entries
+            .checked_mul(nr_hash_funcs)
+            .and_then(|bits| (bits / 5).checked_mul(7))
+        {
+            Some(bits) if bits <= 1 << 31 => bits.max(64).next_power_of_two() - 1,
+            _ => u32::MAX,
+        };
+        Ok(BloomFilterMap {
+            def: *def,
+            bitset: vec![0; (mask as usize >> 6) + 1],
+            mask,
+            nr_hash_funcs,
+            seed: if def.map_flags & BPF_F_ZERO_SEED != 0 {
+                0
+            } else {
+                seed
+            },
+        })
+    }
+
+    fn bits(&self, value: &[u8]) -> Vec<u32> {
+        let words: Option<Vec<u32>> = if value.len().is_multiple_of(4) {
+            Some(
+                value
+                    .chunks_exact(4)
+                    .map(|word| u32::from_ne_bytes([word[0], word[1], word[2], word[3]]))
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        (0..self.nr_hash_funcs)
+            .map(|i| {
+                let seed = self.seed.wrapping_add(i);
+                let hash = match &words {
+                    Some(words) => jhash2(words, seed),
+                    None => jhash(value, seed),
+                };
+                hash & self.mask
+            })
+            .collect()
+    }
+}
+
+impl Map for BloomFilterMap {
+    fn def(&self) -> &MapDef {
+        &self.def
+    }
+
+    fn lookup(&mut self, _key: &[u8]) -> Option<&mut [u8]> {
+        None
+    }
+
+    fn update(&mut self, _key: &[u8], _value: &[u8], _flags: u64) -> Result<(), i32> {
+        Err(EINVAL)
+    }
+
+    fn delete(&mut self, _key: &[u8]) -> Result<(), i32> {
+        Err(EOPNOTSUPP)
+    }
+
+    fn get_next_key(&self, _key: Option<&[u8]>, _next_key: &mut [u8]) -> Result<(), i32> {
+        Err(EOPNOTSUPP)
+    }
+
+    fn push(&mut self, value: &[u8], flags: u64) -> Result<(), i32> {
+        if flags != BPF_ANY as u64 {
+            return Err(EINVAL);
+        }
+        for bit in self.bits(value) {
+            self.bitset[bit as usize >> 6] |= 1 << (bit & 63);
+        }
+        Ok(())
+    }
+
+    fn peek(&mut self, value: &mut [u8]) -> Result<(), i32> {
+        if self
+            .bits(value)
+            .into_iter()
+            .all(|bit| self.bitset[bit as usize >> 6] & 1 << (bit & 63) != 0)
+        {
+            Ok(())
+        } else {
+            Err(ENOENT)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bloom_filter(map_flags: u32, map_extra: u64, seed: u32) -> BloomFilterMap {
+        BloomFilterMap::new(
+            &MapDef {
+                map_type: BPF_MAP_TYPE_BLOOM_FILTER,
+                value_size: 4,
+                max_entries: 100,
+                map_flags,
+                map_extra,
+                ..Default::default()
+            },
+            seed,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn membership() {
+        let mut map = bloom_filter(0, 3, 0x1234);
+        for i in 0..100u32 {
+            map.push(&i.to_ne_bytes(), 0).unwrap();
+        }
+        for i in 0..100u32 {
+            assert_eq!(map.peek(&mut i.to_ne_bytes()), Ok(()));
+        }
+        let misses = (1000..2000u32)
+            .filter(|i| map.peek(&mut i.to_ne_bytes()).is_err())
+            .count();
+        assert!(misses > 800);
+        assert_eq!(map.push(&0u32.to_ne_bytes(), BPF_EXIST as u64), Err(EINVAL));
+        assert_eq!(map.delete(&[]), Err(EOPNOTSUPP));
+    }
+
+    #[test]
+    fn zero_seed() {
+        let mut a = bloom_filter(BPF_F_ZERO_SEED, 0, 1);
+        let mut b = bloom_filter(BPF_F_ZERO_SEED, 0, 2);
+        a.push(b"abc\0", 0).unwrap();
+        b.push(b"abc\0", 0).unwrap();
+        assert_eq!(a.bitset, b.bitset);
+        assert_eq!(a.nr_hash_funcs, DEFAULT_NR_HASH_FUNCS);
+    }
+}
@@ -0,0 +1,115 @@
+use super::{copy_value, AlignedBytes, Map, MapDef};
+use crate::consts::*;
+use crate::errno::*;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::ops::Bound::{Excluded, Unbounded};
+
+/// `BPF_MAP_TYPE_HASH`: up to `max_entries` elements keyed by arbitrary
+/// `key_size` bytes, iterated in key order.
+pub struct HashMap {
+    def: MapDef,
+    elems: BTreeMap<Vec<u8>, AlignedBytes>,
+    /// Values of deleted or replaced elements, until `reclaim`.
+    deleted: Vec<AlignedBytes>,
+    /// Keys by the address of their value, kept when values hold a timer.
+    keys: BTreeMap<u64, Vec<u8>>,
+}
+
+impl HashMap {
+    pub fn new(def: &MapDef) -> Result<Self, i32> {
+        if def.key_size == 0
+            || def.value_size == 0
+            || def.max_entries == 0
+            || def.map_flags & !(BPF_F_NO_PREALLOC | BPF_F_ZERO_SEED) != 0
+        {
+            return Err(EINVAL);
+        }
+        def.check_fields(true)?;
+        Ok(HashMap {
+            def: *def,
+            elems: BTreeMap::new(),
+            deleted: Vec::new(),
+            keys: BTreeMap::new(),
+        })
+    }
+
+    /// Inserts a new element for `key`, retiring the one it replaces.
+    fn insert(&mut self, key: &[u8], value: &[u8]) {
+        let mut elem = AlignedBytes::zeroed(value.len());
+        copy_value(&self.def, &mut elem, value, 0);
+        if let Some(old) = self.elems.remove(key) {
+            self.retire(old);
+        }
+        if self.def.timer_off.is_some() {
+            self.keys.insert(elem.as_ptr() as u64, key.to_vec());
+        }
+        self.elems.insert(key.to_vec(), elem);
+    }
+
+    fn retire(&mut self, value: AlignedBytes) {
+        self.keys.remove(&(value.as_ptr() as u64));
+        self.deleted.push(value);
+    }
+}
+
+impl Map for HashMap {
+    fn def(&self) -> &MapDef {
+        &self.def
+    }
+
+    fn lookup(&mut self, key: &[u8]) -> Option<&mut [u8]> {
+        self.elems.get_mut(key).map(|value| &mut value[..])
+    }
+
+    /// Replaces an existing element with a new one, as
+    /// `htab_map_update_elem` does, so the old value's timer is freed with
+    /// it. `BPF_F_LOCK` updates the value in place instead.
+    fn update(&mut self, key: &[u8], value: &[u8], flags: u64) -> Result<(), i32> {
+        self.def.check_flags(flags, BPF_EXIST)?;
+        let mode = flags & !(BPF_F_LOCK as u64);
+        let full = self.elems.len() >= self.def.max_entries as usize;
+        match self.elems.get_mut(key) {
+            Some(_) if mode == BPF_NOEXIST as u64 => Err(EEXIST),
+            Some(slot) if flags & BPF_F_LOCK as u64 != 0 => {
+                copy_value(&self.def, slot, value, flags);
+                Ok(())
+            }
+            None if mode == BPF_EXIST as u64 => Err(ENOENT),
+            None if full => Err(E2BIG),
+            _ => {
+                self.insert(key, value);
+                Ok(())
+            }
+        }
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<(), i32> {
+        let value = self.elems.remove(key).ok_or(ENOENT)?;
+        self.retire(value);
+        Ok(())
+    }
+
+    fn reclaim(&mut self, free: &mut dyn FnMut(&mut [u8])) {
+        for mut value in self.deleted.drain(..) {
+            free(&mut value);
+        }
+    }
+
+    fn key_of(&self, value: u64) -> Option<Vec<u8>> {
+        self.keys.get(&value).cloned()
+    }
+
+    fn get_next_key(&self, key: Option<&[u8]>, next_key: &mut [u8]) -> Result<(), i32> {
+        let next = match key {
+            Some(key) if self.elems.contains_key(key) => self
+                .elems
+                .range::<[u8], _>((Excluded(key), Unbounded))
+                .next(),
+            _ => self.elems.iter().next(),
+        };
+        let (next, _) = next.ok_or(ENOENT)?;
+        next_key.copy_from_slice(next);
+        Ok(())
+    }
+}
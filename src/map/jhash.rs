@@ -0,0 +1,99 @@
+// Bob Jenkins' lookup3 as found in include/linux/jhash.h, so that ids and
+// bit positions derived from it match the kernel.
+
+const JHASH_INITVAL: u32 = 0xdeadbeef;
+
+fn mix(a: &mut u32, b: &mut u32, c: &mut u32) {
+    *a = a.wrapping_sub(*c);
+    *a ^= c.rotate_left(4);
+    *c = c.wrapping_add(*b);
+    *b = b.wrapping_sub(*a);
+    *b ^= a.rotate_left(6);
+    *a = a.wrapping_add(*c);
+    *c = c.wrapping_sub(*b);
+    *c ^= b.rotate_left(8);
+    *b = b.wrapping_add(*a);
+    *a = a.wrapping_sub(*c);
+    *a ^= c.rotate_left(16);
+    *c = c.wrapping_add(*b);
+    *b = b.wrapping_sub(*a);
+    *b ^= a.rotate_left(19);
+    *a = a.wrapping_add(*c);
+    *c = c.wrapping_sub(*b);
+    *c ^= b.rotate_left(4);
+    *b = b.wrapping_add(*a);
+}
+
+fn finalize(a: &mut u32, b: &mut u32, c: &mut u32) {
+    *c ^= *b;
+    *c = c.wrapping_sub(b.rotate_left(14));
+    *a ^= *c;
+    *a = a.wrapping_sub(c.rotate_left(11));
+    *b ^= *a;
+    *b = b.wrapping_sub(a.rotate_left(25));
+    *c ^= *b;
+    *c = c.wrapping_sub(b.rotate_left(16));
+    *a ^= *c;
+    *a = a.wrapping_sub(c.rotate_left(4));
+    *b ^= *a;
+    *b = b.wrapping_sub(a.rotate_left(14));
+    *c ^= *b;
+    *c = c.wrapping_sub(b.rotate_left(24));
+}
+
+pub fn jhash(key: &[u8], initval: u32) -> u32 {
+    let init = JHASH_INITVAL
+        .wrapping_add(key.len() as u32)
+        .wrapping_add(initval);
+    let (mut a, mut b, mut c) = (init, init, init);
+    let mut key = key;
+    while key.len() > 12 {
+        a = a.wrapping_add(u32::from_le_bytes([key[0], key[1], key[2], key[3]]));
+        b = b.wrapping_add(u32::from_le_bytes([key[4], key[5], key[6], key[7]]));
+        c = c.wrapping_add(u32::from_le_bytes([key[8], key[9], key[10], key[11]]));
+        mix(&mut a, &mut b, &mut c);
+        key = &key[12..];
+    }
+    if key.is_empty() {
+        return c;
+    }
+    let mut tail = [0u32; 3];
+    for (i, byte) in key.iter().enumerate() {
+        tail[i / 4] |= (*byte as u32) << (i % 4 * 8);
+    }
+    a = a.wrapping_add(tail[0]);
+    b = b.wrapping_add(tail[1]);
+    c = c.wrapping_add(tail[2]);
+    finalize(&mut a, &mut b, &mut c);
+    c
+}
+
+pub fn jhash2(key: &[u32], initval: u32) -> u32 {
+    let init = JHASH_INITVAL
+        .wrapping_add((key.len() as u32) << 2)
+        .wrapping_add(initval);
+    let (mut a, mut b, mut c) = (init, init, init);
+    let mut key = key;
+    while key.len() > 3 {
+        a = a.wrapping_add(key[0]);
+        b = b.wrapping_add(key[1]);
+        c = c.wrapping_add(key[2]);
+        mix(&mut a, &mut b, &mut c);
+        key = &key[3..];
+    }
+    match *key {
+        [] => return c,
+        [k0] => a = a.wrapping_add(k0),
+        [k0, k1] => {
+            a = a.wrapping_add(k0);
+            b = b.wrapping_add(k1);
+        }
+        [k0, k1, k2, ..] => {
+            a = a.wrapping_add(k0);
+            b = b.wrapping_add(k1);
+            c = c.wrapping_add(k2);
+        }
+    }
+    finalize(&mut a, &mut b, &mut c);
+    c
+}
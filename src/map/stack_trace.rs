@@ -0,0 +1,122 @@
+use super::jhash::jhash2;
+use super::{Map, MapDef};
+use crate::consts::*;
+use crate::errno::*;
+use alloc::vec;
+use alloc::vec::Vec;
+
+struct Bucket {
+    hash: u32,
+    nr: u32,
+    data: Vec<u8>,
+}
+
+/// `BPF_MAP_TYPE_STACK_TRACE`: maps a `u32` stack id to the instruction
+/// pointers of a call stack, zero-padded up to `value_size / 8` frames.
+pub struct StackTraceMap {
+    def: MapDef,
+    buckets: Vec<Option<Bucket>>,
+}
+
+impl StackTraceMap {
+    pub fn new(def: &MapDef) -> Result<Self, i32> {
+        if def.key_size != 4
+            || def.value_size < 8
+            || !def.value_size.is_multiple_of(8)
+            || def.value_size / 8 > PERF_MAX_STACK_DEPTH
+            || def.max_entries == 0
+            || def.map_flags != 0
+        {
+            return Err(EINVAL);
+        }
+        def.check_fields(false)?;
+        let n_buckets = def.max_entries.checked_next_power_of_two().ok_or(E2BIG)?;
+        let mut buckets = Vec::new();
+        buckets.resize_with(n_buckets as usize, || None);
+        Ok(StackTraceMap { def: *def, buckets })
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.def.value_size as usize / 8
+    }
+
+    /// Stores `ips` and returns its stack id, following `bpf_get_stackid`:
+    /// an id already holding an identical stack is reused, one holding a
+    /// different stack is only overwritten with `BPF_F_REUSE_STACKID`.
+    pub fn get_id(&mut self, ips: &[u64], flags: u64) -> Result<u32, i32> {
+        let ips = &ips[..ips.len().min(self.max_depth())];
+        if ips.is_empty() {
+            return Err(EFAULT);
+        }
+        let words: Vec<u32> = ips
+            .iter()
+            .flat_map(|ip| [*ip as u32, (*ip >> 32) as u32])
+            .collect();
+        let hash = jhash2(&words, 0);
+        let id = hash & (self.buckets.len() as u32 - 1);
+        let data: Vec<u8> = ips.iter().flat_map(|ip| ip.to_ne_bytes()).collect();
+        if let Some(bucket) = &self.buckets[id as usize] {
+            if bucket.hash == hash
+                && (flags & BPF_F_FAST_STACK_CMP as u64 != 0
+                    || bucket.nr == ips.len() as u32 && bucket.data[..data.len()] == data[..])
+            {
+                return Ok(id);
+            }
+            if flags & BPF_F_REUSE_STACKID as u64 == 0 {
+                return Err(EEXIST);
+            }
+        }
+        let mut value = vec![0; self.def.value_size as usize];
+        value[..data.len()].copy_from_slice(&data);
+        self.buckets[id as usize] = Some(Bucket {
+            hash,
+            nr: ips.len() as u32,
+            data: value,
+        });
+        Ok(id)
+    }
+
+    fn id(&self, key: &[u8]) -> Option<usize> {
+        let id = u32::from_ne_bytes([key[0], key[1], key[2], key[3]]) as usize;
+        if id < self.buckets.len() {
+            Some(id)
+        } else {
+            None
+        }
+    }
+}
+
+impl Map for StackTraceMap {
+    fn def(&self) -> &MapDef {
+        &self.def
+    }
+
+    fn lookup(&mut self, key: &[u8]) -> Option<&mut [u8]> {
+        let id = self.id(key)?;
+        self.buckets[id].as_mut().map(|bucket| &mut bucket.data[..])
+    }
+
+    fn update(&mut self, _key: &[u8], _value: &[u8], _flags: u64) -> Result<(), i32> {
+        Err(EINVAL)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<(), i32> {
+        let id = self.id(key).ok_or(EINVAL)?;
+        match self.buckets[id].take() {
+            Some(_) => Ok(()),
+            None => Err(ENOENT),
+        }
+    }
+
+    fn get_next_key(&self, key: Option<&[u8]>, next_key: &mut [u8]) -> Result<(), i32> {
+        let start = match key.and_then(|key| self.id(key)) {
+            Some(id) => id + 1,
+            None => 0,
+        };
+        let id = (start..self.buckets.len())
+            .find(|id| self.buckets[*id].is_some())
+            .ok_or(ENOENT)?;
+        next_key.copy_from_slice(&(id as u32).to_ne_bytes());
+        Ok(())
+    }
+}
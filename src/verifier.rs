@@ -0,0 +1,2253 @@
+//! Static checks run on a program before it is interpreted, modeled after
+//! kernel/bpf/verifier.c.
+
+use crate::consts::*;
+use crate::helpers::proto::ArgType;
+use crate::helpers::HelperRegistry;
+use crate::map::MapDef;
+use crate::types::*;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::{fmt, mem};
+
+mod bounds;
+mod call;
+mod cfg;
+mod ctx;
+mod log;
+mod stack;
+mod state;
+mod tnum;
+
+pub use bounds::Bounds;
+pub use log::{Log, Record, LOG_LEVEL1, LOG_LEVEL2};
+pub use state::{RegState, RegType, SlotType, StackSlot, State};
+pub use tnum::Tnum;
+
+/// A decoded instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Insn {
+    pub op: u8,
+    pub dst: u8,
+    pub src: u8,
+    pub off: i16,
+    pub imm: i32,
+}
+
+impl Insn {
+    pub fn decode(inst: u64) -> Self {
+        Insn {
+            op: inst as u8,
+            dst: (inst >> 8) as u8 & 0x0f,
+            src: (inst >> 12) as u8 & 0x0f,
+            off: (inst >> 16) as i16,
+            imm: (inst >> 32) as i32,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn encode(op: u8, dst: u8, src: u8, off: i16, imm: i32) -> u64 {
+        op as u64
+            | (dst as u64) << 8
+            | (src as u64) << 12
+            | (off as u16 as u64) << 16
+            | (imm as u32 as u64) << 32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The program has no instructions.
+    Empty,
+    /// The program is longer than `BPF_MAXINSNS`.
+    TooLarge {
+        len: usize,
+    },
+    UnknownOpcode {
+        op: u8,
+    },
+    /// A field the opcode does not use is nonzero.
+    ReservedFields,
+    /// A division or modulo by an immediate zero.
+    DivByZero,
+    /// A shift by an immediate that is negative or not less than the width
+    /// of the operand.
+    InvalidShift {
+        shift: i32,
+    },
+    InvalidRegister {
+        reg: u8,
+    },
+    /// `LD_IMM_DW` is missing its second half.
+    IncompleteLdImm,
+    /// A call other than to a helper or kfunc.
+    UnsupportedCall,
+    JumpOutOfRange {
+        target: i64,
+    },
+    JumpIntoLdImm {
+        target: usize,
+    },
+    /// The last instruction of a function falls through.
+    FallThrough,
+    Unreachable,
+    /// A register is read before it is written.
+    UninitRegister {
+        reg: u8,
+    },
+    /// r10 is written to.
+    FramePointerWrite,
+    /// The operation is not allowed on the pointer in `reg`.
+    PointerArithmetic {
+        reg: u8,
+    },
+    /// The pointer in `reg` escapes into memory or the return value.
+    PointerLeak {
+        reg: u8,
+    },
+    /// Memory is accessed through `reg`, which is not a valid pointer.
+    InvalidMemAccess {
+        reg: u8,
+    },
+    /// `BPF_PSEUDO_MAP_FD` names a map missing from the `Env`.
+    InvalidMapFd {
+        fd: u32,
+    },
+    /// Arithmetic on `reg` moves a stack pointer out of the frame.
+    StackOutOfRange {
+        reg: u8,
+    },
+    /// A stack access is out of the frame, misaligned, or touches part of a
+    /// spilled pointer.
+    InvalidStackAccess {
+        off: i64,
+        size: u32,
+    },
+    /// Stack is read before it is written.
+    UninitStack {
+        off: i64,
+        size: u32,
+    },
+    /// A call to a helper missing from the `Env`.
+    UnknownHelper {
+        id: u32,
+    },
+    UnknownKfunc {
+        id: u32,
+    },
+    /// A program writes to map `fd`, which is `BPF_F_RDONLY_PROG`.
+    ReadOnlyMap {
+        fd: u32,
+    },
+    /// A constant string at `off` in a map value has no NUL before the end
+    /// of the value.
+    UnterminatedStr {
+        off: i64,
+    },
+    /// A helper other than `bpf_spin_unlock` is called with a spin lock
+    /// held.
+    CallWhileLocked,
+    /// `bpf_spin_lock` is called with a spin lock already held.
+    NestedLock,
+    /// `bpf_spin_unlock` is called on a spin lock that is not held.
+    UnlockNotHeld,
+    /// The program exits with a spin lock held.
+    ExitWhileLocked,
+    /// The prototype of helper `id` returns a map value without taking a
+    /// map.
+    InvalidProto {
+        id: u32,
+    },
+    /// `reg` does not hold what the helper's prototype expects.
+    InvalidArg {
+        reg: u8,
+        ty: RegType,
+        expected: ArgType,
+    },
+    /// The size of helper memory in `reg` is not a bounded scalar, or is
+    /// zero where the helper does not allow it.
+    InvalidMemSize {
+        reg: u8,
+    },
+    /// An access to a map key or value of `limit` bytes is out of bounds.
+    InvalidMapAccess {
+        off: i64,
+        size: u32,
+        limit: u32,
+    },
+    /// An access to a map value overlaps its `bpf_spin_lock` or
+    /// `bpf_timer`, which only helpers may use.
+    InvalidFieldAccess {
+        off: i64,
+        size: u32,
+    },
+    /// A context access is outside any field the program type may use,
+    /// misaligned, of the wrong width, or writes a read-only field.
+    InvalidCtxAccess {
+        off: i64,
+        size: u32,
+    },
+    /// The context of a program of `prog_type`, which has no description
+    /// of its layout, is accessed.
+    UnknownProgType {
+        prog_type: u32,
+    },
+    /// The context is accessed through `reg` after arithmetic on it.
+    ModifiedCtx {
+        reg: u8,
+    },
+    /// A packet access is not within the range proven by comparing with
+    /// `data_end`.
+    InvalidPacketAccess {
+        off: i64,
+        size: u32,
+        range: u32,
+    },
+    /// A callback passes itself to a helper, directly or not.
+    RecursiveCall {
+        target: usize,
+    },
+    /// A loop comes back to an instruction in the same state, so it never
+    /// ends.
+    InfiniteLoop,
+    /// Exploring every path took more than `COMPLEXITY_LIMIT` instructions.
+    TooComplex {
+        insns: usize,
+    },
+}
+
+/// Why a program was rejected, and at which instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Error {
+    pub pc: usize,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "insn {}: ", self.pc)?;
+        match self.kind {
+            ErrorKind::Empty => write!(f, "program is empty"),
+            ErrorKind::TooLarge { len } => write!(f, "program of {} insns is too large", len),
+            ErrorKind::UnknownOpcode { op } => write!(f, "unknown opcode {:02x}", op),
+            ErrorKind::ReservedFields => write!(f, "uses reserved fields"),
+            ErrorKind::DivByZero => write!(f, "div by zero"),
+            ErrorKind::InvalidShift { shift } => write!(f, "invalid shift {}", shift),
+            ErrorKind::InvalidRegister { reg } => write!(f, "R{} is invalid", reg),
+            ErrorKind::IncompleteLdImm => write!(f, "invalid ld_imm64 insn"),
+            ErrorKind::UnsupportedCall => write!(f, "unsupported call"),
+            ErrorKind::JumpOutOfRange { target } => {
+                write!(f, "jump out of range to insn {}", target)
+            }
+            ErrorKind::JumpIntoLdImm { target } => {
+                write!(f, "jump into the middle of ld_imm64 insn {}", target - 1)
+            }
+            ErrorKind::FallThrough => write!(f, "last insn is not an exit or jmp"),
+            ErrorKind::Unreachable => write!(f, "unreachable insn"),
+            ErrorKind::UninitRegister { reg } => write!(f, "R{} !read_ok", reg),
+            ErrorKind::FramePointerWrite => write!(f, "frame pointer is read only"),
+            ErrorKind::PointerArithmetic { reg } => {
+                write!(f, "pointer arithmetic on R{} prohibited", reg)
+            }
+            ErrorKind::PointerLeak { reg } => write!(f, "R{} leaks addr", reg),
+            ErrorKind::InvalidMemAccess { reg } => write!(f, "R{} invalid mem access", reg),
+            ErrorKind::InvalidMapFd { fd } => {
+                write!(f, "fd {} is not pointing to valid bpf_map", fd)
+            }
+            ErrorKind::StackOutOfRange { reg } => {
+                write!(f, "R{} stack pointer arithmetic goes out of range", reg)
+            }
+            ErrorKind::InvalidStackAccess { off, size } => {
+                write!(f, "invalid stack access off={} size={}", off, size)
+            }
+            ErrorKind::UninitStack { off, size } => {
+                write!(f, "invalid read from stack off {} size {}", off, size)
+            }
+            ErrorKind::UnknownHelper { id } => write!(f, "invalid func unknown#{}", id),
+            ErrorKind::UnknownKfunc { id } => {
+                write!(f, "calling kernel function {} is not allowed", id)
+            }
+            ErrorKind::ReadOnlyMap { fd } => write!(f, "write into map fd {} forbidden", fd),
+            ErrorKind::UnterminatedStr { off } => {
+                write!(f, "string at off={} is not zero-terminated", off)
+            }
+            ErrorKind::CallWhileLocked => {
+                write!(f, "function calls are not allowed while holding a lock")
+            }
+            ErrorKind::NestedLock => write!(f, "Locking two bpf_spin_locks are not allowed"),
+            ErrorKind::UnlockNotHeld => write!(f, "bpf_spin_unlock of a lock not held"),
+            ErrorKind::ExitWhileLocked => write!(f, "bpf_spin_unlock is missing"),
+            ErrorKind::InvalidProto { id } => {
+                write!(f, "kernel subsystem misconfigured func#{}", id)
+            }
+            ErrorKind::InvalidArg { reg, ty, expected } => {
+                write!(f, "R{} type={} expected={:?}", reg, ty, expected)
+            }
+            ErrorKind::InvalidMemSize { reg } => write!(f, "R{} invalid memory size", reg),
+            ErrorKind::InvalidMapAccess { off, size, limit } => write!(
+                f,
+                "invalid access to map memory, size={} off={} size={}",
+                limit, off, size
+            ),
+            ErrorKind::InvalidFieldAccess { off, size } => write!(
+                f,
+                "bpf_spin_lock or bpf_timer cannot be accessed directly by load/store, off={} size={}",
+                off, size
+            ),
+            ErrorKind::InvalidCtxAccess { off, size } => {
+                write!(f, "invalid bpf_context access off={} size={}", off, size)
+            }
+            ErrorKind::UnknownProgType { prog_type } => {
+                write!(f, "unknown program type {}", prog_type)
+            }
+            ErrorKind::ModifiedCtx { reg } => {
+                write!(f, "dereference of modified ctx ptr R{} disallowed", reg)
+            }
+            ErrorKind::InvalidPacketAccess { off, size, range } => write!(
+                f,
+                "invalid access to packet, off={} size={} range={}",
+                off, size, range
+            ),
+            ErrorKind::RecursiveCall { target } => {
+                write!(f, "recursive call to insn {}", target)
+            }
+            ErrorKind::InfiniteLoop => write!(f, "infinite loop detected"),
+            ErrorKind::TooComplex { insns } => {
+                write!(f, "program is too complex, processed {} insns", insns)
+            }
+        }
+    }
+}
+
+/// What the verifier knows about the environment the program will run in.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Env<'a> {
+    /// Definitions of the maps `BPF_PSEUDO_MAP_FD` refers to, indexed by fd.
+    pub maps: &'a [MapDef],
+    /// Helpers and kfuncs the program may call. Without a registry, calls
+    /// are checked against the built-in helpers' prototypes.
+    pub helpers: Option<&'a HelperRegistry>,
+    /// Type of the program, `BPF_PROG_TYPE_*`, which decides the layout of
+    /// its context.
+    pub prog_type: u32,
+    /// Values of the frozen single-element array maps, indexed by fd like
+    /// `maps`. Strings in those that are `BPF_F_RDONLY_PROG` may be passed
+    /// to helpers as constants.
+    pub frozen: &'a [Option<&'a [u8]>],
+}
+
+/// Checks that `insts` is a well-formed program that only uses registers
+/// and memory as their types allow, along every path.
+pub fn verify(insts: &[u64], env: &Env) -> Result<(), Error> {
+    verify_with_log(insts, env, &mut Log::default())
+}
+
+/// Like [`verify`], and records what the verifier did in `log`, as much as
+/// its level asks for.
+pub fn verify_with_log(insts: &[u64], env: &Env, log: &mut Log) -> Result<(), Error> {
+    let funcs = match cfg::check(insts) {
+        Ok(funcs) => funcs,
+        Err(error) => {
+            if log.level > 0 {
+                let backtrace = Vec::new();
+                log.records.push(Record::Error { error, backtrace });
+            }
+            return Err(error);
+        }
+    };
+    let mut verifier = Verifier {
+        insts,
+        env,
+        pending: Vec::new(),
+        id_gen: 0,
+        prune_points: cfg::prune_points(insts),
+        visited: vec![Vec::new(); insts.len()],
+        checkpoints: Vec::new(),
+        parent: None,
+        processed: 0,
+        callbacks: Vec::new(),
+        active: Vec::new(),
+        called: BTreeSet::new(),
+        caller_writes: BTreeMap::new(),
+        log: mem::take(log),
+        trail: Vec::new(),
+        trace: None,
+    };
+    let result = verifier.verify(&funcs);
+    *log = verifier.log;
+    log.processed = verifier.processed;
+    if let (Err(error), true) = (result, log.level > 0) {
+        let mut backtrace = Vec::new();
+        let mut trace = verifier.trace;
+        while let Some(idx) = trace {
+            backtrace.push(verifier.trail[idx].0);
+            trace = verifier.trail[idx].1;
+        }
+        backtrace.reverse();
+        log.records.push(Record::Error { error, backtrace });
+    }
+    result
+}
+
+/// Largest constant offset of a pointer, `BPF_MAX_VAR_OFF` in the kernel.
+const MAX_OFF: i64 = 1 << 29;
+
+/// Most instructions processed over all paths, as
+/// `BPF_COMPLEXITY_LIMIT_INSNS`.
+const COMPLEXITY_LIMIT: usize = 1_000_000;
+
+/// Most states saved at one instruction, as `BPF_COMPLEXITY_LIMIT_STATES`.
+const MAX_CHECKPOINTS: usize = 64;
+
+/// Fewest instructions a path processes between saving states, so that
+/// tight loops do not save one per iteration.
+const CHECKPOINT_DISTANCE: usize = 8;
+
+/// A branch still to explore.
+struct Path {
+    pc: usize,
+    state: State,
+    parent: Option<usize>,
+    /// Instructions processed since `parent` was saved.
+    since: usize,
+    /// The branch the path was set aside at.
+    from: Option<usize>,
+    /// Length of the log when the path was set aside.
+    log_pos: usize,
+    /// The last instruction of the path in `Verifier::trail`.
+    trace: Option<usize>,
+}
+
+/// A state saved at a prune point, after `struct bpf_verifier_state_list`.
+struct Checkpoint {
+    /// Taken once the checkpoint is dropped from its instruction's list.
+    state: Option<State>,
+    /// Paths from the state and checkpoints after it still being explored.
+    /// Only once this is 0 is everything reachable from the state known to
+    /// be safe.
+    branches: u32,
+    /// The checkpoint the path last passed before this one.
+    parent: Option<usize>,
+}
+
+struct Verifier<'a> {
+    insts: &'a [u64],
+    env: &'a Env<'a>,
+    pending: Vec<Path>,
+    /// Last id given to registers holding copies of the same value.
+    id_gen: u32,
+    prune_points: Vec<bool>,
+    /// Indices into `checkpoints` of the states saved at each instruction.
+    visited: Vec<Vec<usize>>,
+    checkpoints: Vec<Checkpoint>,
+    /// The checkpoint the path being followed last passed.
+    parent: Option<usize>,
+    processed: usize,
+    /// Callbacks passed to the helper just called, with their entry state.
+    callbacks: Vec<(usize, State)>,
+    /// Callbacks being verified, innermost last.
+    active: Vec<usize>,
+    called: BTreeSet<usize>,
+    /// The offsets of its caller's frame each callback may store to through
+    /// its context, on any path followed so far.
+    caller_writes: BTreeMap<usize, (i64, i64)>,
+    log: Log,
+    /// Instructions processed while logging, each with the index of the one
+    /// before it on its path, so the path to an error can be traced back.
+    trail: Vec<(usize, Option<usize>)>,
+    /// The last instruction of the path being followed in `trail`.
+    trace: Option<usize>,
+}
+
+impl Verifier<'_> {
+    fn verify(&mut self, funcs: &[usize]) -> Result<(), Error> {
+        let mut args = [RegState::NOT_INIT; 5];
+        args[0] = RegState::new(RegType::PtrToCtx);
+        self.explore(0, State::entry(args))?;
+        // callbacks no helper is passed are never run, but must still be sound
+        for &entry in &funcs[1..] {
+            if !self.called.contains(&entry) {
+                self.trace = None;
+                self.explore(entry, State::entry([RegState::SCALAR; 5]))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Follows every path from `entry`, as `do_check` does. Loops are
+    /// unrolled until a state is seen again or their condition is decided,
+    /// and paths are cut short where a state already shown safe covers them.
+    fn explore(&mut self, entry: usize, state: State) -> Result<(), Error> {
+        self.pending.push(Path {
+            pc: entry,
+            state,
+            parent: None,
+            since: 0,
+            from: None,
+            log_pos: self.log.records.len(),
+            trace: self.trace,
+        });
+        while let Some(mut path) = self.pending.pop() {
+            self.parent = path.parent;
+            self.trace = path.trace;
+            if self.log.level == LOG_LEVEL1 {
+                self.log.records.truncate(path.log_pos);
+            }
+            if let (Some(from), true) = (path.from, self.log.level > 0) {
+                let (to, state) = (path.pc, path.state.clone());
+                self.log.records.push(Record::Branch { from, to, state });
+            }
+            loop {
+                let pc = path.pc;
+                let err = |kind| Error { pc, kind };
+                if self.log.level > 0 {
+                    self.trail.push((pc, self.trace));
+                    self.trace = Some(self.trail.len() - 1);
+                }
+                if self.prune_points[pc] && self.visit(&mut path).map_err(err)? {
+                    if self.log.level > 0 {
+                        self.log.records.push(Record::Pruned { pc });
+                    }
+                    break;
+                }
+                if self.log.level > 0 {
+                    let op = self.insts[pc] as u8;
+                    let text = log::disasm(self.insts, pc);
+                    let state = (self.log.level >= LOG_LEVEL2).then(|| path.state.clone());
+                    self.log.records.push(Record::Insn {
+                        pc,
+                        op,
+                        text,
+                        state,
+                    });
+                }
+                self.processed += 1;
+                if self.processed > COMPLEXITY_LIMIT {
+                    let insns = self.processed;
+                    return Err(err(ErrorKind::TooComplex { insns }));
+                }
+                path.since += 1;
+                let next = self.step(pc, &mut path.state).map_err(err)?;
+                let trace = self.trace;
+                for (entry, state) in mem::take(&mut self.callbacks) {
+                    self.explore_callback(pc, entry, state)?;
+                    if let Some(&written) = self.caller_writes.get(&entry) {
+                        stack::clobber(&mut path.state, written, false);
+                    }
+                    self.trace = trace;
+                }
+                match next {
+                    Some(next) => path.pc = next,
+                    None => break,
+                }
+            }
+            self.finish(self.parent);
+        }
+        Ok(())
+    }
+
+    /// Verifies a callback passed to the helper called at `pc` as though
+    /// the helper called it there, setting the caller's paths aside. Since
+    /// it may run again after storing to its caller's frame, it is verified
+    /// again, knowing nothing of the bytes it may store to, until those
+    /// stop growing.
+    fn explore_callback(&mut self, pc: usize, entry: usize, state: State) -> Result<(), Error> {
+        if self.active.contains(&entry) {
+            let kind = ErrorKind::RecursiveCall { target: entry };
+            return Err(Error { pc, kind });
+        }
+        self.called.insert(entry);
+        let pending = mem::take(&mut self.pending);
+        let parent = self.parent;
+        self.active.push(entry);
+        loop {
+            let written = self.caller_writes.get(&entry).copied();
+            let mut state = state.clone();
+            if let Some(written) = written {
+                stack::clobber(&mut state, written, true);
+            }
+            self.explore(entry, state)?;
+            if self.caller_writes.get(&entry).copied() == written {
+                break;
+            }
+        }
+        self.active.pop();
+        self.pending = pending;
+        self.parent = parent;
+        Ok(())
+    }
+
+    /// Compares the path with the states saved at its instruction, as
+    /// `is_state_visited` does, and returns whether it can be cut short.
+    /// Otherwise the state is saved, for later paths and later iterations
+    /// of a loop to be compared with.
+    fn visit(&mut self, path: &mut Path) -> Result<bool, ErrorKind> {
+        let mut evict = None;
+        for (i, &idx) in self.visited[path.pc].iter().enumerate() {
+            let checkpoint = &self.checkpoints[idx];
+            let old = checkpoint.state.as_ref().unwrap();
+            match checkpoint.branches {
+                0 if old.subsumes(&path.state) => return Ok(true),
+                0 => evict = Some(i),
+                // still being explored, so the path came from it
+                _ if *old == path.state => return Err(ErrorKind::InfiniteLoop),
+                _ => {}
+            }
+        }
+        if path.since < CHECKPOINT_DISTANCE {
+            return Ok(false);
+        }
+        let visited = &mut self.visited[path.pc];
+        if visited.len() >= MAX_CHECKPOINTS {
+            match evict {
+                Some(i) => self.checkpoints[visited.remove(i)].state = None,
+                None => return Ok(false),
+            }
+        }
+        visited.push(self.checkpoints.len());
+        self.checkpoints.push(Checkpoint {
+            state: Some(path.state.clone()),
+            branches: 1,
+            parent: self.parent,
+        });
+        self.parent = Some(self.checkpoints.len() - 1);
+        path.parent = self.parent;
+        path.since = 0;
+        Ok(false)
+    }
+
+    /// Queues the other side at `pc` of the branch at `from` of the path
+    /// being followed.
+    fn fork(&mut self, from: usize, pc: usize, state: State) {
+        if let Some(parent) = self.parent {
+            self.checkpoints[parent].branches += 1;
+        }
+        self.pending.push(Path {
+            pc,
+            state,
+            parent: self.parent,
+            since: 0,
+            from: Some(from),
+            log_pos: self.log.records.len(),
+            trace: self.trace,
+        });
+    }
+
+    /// Records that a path from `parent` has ended, and so has every
+    /// checkpoint before it that no path is left from, as
+    /// `update_branch_counts` does.
+    fn finish(&mut self, mut parent: Option<usize>) {
+        while let Some(idx) = parent {
+            let checkpoint = &mut self.checkpoints[idx];
+            checkpoint.branches -= 1;
+            if checkpoint.branches > 0 {
+                break;
+            }
+            parent = checkpoint.parent;
+        }
+    }
+
+    /// Applies the instruction at `pc` to `state`, queueing the other side
+    /// of a branch, and returns the next instruction, or `None` where the
+    /// path ends.
+    fn step(&mut self, pc: usize, state: &mut State) -> Result<Option<usize>, ErrorKind> {
+        let insn = Insn::decode(self.insts[pc]);
+        let next = pc + 1;
+        match insn.op as u32 & 0x07 {
+            BPF_ALU | BPF_ALU64 => self.check_alu(&insn, state)?,
+            BPF_JMP | BPF_JMP32 => match insn.op as u32 & 0xf0 {
+                BPF_EXIT => {
+                    if state.lock.is_some() {
+                        return Err(ErrorKind::ExitWhileLocked);
+                    }
+                    if read(state, 0)?.ty.is_pointer() {
+                        return Err(ErrorKind::PointerLeak { reg: 0 });
+                    }
+                    return Ok(None);
+                }
+                BPF_CALL => self.check_call(&insn, state)?,
+                BPF_JA => return Ok(Some(jump(next, insn.off))),
+                _ => {
+                    let target = jump(next, insn.off);
+                    match check_cond(&insn, state, self.env.maps)? {
+                        (Some(fall), taken) => {
+                            if let Some(taken) = taken {
+                                self.fork(pc, target, taken);
+                            }
+                            *state = fall;
+                        }
+                        (None, Some(taken)) => {
+                            *state = taken;
+                            return Ok(Some(target));
+                        }
+                        (None, None) => return Ok(None),
+                    }
+                }
+            },
+            BPF_LD => {
+                let value = match insn.src as u32 {
+                    BPF_PSEUDO_MAP_FD => {
+                        let fd = insn.imm as u32;
+                        if fd as usize >= self.env.maps.len() {
+                            return Err(ErrorKind::InvalidMapFd { fd });
+                        }
+                        RegState::new(RegType::ConstMapPtr { fd })
+                    }
+                    BPF_PSEUDO_FUNC => RegState::new(RegType::PtrToFunc {
+                        pc: (next as i64 + insn.imm as i64) as usize,
+                    }),
+                    _ => {
+                        let high = Insn::decode(self.insts[next]).imm as u32 as u64;
+                        RegState::scalar(Bounds::constant(insn.imm as u32 as u64 | high << 32))
+                    }
+                };
+                write(state, insn.dst, value)?;
+                return Ok(Some(pc + 2));
+            }
+            BPF_LDX => {
+                let ptr = check_mem(state, insn.src)?;
+                let size = size(insn.op);
+                check_map_access(self.env.maps, &ptr, insn.off as i64, size)?;
+                check_packet_access(&ptr, insn.off as i64, size)?;
+                let value = match ptr.ty {
+                    RegType::PtrToStack => stack::read(state, &ptr, insn.off, size)?,
+                    RegType::PtrToCallerStack => stack::read_caller(state, &ptr, insn.off, size)?,
+                    RegType::PtrToCtx => self.check_ctx(&ptr, insn.src, insn.off, size, false)?,
+                    _ => unknown(size),
+                };
+                write(state, insn.dst, value)?;
+            }
+            class => {
+                let (value, reg) = match class {
+                    BPF_ST => {
+                        let imm = Bounds::constant(insn.imm as i64 as u64);
+                        (RegState::scalar(imm), insn.dst)
+                    }
+                    _ => (*read(state, insn.src)?, insn.src),
+                };
+                let ptr = check_mem(state, insn.dst)?;
+                check_map_access(self.env.maps, &ptr, insn.off as i64, size(insn.op))?;
+                check_map_write(self.env.maps, &ptr)?;
+                check_packet_access(&ptr, insn.off as i64, size(insn.op))?;
+                match ptr.ty {
+                    RegType::PtrToMapKey { .. } => {
+                        return Err(ErrorKind::InvalidMemAccess { reg: insn.dst })
+                    }
+                    RegType::PtrToStack => {
+                        stack::write(state, &ptr, insn.off, size(insn.op), &value)?
+                    }
+                    RegType::PtrToCallerStack => {
+                        let (lo, hi) =
+                            stack::write_caller(state, &ptr, insn.off, size(insn.op), &value)?;
+                        if let Some(&entry) = self.active.last() {
+                            let written = self.caller_writes.entry(entry).or_insert((lo, hi));
+                            *written = (written.0.min(lo), written.1.max(hi));
+                        }
+                    }
+                    _ if value.ty.is_pointer() => return Err(ErrorKind::PointerLeak { reg }),
+                    RegType::PtrToCtx => {
+                        self.check_ctx(&ptr, insn.dst, insn.off, size(insn.op), true)?;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(Some(next))
+    }
+
+    /// Checks an access of `size` bytes at `off` from the context pointer in
+    /// `reg` against the layout of the program type's context, and returns
+    /// what a load gives.
+    fn check_ctx(
+        &self,
+        ptr: &RegState,
+        reg: u8,
+        off: i16,
+        size: u32,
+        write: bool,
+    ) -> Result<RegState, ErrorKind> {
+        let prog_type = self.env.prog_type;
+        let fields = ctx::fields(prog_type).ok_or(ErrorKind::UnknownProgType { prog_type })?;
+        // the kernel rewrites the access to the real field, so it has to
+        // know which one
+        if ptr.off != 0 || ptr.bounds.value() != Some(0) {
+            return Err(ErrorKind::ModifiedCtx { reg });
+        }
+        ctx::access(fields, off as i64, size, write)
+    }
+
+    fn check_alu(&mut self, insn: &Insn, state: &mut State) -> Result<(), ErrorKind> {
+        let alu64 = insn.op as u32 & 0x07 == BPF_ALU64;
+        let code = insn.op as u32 & 0xf0;
+        // the source bit of END selects the byte order
+        let src = match insn.op as u32 & BPF_X {
+            BPF_X if code != BPF_END => Some(*read(state, insn.src)?),
+            _ => None,
+        };
+        let dst = match code {
+            BPF_MOV => RegState::NOT_INIT,
+            _ => *read(state, insn.dst)?,
+        };
+        let imm = Bounds::constant(insn.imm as i64 as u64);
+        let src_bounds = src.map_or(imm, |src| src.bounds);
+        let src_ptr = src.is_some_and(|src| src.ty.is_pointer());
+        let value = match code {
+            BPF_MOV => match src {
+                // copies of a scalar share what is learned about it
+                Some(mut src) if alu64 => {
+                    if src.ty == RegType::Scalar && src.id == 0 {
+                        self.id_gen += 1;
+                        src.id = self.id_gen;
+                        state.regs[insn.src as usize].id = src.id;
+                    }
+                    src
+                }
+                // a 32-bit move truncates a pointer to a scalar
+                Some(_) if src_ptr => RegState::SCALAR,
+                _ => RegState::scalar(bounds::alu(code, alu64, &dst.bounds, &src_bounds)),
+            },
+            BPF_END if !dst.ty.is_pointer() => {
+                let big_endian = insn.op as u32 & BPF_X == BPF_TO_BE;
+                RegState::scalar(bounds::end(&dst.bounds, big_endian, insn.imm as u32))
+            }
+            _ if !dst.ty.is_pointer() && !src_ptr => {
+                RegState::scalar(bounds::alu(code, alu64, &dst.bounds, &src_bounds))
+            }
+            BPF_ADD | BPF_SUB if alu64 => ptr_arithmetic(insn, dst, src, &mut self.id_gen)?,
+            _ => {
+                let reg = if dst.ty.is_pointer() {
+                    insn.dst
+                } else {
+                    insn.src
+                };
+                return Err(ErrorKind::PointerArithmetic { reg });
+            }
+        };
+        write(state, insn.dst, value)
+    }
+}
+
+/// Returns `next + off`, which `cfg::check` has made sure is in range.
+fn jump(next: usize, off: i16) -> usize {
+    (next as i64 + off as i64) as usize
+}
+
+/// Returns the access size in bytes of a load or store.
+fn size(op: u8) -> u32 {
+    match op as u32 & 0x18 {
+        BPF_B => 1,
+        BPF_H => 2,
+        BPF_W => 4,
+        _ => 8,
+    }
+}
+
+fn read(state: &State, reg: u8) -> Result<&RegState, ErrorKind> {
+    let value = &state.regs[reg as usize];
+    match value.ty {
+        RegType::NotInit => Err(ErrorKind::UninitRegister { reg }),
+        _ => Ok(value),
+    }
+}
+
+fn write(state: &mut State, reg: u8, value: RegState) -> Result<(), ErrorKind> {
+    if reg == 10 {
+        return Err(ErrorKind::FramePointerWrite);
+    }
+    state.regs[reg as usize] = value;
+    Ok(())
+}
+
+/// Checks that memory may be accessed through `reg`.
+fn check_mem(state: &State, reg: u8) -> Result<RegState, ErrorKind> {
+    let value = *read(state, reg)?;
+    match value.ty.is_dereferenceable() {
+        true => Ok(value),
+        false => Err(ErrorKind::InvalidMemAccess { reg }),
+    }
+}
+
+/// Any value loaded from `size` bytes of memory.
+fn unknown(size: u32) -> RegState {
+    match size {
+        8 => RegState::SCALAR,
+        size => RegState::scalar(Bounds::zero_extended(size)),
+    }
+}
+
+/// Checks that an access of `size` bytes at `off` from a pointer into a map
+/// key or value stays inside it, clear of a value's spin lock and timer, as
+/// `check_map_access` does.
+fn check_map_access(maps: &[MapDef], ptr: &RegState, off: i64, size: u32) -> Result<(), ErrorKind> {
+    let (limit, value) = match ptr.ty {
+        RegType::PtrToMapValue { fd } => {
+            let def = &maps[fd as usize];
+            (def.value_size, Some(def))
+        }
+        RegType::PtrToMapKey { fd } => (maps[fd as usize].key_size, None),
+        _ => return Ok(()),
+    };
+    let start = ptr.off as i64 + off;
+    let (lo, hi) = (
+        start + ptr.bounds.smin,
+        start + ptr.bounds.smax + size as i64,
+    );
+    if lo < 0 || hi > limit as i64 {
+        return Err(ErrorKind::InvalidMapAccess {
+            off: start,
+            size,
+            limit,
+        });
+    }
+    for (field, field_size) in value.into_iter().flat_map(MapDef::special_fields) {
+        if lo < (field + field_size) as i64 && (field as i64) < hi {
+            return Err(ErrorKind::InvalidFieldAccess { off: start, size });
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the map value `ptr` may point into is not read-only to
+/// programs, as `check_map_access_type` does.
+fn check_map_write(maps: &[MapDef], ptr: &RegState) -> Result<(), ErrorKind> {
+    match ptr.ty {
+        RegType::PtrToMapValue { fd } if maps[fd as usize].map_flags & BPF_F_RDONLY_PROG != 0 => {
+            Err(ErrorKind::ReadOnlyMap { fd })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks that an access of `size` bytes at `off` from a packet pointer is
+/// within the range proven for it, as `check_packet_access` does.
+fn check_packet_access(ptr: &RegState, off: i64, size: u32) -> Result<(), ErrorKind> {
+    if ptr.ty != RegType::PtrToPacket {
+        return Ok(());
+    }
+    let start = ptr.off as i64 + off;
+    match ptr.bounds.smin >= 0 && start >= 0 && start + size as i64 <= ptr.range as i64 {
+        true => Ok(()),
+        false => Err(ErrorKind::InvalidPacketAccess {
+            off: start,
+            size,
+            range: ptr.range,
+        }),
+    }
+}
+
+/// Adds or subtracts `src`, or the immediate if there is none, to `dst`,
+/// where either is a pointer, as `adjust_ptr_min_max_vals` allows. A packet
+/// pointer moved by a variable amount gets a new id from `id_gen`, as the
+/// range proven for its old position no longer applies.
+fn ptr_arithmetic(
+    insn: &Insn,
+    dst: RegState,
+    src: Option<RegState>,
+    id_gen: &mut u32,
+) -> Result<RegState, ErrorKind> {
+    let sub = insn.op as u32 & 0xf0 == BPF_SUB;
+    let err = |reg| ErrorKind::PointerArithmetic { reg };
+    let (ptr, scalar, reg) = match src {
+        None => {
+            let imm = Bounds::constant(insn.imm as i64 as u64);
+            (dst, RegState::scalar(imm), insn.dst)
+        }
+        Some(src) if !src.ty.is_pointer() => (dst, src, insn.dst),
+        Some(src) if !dst.ty.is_pointer() && !sub => (src, dst, insn.src),
+        Some(_) if !dst.ty.is_pointer() => return Err(err(insn.src)),
+        Some(src) => {
+            return match sub && dst.ty.same_base(src.ty) {
+                true => Ok(RegState::SCALAR),
+                false => Err(err(insn.dst)),
+            };
+        }
+    };
+    if !ptr.ty.allows_arithmetic() {
+        return Err(err(reg));
+    }
+    let mut value = ptr;
+    match scalar.bounds.value() {
+        Some(delta) => {
+            let delta = match sub {
+                true => (delta as i64).wrapping_neg(),
+                false => delta as i64,
+            };
+            match (ptr.off as i64).checked_add(delta) {
+                Some(off) if off > -MAX_OFF && off < MAX_OFF => value.off = off as i32,
+                _ => return Err(err(reg)),
+            }
+        }
+        None => {
+            let code = if sub { BPF_SUB } else { BPF_ADD };
+            value.bounds = bounds::alu(code, true, &ptr.bounds, &scalar.bounds);
+            if value.ty == RegType::PtrToPacket {
+                *id_gen += 1;
+                value.id = *id_gen;
+                value.range = 0;
+            }
+        }
+    }
+    if value.bounds.smin <= -MAX_OFF || value.bounds.smax >= MAX_OFF {
+        return Err(err(reg));
+    }
+    if matches!(value.ty, RegType::PtrToStack | RegType::PtrToCallerStack) {
+        let off = value.off as i64;
+        if off + value.bounds.smin < -stack::STACK_SIZE || off + value.bounds.smax > 0 {
+            return Err(ErrorKind::StackOutOfRange { reg });
+        }
+    }
+    Ok(value)
+}
+
+/// Checks the operands of a conditional jump and returns the states it
+/// falls through and jumps in, or `None` for a side that cannot be taken.
+fn check_cond(
+    insn: &Insn,
+    state: &State,
+    maps: &[MapDef],
+) -> Result<(Option<State>, Option<State>), ErrorKind> {
+    let dst = *read(state, insn.dst)?;
+    let src = match insn.op as u32 & BPF_X {
+        BPF_X => *read(state, insn.src)?,
+        _ => RegState::scalar(Bounds::constant(insn.imm as i64 as u64)),
+    };
+    let mut fall = state.clone();
+    let mut taken = state.clone();
+    if let RegType::PtrToMapValueOrNull { .. } = dst.ty {
+        let (null, non_null) = match insn.op {
+            JMP_K_JEQ if insn.imm == 0 => (&mut taken, &mut fall),
+            JMP_K_JNE if insn.imm == 0 => (&mut fall, &mut taken),
+            _ => return Ok((Some(fall), Some(taken))),
+        };
+        mark_ptr_or_null(null, maps, dst.id, true);
+        mark_ptr_or_null(non_null, maps, dst.id, false);
+        return Ok((Some(fall), Some(taken)));
+    }
+    let code = insn.op as u32 & 0xf0;
+    let jmp32 = insn.op as u32 & 0x07 == BPF_JMP32;
+    if dst.ty != RegType::Scalar || src.ty != RegType::Scalar {
+        if !jmp32 && insn.op as u32 & BPF_X != 0 {
+            match_pkt_pointers(code, &dst, &src, &mut fall, &mut taken);
+        }
+        return Ok((Some(fall), Some(taken)));
+    }
+    match bounds::branch_taken(code, jmp32, &dst.bounds, &src.bounds) {
+        Some(true) => Ok((None, Some(taken))),
+        Some(false) => Ok((Some(fall), None)),
+        None => {
+            let narrow = |mut state: State, is_taken| {
+                let (mut dst, mut src) = (dst.bounds, src.bounds);
+                bounds::refine(code, jmp32, is_taken, &mut dst, &mut src);
+                if !dst.is_sane() || !src.is_sane() {
+                    return None;
+                }
+                set_bounds(&mut state, insn.dst, dst);
+                if insn.op as u32 & BPF_X != 0 {
+                    set_bounds(&mut state, insn.src, src);
+                }
+                Some(state)
+            };
+            Ok((narrow(fall, false), narrow(taken, true)))
+        }
+    }
+}
+
+/// Records the range a comparison of a packet pointer with `data_end` proves
+/// on the side where the pointer is not past the end, as
+/// `try_match_pkt_pointers` does.
+fn match_pkt_pointers(
+    code: u32,
+    dst: &RegState,
+    src: &RegState,
+    fall: &mut State,
+    taken: &mut State,
+) {
+    // as `pkt <op> pkt_end`
+    let (pkt, code) = match (dst.ty, src.ty) {
+        (RegType::PtrToPacket, RegType::PtrToPacketEnd) => (dst, code),
+        (RegType::PtrToPacketEnd, RegType::PtrToPacket) => match code {
+            BPF_JGT => (src, BPF_JLT),
+            BPF_JLT => (src, BPF_JGT),
+            BPF_JGE => (src, BPF_JLE),
+            BPF_JLE => (src, BPF_JGE),
+            _ => return,
+        },
+        _ => return,
+    };
+    // the side on which `pkt` is at most `pkt_end`, and whether it is
+    // strictly less, so the byte at `pkt` is in the packet too
+    let (state, open) = match code {
+        BPF_JGT => (fall, false),
+        BPF_JGE => (fall, true),
+        BPF_JLT => (taken, true),
+        BPF_JLE => (taken, false),
+        _ => return,
+    };
+    if pkt.off < 0 || (pkt.off == 0 && !open) {
+        return;
+    }
+    let range = pkt.off as u32 + open as u32;
+    let same = |reg: &RegState| reg.ty == RegType::PtrToPacket && reg.id == pkt.id;
+    let spilled = state.stack.iter_mut().filter(|slot| slot.is_spill());
+    let spilled = spilled.map(|slot| &mut slot.spilled);
+    for reg in state.regs.iter_mut().chain(spilled).filter(|reg| same(reg)) {
+        reg.range = reg.range.max(range);
+    }
+}
+
+/// Sets the bounds of the scalar in `reg` and its copies, as
+/// `find_equal_scalars` does.
+fn set_bounds(state: &mut State, reg: u8, bounds: Bounds) {
+    let id = state.regs[reg as usize].id;
+    state.regs[reg as usize].bounds = bounds;
+    if id != 0 {
+        for reg in state.regs.iter_mut().filter(|other| other.id == id) {
+            reg.bounds = bounds;
+        }
+    }
+}
+
+/// Settles whether the pointers with `id`, in registers or spilled, are
+/// NULL, as `mark_ptr_or_null_regs` does. A value with a spin lock keeps its
+/// id, which tells whose lock it takes.
+fn mark_ptr_or_null(state: &mut State, maps: &[MapDef], id: u32, is_null: bool) {
+    let spilled = state.stack.iter_mut().filter(|slot| slot.is_spill());
+    let spilled = spilled.map(|slot| &mut slot.spilled);
+    for reg in state
+        .regs
+        .iter_mut()
+        .chain(spilled)
+        .filter(|reg| reg.id == id)
+    {
+        if let RegType::PtrToMapValueOrNull { fd } = reg.ty {
+            *reg = match is_null {
+                true => RegState::scalar(Bounds::constant(0)),
+                false => RegState {
+                    ty: RegType::PtrToMapValue { fd },
+                    id: match maps[fd as usize].spin_lock_off {
+                        Some(_) => id,
+                        None => 0,
+                    },
+                    ..*reg
+                },
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::helpers::proto::{HelperProto, RetType};
+    use crate::helpers::BpfFunc;
+    use core::convert::TryInto;
+
+    fn verify_at(insts: &[u64], env: &Env) -> Result<(), (usize, ErrorKind)> {
+        verify(insts, env).map_err(|err| (err.pc, err.kind))
+    }
+
+    #[test]
+    fn registers() {
+        let inst = Insn::encode;
+        let env = Env {
+            prog_type: BPF_PROG_TYPE_KPROBE,
+            ..Env::default()
+        };
+        let exit = inst(JMP_K_EXIT, 0, 0, 0, 0);
+        let mov = inst(ALU64_K_MOV, 0, 0, 0, 0);
+        assert_eq!(verify_at(&[mov, exit], &env), Ok(()));
+        assert_eq!(
+            verify_at(&[exit], &env),
+            Err((0, ErrorKind::UninitRegister { reg: 0 }))
+        );
+        assert_eq!(
+            verify_at(&[inst(ALU64_X_MOV, 0, 2, 0, 0), exit], &env),
+            Err((0, ErrorKind::UninitRegister { reg: 2 }))
+        );
+        assert_eq!(
+            verify_at(&[inst(ALU64_K_MOV, 10, 0, 0, 0), mov, exit], &env),
+            Err((0, ErrorKind::FramePointerWrite))
+        );
+        // a call clobbers r1-r5
+        let call = inst(JMP_K_CALL, 0, 0, 0, BpfFunc::KtimeGetNs as i32);
+        assert_eq!(
+            verify_at(&[call, inst(ALU64_X_MOV, 0, 1, 0, 0), exit], &env),
+            Err((1, ErrorKind::UninitRegister { reg: 1 }))
+        );
+        // r0 is only set on the path that falls through
+        let prog = [inst(JMP_K_JEQ, 1, 0, 1, 0), mov, exit];
+        assert_eq!(
+            verify_at(&prog, &env),
+            Err((2, ErrorKind::UninitRegister { reg: 0 }))
+        );
+        let prog = [mov, inst(JMP_K_JEQ, 1, 0, 1, 0), mov, exit];
+        assert_eq!(verify_at(&prog, &env), Ok(()));
+    }
+
+    #[test]
+    fn pointers() {
+        let inst = Insn::encode;
+        let env = Env {
+            prog_type: BPF_PROG_TYPE_KPROBE,
+            ..Env::default()
+        };
+        let exit = inst(JMP_K_EXIT, 0, 0, 0, 0);
+        let mov = inst(ALU64_K_MOV, 0, 0, 0, 0);
+        let fp = inst(ALU64_X_MOV, 2, 10, 0, 0);
+        let prog = [
+            fp,
+            inst(ALU64_K_ADD, 2, 0, 0, -8),
+            inst(ST_MEM_DW, 2, 0, 0, 0),
+            inst(STX_MEM_DW, 2, 1, 0, 0),
+            inst(LDX_MEM_W, 0, 1, 0, 0),
+            inst(ALU64_X_SUB, 2, 10, 0, 0),
+            inst(ALU64_X_ADD, 0, 2, 0, 0),
+            exit,
+        ];
+        assert_eq!(verify_at(&prog, &env), Ok(()));
+
+        let bad = [
+            (inst(ALU64_K_MUL, 2, 0, 0, 2), 2),
+            (inst(ALU_K_ADD, 2, 0, 0, 8), 2),
+            (inst(ALU64_K_NEG, 2, 0, 0, 0), 2),
+            (inst(ALU64_X_ADD, 2, 1, 0, 0), 2),
+            (inst(ALU64_X_SUB, 2, 1, 0, 0), 2),
+            (inst(ALU64_K_ADD, 2, 0, 0, 1 << 29), 2),
+            (inst(ALU64_X_SUB, 0, 2, 0, 0), 2),
+        ];
+        for (insn, reg) in bad {
+            assert_eq!(
+                verify_at(&[mov, fp, insn, exit], &env),
+                Err((2, ErrorKind::PointerArithmetic { reg }))
+            );
+        }
+        // a 32-bit move leaves a scalar
+        let prog = [inst(ALU_X_MOV, 0, 10, 0, 0), exit];
+        assert_eq!(verify_at(&prog, &env), Ok(()));
+        let prog = [inst(ALU64_X_MOV, 0, 10, 0, 0), exit];
+        assert_eq!(
+            verify_at(&prog, &env),
+            Err((1, ErrorKind::PointerLeak { reg: 0 }))
+        );
+        let prog = [inst(STX_MEM_DW, 1, 10, 0, 0), mov, exit];
+        assert_eq!(
+            verify_at(&prog, &env),
+            Err((0, ErrorKind::PointerLeak { reg: 10 }))
+        );
+        let prog = [mov, inst(LDX_MEM_DW, 0, 0, 0, 0), exit];
+        assert_eq!(
+            verify_at(&prog, &env),
+            Err((1, ErrorKind::InvalidMemAccess { reg: 0 }))
+        );
+    }
+
+    #[test]
+    fn bounds() {
+        let inst = Insn::encode;
+        let env = Env {
+            prog_type: BPF_PROG_TYPE_KPROBE,
+            ..Env::default()
+        };
+        let exit = inst(JMP_K_EXIT, 0, 0, 0, 0);
+
+        // the jump is never taken, so r2 is never read
+        let prog = [
+            inst(ALU64_K_MOV, 0, 0, 0, 1),
+            inst(JMP_K_JNE, 0, 0, 1, 1),
+            exit,
+            inst(ALU64_X_MOV, 0, 2, 0, 0),
+            exit,
+        ];
+        assert_eq!(verify_at(&prog, &env), Ok(()));
+
+        // a variable offset is fine once bounded, here by a 32-bit check on
+        // a copy of the value
+        let prog = |check| {
+            [
+                inst(LDX_MEM_W, 3, 1, 0, 0),
+                inst(ALU64_K_MOV, 0, 0, 0, 0),
+                inst(ALU64_X_MOV, 4, 3, 0, 0),
+                check,
+                inst(ALU64_X_MOV, 2, 10, 0, 0),
+                inst(ALU64_K_ADD, 2, 0, 0, -16),
+                inst(ALU64_X_ADD, 2, 3, 0, 0),
+                exit,
+            ]
+        };
+        let check = inst(JMP32_K_JGT, 4, 0, 3, 8);
+        assert_eq!(verify_at(&prog(check), &env), Ok(()));
+        let check = inst(JMP32_K_JGT, 5, 0, 3, 8);
+        assert_eq!(
+            verify_at(&prog(check), &env),
+            Err((3, ErrorKind::UninitRegister { reg: 5 }))
+        );
+        let check = inst(JMP_K_JSGT, 4, 0, 3, 8);
+        assert_eq!(verify_at(&prog(check), &env), Ok(()));
+        let check = inst(JMP_K_JLT, 4, 0, 3, 8);
+        assert_eq!(
+            verify_at(&prog(check), &env),
+            Err((6, ErrorKind::PointerArithmetic { reg: 2 }))
+        );
+        let prog = [
+            inst(LDX_MEM_W, 3, 1, 0, 0),
+            inst(ALU64_X_MOV, 0, 10, 0, 0),
+            inst(ALU64_X_ADD, 0, 3, 0, 0),
+            exit,
+        ];
+        assert_eq!(
+            verify_at(&prog, &env),
+            Err((2, ErrorKind::PointerArithmetic { reg: 0 }))
+        );
+
+        // 32-bit results are zero-extended, so w1 = -1 leaves r1 = 0xffffffff
+        let prog = |shift| {
+            [
+                inst(ALU64_X_MOV, 2, 10, 0, 0),
+                inst(ALU64_K_ADD, 2, 0, 0, -8),
+                inst(ALU_K_MOV, 1, 0, 0, -1),
+                inst(ALU_K_ADD, 1, 0, 0, 0),
+                inst(ALU64_K_RSH, 1, 0, 0, shift),
+                inst(ALU64_X_ADD, 2, 1, 0, 0),
+                inst(ST_MEM_B, 2, 0, 0, 0),
+                inst(ALU64_K_MOV, 0, 0, 0, 0),
+                exit,
+            ]
+        };
+        assert_eq!(verify_at(&prog(32), &env), Ok(()));
+        assert_eq!(
+            verify_at(&prog(0), &env),
+            Err((5, ErrorKind::PointerArithmetic { reg: 2 }))
+        );
+    }
+
+    #[test]
+    fn stack() {
+        let inst = Insn::encode;
+        let env = Env {
+            prog_type: BPF_PROG_TYPE_KPROBE,
+            ..Env::default()
+        };
+        let exit = inst(JMP_K_EXIT, 0, 0, 0, 0);
+        let mov = inst(ALU64_K_MOV, 0, 0, 0, 0);
+
+        // a spilled pointer is filled with its type, so r0 leaks it
+        let prog = [
+            inst(STX_MEM_DW, 10, 1, -8, 0),
+            inst(LDX_MEM_DW, 0, 10, -8, 0),
+            exit,
+        ];
+        assert_eq!(
+            verify_at(&prog, &env),
+            Err((2, ErrorKind::PointerLeak { reg: 0 }))
+        );
+        let prog = [
+            inst(STX_MEM_DW, 10, 1, -8, 0),
+            inst(LDX_MEM_DW, 2, 10, -8, 0),
+            inst(LDX_MEM_W, 0, 2, 0, 0),
+            exit,
+        ];
+        assert_eq!(verify_at(&prog, &env), Ok(()));
+        // a spilled scalar keeps its bounds
+        let prog = [
+            inst(ST_MEM_DW, 10, 0, -16, 8),
+            inst(LDX_MEM_DW, 3, 10, -16, 0),
+            inst(ALU64_X_MOV, 2, 10, 0, 0),
+            inst(ALU64_K_ADD, 2, 0, 0, -8),
+            inst(ALU64_X_SUB, 2, 3, 0, 0),
+            inst(LDX_MEM_DW, 0, 2, 0, 0),
+            exit,
+        ];
+        assert_eq!(verify_at(&prog, &env), Ok(()));
+
+        let prog = [inst(LDX_MEM_W, 0, 10, -4, 0), exit];
+        assert_eq!(
+            verify_at(&prog, &env),
+            Err((0, ErrorKind::UninitStack { off: -4, size: 4 }))
+        );
+        let prog = [
+            inst(ST_MEM_W, 10, 0, -8, 0),
+            inst(LDX_MEM_DW, 0, 10, -8, 0),
+            exit,
+        ];
+        assert_eq!(
+            verify_at(&prog, &env),
+            Err((1, ErrorKind::UninitStack { off: -8, size: 8 }))
+        );
+        let prog = [
+            inst(ST_MEM_W, 10, 0, -8, 0),
+            inst(ST_MEM_H, 10, 0, -4, 7),
+            inst(ST_MEM_H, 10, 0, -2, 7),
+            inst(LDX_MEM_DW, 0, 10, -8, 0),
+            exit,
+        ];
+        assert_eq!(verify_at(&prog, &env), Ok(()));
+        // only the part of a spilled pointer that is left
+        let prog = [
+            inst(STX_MEM_DW, 10, 1, -8, 0),
+            inst(ST_MEM_W, 10, 0, -8, 0),
+            inst(LDX_MEM_DW, 0, 10, -8, 0),
+            exit,
+        ];
+        assert_eq!(verify_at(&prog, &env), Ok(()));
+        let prog = [
+            inst(STX_MEM_DW, 10, 1, -8, 0),
+            inst(LDX_MEM_W, 0, 10, -8, 0),
+            exit,
+        ];
+        assert_eq!(
+            verify_at(&prog, &env),
+            Err((1, ErrorKind::InvalidStackAccess { off: -8, size: 4 }))
+        );
+
+        let bad = [
+            (inst(ST_MEM_DW, 10, 0, 0, 0), 0, 8),
+            (inst(ST_MEM_DW, 10, 0, -520, 0), -520, 8),
+            (inst(ST_MEM_W, 10, 0, -6, 0), -6, 4),
+            (inst(STX_MEM_W, 10, 1, -8, 0), -8, 4),
+        ];
+        for (insn, off, size) in bad {
+            assert_eq!(
+                verify_at(&[insn, mov, exit], &env),
+                Err((0, ErrorKind::InvalidStackAccess { off, size }))
+            );
+        }
+        let prog = [
+            inst(ALU64_X_MOV, 2, 10, 0, 0),
+            inst(ALU64_K_ADD, 2, 0, 0, -516),
+            mov,
+            exit,
+        ];
+        assert_eq!(
+            verify_at(&prog, &env),
+            Err((1, ErrorKind::StackOutOfRange { reg: 2 }))
+        );
+    }
+
+    #[test]
+    fn context() {
+        let inst = Insn::encode;
+        let exit = inst(JMP_K_EXIT, 0, 0, 0, 0);
+        let mov = inst(ALU64_K_MOV, 0, 0, 0, 0);
+        let env = |prog_type| Env {
+            prog_type,
+            ..Env::default()
+        };
+        let xdp = env(BPF_PROG_TYPE_XDP);
+        let tc = env(BPF_PROG_TYPE_SCHED_CLS);
+        let filter = env(BPF_PROG_TYPE_SOCKET_FILTER);
+        let access = |insn, env: &Env| verify_at(&[insn, mov, exit], env);
+        let invalid = |off, size| Err((0, ErrorKind::InvalidCtxAccess { off, size }));
+
+        // data is a packet pointer, so it may not be returned
+        let prog = [inst(LDX_MEM_W, 0, 1, 0, 0), exit];
+        assert_eq!(
+            verify_at(&prog, &xdp),
+            Err((1, ErrorKind::PointerLeak { reg: 0 }))
+        );
+        assert_eq!(access(inst(LDX_MEM_W, 2, 1, 12, 0), &xdp), Ok(()));
+        assert_eq!(access(inst(LDX_MEM_W, 2, 1, 24, 0), &xdp), invalid(24, 4));
+        assert_eq!(access(inst(LDX_MEM_W, 2, 1, 14, 0), &xdp), invalid(14, 4));
+        assert_eq!(access(inst(LDX_MEM_H, 2, 1, 4, 0), &xdp), invalid(4, 2));
+        assert_eq!(access(inst(LDX_MEM_DW, 2, 1, 12, 0), &xdp), invalid(12, 8));
+        assert_eq!(access(inst(ST_MEM_W, 1, 0, 12, 0), &xdp), invalid(12, 4));
+
+        // part of a scalar field may be loaded, but only all of it stored
+        assert_eq!(access(inst(LDX_MEM_B, 2, 1, 1, 0), &tc), Ok(()));
+        assert_eq!(access(inst(ST_MEM_W, 1, 0, 8, 1), &tc), Ok(()));
+        assert_eq!(access(inst(ST_MEM_H, 1, 0, 8, 1), &tc), invalid(8, 2));
+        assert_eq!(access(inst(ST_MEM_W, 1, 0, 8, 1), &filter), invalid(8, 4));
+        assert_eq!(access(inst(ST_MEM_W, 1, 0, 64, 1), &filter), Ok(()));
+        assert_eq!(
+            access(inst(LDX_MEM_W, 2, 1, 76, 0), &filter),
+            invalid(76, 4)
+        );
+        let kprobe = env(BPF_PROG_TYPE_KPROBE);
+        assert_eq!(access(inst(LDX_MEM_DW, 2, 1, 8, 0), &kprobe), Ok(()));
+        assert_eq!(access(inst(LDX_MEM_W, 2, 1, 4, 0), &kprobe), Ok(()));
+        assert_eq!(
+            access(inst(LDX_MEM_DW, 2, 1, 4096, 0), &kprobe),
+            invalid(4096, 8)
+        );
+        let raw = env(BPF_PROG_TYPE_RAW_TRACEPOINT);
+        assert_eq!(access(inst(LDX_MEM_W, 2, 1, 8, 0), &raw), invalid(8, 4));
+
+        let prog = [
+            inst(ALU64_K_ADD, 1, 0, 0, 12),
+            inst(LDX_MEM_W, 2, 1, 0, 0),
+            mov,
+            exit,
+        ];
+        assert_eq!(
+            verify_at(&prog, &xdp),
+            Err((1, ErrorKind::ModifiedCtx { reg: 1 }))
+        );
+        // a program type without a description has no context to access
+        assert_eq!(
+            verify_at(&prog, &Env::default()),
+            Err((1, ErrorKind::UnknownProgType { prog_type: 0 }))
+        );
+    }
+
+    #[test]
+    fn packets() {
+        let inst = Insn::encode;
+        let exit = inst(JMP_K_EXIT, 0, 0, 0, 0);
+        let env = Env {
+            prog_type: BPF_PROG_TYPE_XDP,
+            ..Env::default()
+        };
+        let prog = |check, load| {
+            [
+                inst(LDX_MEM_W, 2, 1, 0, 0),
+                inst(LDX_MEM_W, 3, 1, 4, 0),
+                inst(ALU64_X_MOV, 4, 2, 0, 0),
+                inst(ALU64_K_ADD, 4, 0, 0, 14),
+                check,
+                load,
+                inst(ALU64_K_MOV, 0, 0, 0, 0),
+                exit,
+            ]
+        };
+        let invalid =
+            |off, size, range| Err((5, ErrorKind::InvalidPacketAccess { off, size, range }));
+
+        // data + 14 <= data_end proves the first 14 bytes
+        let jgt = inst(JMP_X_JGT, 4, 3, 1, 0);
+        assert_eq!(
+            verify_at(&prog(jgt, inst(LDX_MEM_H, 5, 2, 12, 0)), &env),
+            Ok(())
+        );
+        assert_eq!(
+            verify_at(&prog(jgt, inst(LDX_MEM_B, 5, 4, -1, 0)), &env),
+            Ok(())
+        );
+        assert_eq!(
+            verify_at(&prog(jgt, inst(LDX_MEM_H, 5, 2, 13, 0)), &env),
+            invalid(13, 2, 14)
+        );
+        assert_eq!(
+            verify_at(&prog(jgt, inst(LDX_MEM_B, 5, 2, -1, 0)), &env),
+            invalid(-1, 1, 14)
+        );
+        assert_eq!(
+            verify_at(&prog(jgt, inst(ST_MEM_B, 4, 0, 0, 0)), &env),
+            invalid(14, 1, 14)
+        );
+        // and data + 14 < data_end one more, from either side
+        let jge = inst(JMP_X_JGE, 4, 3, 1, 0);
+        assert_eq!(
+            verify_at(&prog(jge, inst(LDX_MEM_H, 5, 2, 13, 0)), &env),
+            Ok(())
+        );
+        let jle = inst(JMP_X_JLE, 3, 4, 1, 0);
+        assert_eq!(
+            verify_at(&prog(jle, inst(LDX_MEM_H, 5, 2, 13, 0)), &env),
+            Ok(())
+        );
+        let jlt = inst(JMP_X_JLT, 3, 4, 1, 0);
+        assert_eq!(
+            verify_at(&prog(jlt, inst(LDX_MEM_H, 5, 2, 13, 0)), &env),
+            invalid(13, 2, 14)
+        );
+        // the proof holds on the side where the pointer is not past the end
+        let jle = inst(JMP_X_JLE, 4, 3, 1, 0);
+        assert_eq!(
+            verify_at(&prog(jle, inst(LDX_MEM_H, 5, 2, 12, 0)), &env),
+            invalid(12, 2, 0)
+        );
+        let jgt32 = inst(JMP32_X_JGT, 4, 3, 1, 0);
+        assert_eq!(
+            verify_at(&prog(jgt32, inst(LDX_MEM_H, 5, 2, 12, 0)), &env),
+            invalid(12, 2, 0)
+        );
+
+        // moving a pointer by a variable amount starts a new range
+        let prog = [
+            inst(LDX_MEM_W, 2, 1, 0, 0),
+            inst(LDX_MEM_W, 3, 1, 4, 0),
+            inst(LDX_MEM_W, 4, 1, 12, 0),
+            inst(ALU64_K_AND, 4, 0, 0, 0xff),
+            inst(ALU64_X_ADD, 2, 4, 0, 0),
+            inst(LDX_MEM_B, 5, 2, 0, 0),
+            inst(ALU64_K_MOV, 0, 0, 0, 0),
+            exit,
+        ];
+        assert_eq!(
+            verify_at(&prog, &env),
+            Err((
+                5,
+                ErrorKind::InvalidPacketAccess {
+                    off: 0,
+                    size: 1,
+                    range: 0
+                }
+            ))
+        );
+        let mut checked = prog.to_vec();
+        checked.splice(
+            5..5,
+            [
+                inst(ALU64_X_MOV, 6, 2, 0, 0),
+                inst(ALU64_K_ADD, 6, 0, 0, 1),
+                inst(JMP_X_JGT, 6, 3, 1, 0),
+            ],
+        );
+        assert_eq!(verify_at(&checked, &env), Ok(()));
+    }
+
+    #[test]
+    fn maps_and_callbacks() {
+        let inst = Insn::encode;
+        let exit = inst(JMP_K_EXIT, 0, 0, 0, 0);
+        let mov = inst(ALU64_K_MOV, 0, 0, 0, 0);
+        let map_fd = BPF_PSEUDO_MAP_FD as u8;
+        let prog = [inst(LD_IMM_DW, 1, map_fd, 0, 0), 0, mov, exit];
+        assert_eq!(
+            verify_at(&prog, &Env::default()),
+            Err((0, ErrorKind::InvalidMapFd { fd: 0 }))
+        );
+        let maps = [MapDef::default()];
+        let env = Env {
+            maps: &maps,
+            ..Env::default()
+        };
+        assert_eq!(verify_at(&prog, &env), Ok(()));
+        let prog = [
+            inst(LD_IMM_DW, 1, map_fd, 0, 0),
+            0,
+            inst(LDX_MEM_DW, 0, 1, 0, 0),
+            exit,
+        ];
+        assert_eq!(
+            verify_at(&prog, &env),
+            Err((2, ErrorKind::InvalidMemAccess { reg: 1 }))
+        );
+        let prog = [
+            inst(LD_IMM_DW, 1, map_fd, 0, 0),
+            0,
+            inst(ALU64_K_ADD, 1, 0, 0, 8),
+            mov,
+            exit,
+        ];
+        assert_eq!(
+            verify_at(&prog, &env),
+            Err((2, ErrorKind::PointerArithmetic { reg: 1 }))
+        );
+
+        // a callback starts with no context and may not return pointers
+        let func = BPF_PSEUDO_FUNC as u8;
+        let prog = [
+            inst(LD_IMM_DW, 2, func, 0, 3),
+            0,
+            mov,
+            exit,
+            inst(ALU64_X_MOV, 0, 1, 0, 0),
+            exit,
+        ];
+        assert_eq!(verify_at(&prog, &env), Ok(()));
+        let prog = [
+            inst(LD_IMM_DW, 2, func, 0, 3),
+            0,
+            inst(ALU64_X_MOV, 0, 2, 0, 0),
+            exit,
+            mov,
+            exit,
+        ];
+        assert_eq!(
+            verify_at(&prog, &env),
+            Err((3, ErrorKind::PointerLeak { reg: 0 }))
+        );
+    }
+
+    #[test]
+    fn loops() {
+        let inst = Insn::encode;
+        let env = Env {
+            prog_type: BPF_PROG_TYPE_KPROBE,
+            ..Env::default()
+        };
+        let exit = inst(JMP_K_EXIT, 0, 0, 0, 0);
+
+        // counts down from 100, each iteration a new state
+        let prog = [
+            inst(ALU64_K_MOV, 0, 0, 0, 0),
+            inst(ALU64_K_MOV, 1, 0, 0, 100),
+            inst(ALU64_K_ADD, 0, 0, 0, 2),
+            inst(ALU64_K_SUB, 1, 0, 0, 1),
+            inst(JMP_K_JNE, 1, 0, -3, 0),
+            exit,
+        ];
+        assert_eq!(verify_at(&prog, &env), Ok(()));
+        let prog = [inst(ALU64_K_MOV, 0, 0, 0, 0), inst(JMP_K_JA, 0, 0, -2, 0)];
+        assert_eq!(verify_at(&prog, &env), Err((0, ErrorKind::InfiniteLoop)));
+
+        // an unknown count is never decided, and the bound shrinks by one
+        // each iteration
+        let prog = [
+            inst(LDX_MEM_W, 1, 1, 0, 0),
+            inst(ALU64_K_SUB, 1, 0, 0, 1),
+            inst(JMP_K_JNE, 1, 0, -2, 0),
+            inst(ALU64_K_MOV, 0, 0, 0, 0),
+            exit,
+        ];
+        let err = verify(&prog, &env).unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::TooComplex { .. }));
+
+        // both sides of each branch meet again, so the second is pruned
+        // rather than doubling the paths at every step
+        let mut prog = vec![];
+        for _ in 0..40 {
+            prog.extend_from_slice(&[
+                inst(LDX_MEM_W, 2, 1, 0, 0),
+                inst(JMP_K_JGT, 2, 0, 2, 10),
+                inst(ALU64_K_MOV, 2, 0, 0, 0),
+                inst(JMP_K_JA, 0, 0, 1, 0),
+                inst(ALU64_K_MOV, 2, 0, 0, 0),
+            ]);
+        }
+        prog.extend_from_slice(&[inst(ALU64_K_MOV, 0, 0, 0, 0), exit]);
+        assert_eq!(verify_at(&prog, &env), Ok(()));
+
+        let prog = include_bytes!("tests/gauss.bin")
+            .chunks_exact(8)
+            .map(|x| u64::from_le_bytes(x.try_into().unwrap()))
+            .collect::<Vec<u64>>();
+        assert_eq!(verify_at(&prog, &env), Ok(()));
+    }
+
+    #[test]
+    fn helpers() {
+        let inst = Insn::encode;
+        let exit = inst(JMP_K_EXIT, 0, 0, 0, 0);
+        let mov = inst(ALU64_K_MOV, 0, 0, 0, 0);
+        let call = |func: BpfFunc| inst(JMP_K_CALL, 0, 0, 0, func as i32);
+        let map_fd = BPF_PSEUDO_MAP_FD as u8;
+        let maps = [MapDef {
+            key_size: 8,
+            value_size: 4,
+            ..MapDef::default()
+        }];
+        let env = Env {
+            maps: &maps,
+            ..Env::default()
+        };
+
+        // the key must be initialized, and the value checked for NULL and
+        // accessed within its size
+        let lookup = |store, check, load| {
+            [
+                inst(LD_IMM_DW, 1, map_fd, 0, 0),
+                0,
+                store,
+                inst(ALU64_X_MOV, 2, 10, 0, 0),
+                inst(ALU64_K_ADD, 2, 0, 0, -8),
+                call(BpfFunc::MapLookupElem),
+                check,
+                load,
+                mov,
+                exit,
+            ]
+        };
+        let store = inst(ST_MEM_DW, 10, 0, -8, 0);
+        let check = inst(JMP_K_JEQ, 0, 0, 1, 0);
+        let load = inst(LDX_MEM_W, 1, 0, 0, 0);
+        assert_eq!(verify_at(&lookup(store, check, load), &env), Ok(()));
+        assert_eq!(
+            verify_at(&lookup(mov, check, load), &env),
+            Err((5, ErrorKind::UninitStack { off: -8, size: 8 }))
+        );
+        assert_eq!(
+            verify_at(&lookup(store, mov, load), &env),
+            Err((7, ErrorKind::InvalidMemAccess { reg: 0 }))
+        );
+        let load = inst(LDX_MEM_W, 1, 0, 4, 0);
+        assert_eq!(
+            verify_at(&lookup(store, check, load), &env),
+            Err((
+                7,
+                ErrorKind::InvalidMapAccess {
+                    off: 4,
+                    size: 4,
+                    limit: 4
+                }
+            ))
+        );
+        // only helpers may touch a value's spin lock and timer
+        let fields = [MapDef {
+            key_size: 8,
+            value_size: 32,
+            spin_lock_off: Some(0),
+            timer_off: Some(8),
+            ..MapDef::default()
+        }];
+        let fields_env = Env {
+            maps: &fields,
+            ..Env::default()
+        };
+        let load = |off| inst(LDX_MEM_W, 1, 0, off, 0);
+        assert_eq!(
+            verify_at(&lookup(store, check, load(4)), &fields_env),
+            Ok(())
+        );
+        assert_eq!(
+            verify_at(&lookup(store, check, load(24)), &fields_env),
+            Ok(())
+        );
+        for off in [0, 20] {
+            assert_eq!(
+                verify_at(&lookup(store, check, load(off)), &fields_env),
+                Err((
+                    7,
+                    ErrorKind::InvalidFieldAccess {
+                        off: off as i64,
+                        size: 4
+                    }
+                ))
+            );
+        }
+
+        // a spin lock is held until unlocked, with no other calls meanwhile
+        let locked = |body: &[u64]| {
+            let lock = [
+                inst(LD_IMM_DW, 1, map_fd, 0, 0),
+                0,
+                store,
+                inst(ALU64_X_MOV, 2, 10, 0, 0),
+                inst(ALU64_K_ADD, 2, 0, 0, -8),
+                call(BpfFunc::MapLookupElem),
+                inst(JMP_K_JNE, 0, 0, 2, 0),
+                mov,
+                exit,
+                inst(ALU64_X_MOV, 6, 0, 0, 0),
+                inst(ALU64_X_MOV, 1, 6, 0, 0),
+                call(BpfFunc::SpinLock),
+            ];
+            [&lock[..], body, &[mov, exit]].concat()
+        };
+        let r1 = inst(ALU64_X_MOV, 1, 6, 0, 0);
+        let unlock = [r1, call(BpfFunc::SpinUnlock)];
+        let body = [&[inst(ST_MEM_W, 6, 0, 4, 1)][..], &unlock].concat();
+        assert_eq!(verify_at(&locked(&body), &fields_env), Ok(()));
+        assert_eq!(
+            verify_at(&locked(&[]), &fields_env),
+            Err((13, ErrorKind::ExitWhileLocked))
+        );
+        assert_eq!(
+            verify_at(&locked(&[call(BpfFunc::KtimeGetNs)]), &fields_env),
+            Err((12, ErrorKind::CallWhileLocked))
+        );
+        assert_eq!(
+            verify_at(&locked(&[r1, call(BpfFunc::SpinLock)]), &fields_env),
+            Err((13, ErrorKind::NestedLock))
+        );
+        assert_eq!(
+            verify_at(&locked(&[unlock, unlock].concat()), &fields_env),
+            Err((15, ErrorKind::UnlockNotHeld))
+        );
+
+        // constant strings are read from frozen maps programs cannot write
+        let rodata = [MapDef {
+            key_size: 4,
+            value_size: 8,
+            max_entries: 1,
+            map_flags: BPF_F_RDONLY_PROG,
+            ..MapDef::default()
+        }];
+        let frozen = [Some(&b"abc\0efgh"[..])];
+        let rodata_env = Env {
+            maps: &rodata,
+            frozen: &frozen,
+            ..Env::default()
+        };
+        let strncmp = |off| {
+            [
+                inst(LD_IMM_DW, 1, map_fd, 0, 0),
+                0,
+                store,
+                inst(ALU64_X_MOV, 2, 10, 0, 0),
+                inst(ALU64_K_ADD, 2, 0, 0, -8),
+                call(BpfFunc::MapLookupElem),
+                inst(JMP_K_JNE, 0, 0, 2, 0),
+                mov,
+                exit,
+                inst(ALU64_X_MOV, 3, 0, 0, 0),
+                inst(ALU64_K_ADD, 3, 0, 0, off),
+                inst(ALU64_X_MOV, 1, 10, 0, 0),
+                inst(ALU64_K_ADD, 1, 0, 0, -8),
+                inst(ALU64_K_MOV, 2, 0, 0, 8),
+                call(BpfFunc::Strncmp),
+                mov,
+                exit,
+            ]
+        };
+        assert_eq!(verify_at(&strncmp(0), &rodata_env), Ok(()));
+        assert_eq!(
+            verify_at(&strncmp(4), &rodata_env),
+            Err((14, ErrorKind::UnterminatedStr { off: 4 }))
+        );
+        let not_const = Err((
+            14,
+            ErrorKind::InvalidArg {
+                reg: 3,
+                ty: RegType::PtrToMapValue { fd: 0 },
+                expected: ArgType::PtrToConstStr,
+            },
+        ));
+        let unfrozen = Env {
+            frozen: &[],
+            ..rodata_env
+        };
+        assert_eq!(verify_at(&strncmp(0), &unfrozen), not_const);
+        let writable = [MapDef {
+            map_flags: 0,
+            ..rodata[0]
+        }];
+        let writable_env = Env {
+            maps: &writable,
+            ..rodata_env
+        };
+        assert_eq!(verify_at(&strncmp(0), &writable_env), not_const);
+        let snprintf = |out, off, size| {
+            [
+                inst(LD_IMM_DW, 1, map_fd, 0, 0),
+                0,
+                store,
+                inst(ST_MEM_DW, 10, 0, -16, 0),
+                inst(ALU64_X_MOV, 2, 10, 0, 0),
+                inst(ALU64_K_ADD, 2, 0, 0, -8),
+                call(BpfFunc::MapLookupElem),
+                inst(JMP_K_JNE, 0, 0, 2, 0),
+                mov,
+                exit,
+                inst(ALU64_X_MOV, 3, 0, 0, 0),
+                out,
+                inst(ALU64_K_ADD, 1, 0, 0, off),
+                inst(ALU64_K_MOV, 2, 0, 0, size),
+                inst(ALU64_K_MOV, 4, 0, 0, 0),
+                inst(ALU64_K_MOV, 5, 0, 0, 0),
+                call(BpfFunc::Snprintf),
+                // what the helper wrote over is no longer known
+                inst(LDX_MEM_DW, 1, 10, -16, 0),
+                inst(ALU64_X_MOV, 2, 10, 0, 0),
+                inst(ALU64_X_ADD, 2, 1, 0, 0),
+                mov,
+                exit,
+            ]
+        };
+        let null = inst(ALU64_K_MOV, 1, 0, 0, 0);
+        assert_eq!(verify_at(&snprintf(null, 0, 0), &rodata_env), Ok(()));
+        assert_eq!(
+            verify_at(&snprintf(null, 0, 8), &rodata_env),
+            Err((16, ErrorKind::InvalidMemSize { reg: 2 }))
+        );
+        let stack = inst(ALU64_X_MOV, 1, 10, 0, 0);
+        assert_eq!(
+            verify_at(&snprintf(stack, -16, 8), &rodata_env),
+            Err((19, ErrorKind::PointerArithmetic { reg: 2 }))
+        );
+        let value = inst(ALU64_X_MOV, 1, 0, 0, 0);
+        assert_eq!(
+            verify_at(&snprintf(value, 0, 8), &rodata_env),
+            Err((16, ErrorKind::ReadOnlyMap { fd: 0 }))
+        );
+        let write = inst(ST_MEM_W, 0, 0, 0, 1);
+        assert_eq!(
+            verify_at(&lookup(store, check, write), &rodata_env),
+            Err((7, ErrorKind::ReadOnlyMap { fd: 0 }))
+        );
+        let prog = [
+            inst(LD_IMM_DW, 1, map_fd, 0, 0),
+            0,
+            call(BpfFunc::MapDeleteElem),
+            exit,
+        ];
+        assert_eq!(
+            verify_at(&prog, &rodata_env),
+            Err((2, ErrorKind::ReadOnlyMap { fd: 0 }))
+        );
+
+        // the check settles copies spilled before it too
+        let prog = [
+            inst(LD_IMM_DW, 1, map_fd, 0, 0),
+            0,
+            store,
+            inst(ALU64_X_MOV, 2, 10, 0, 0),
+            inst(ALU64_K_ADD, 2, 0, 0, -8),
+            call(BpfFunc::MapLookupElem),
+            inst(STX_MEM_DW, 10, 0, -16, 0),
+            inst(JMP_K_JEQ, 0, 0, 3, 0),
+            inst(LDX_MEM_DW, 1, 10, -16, 0),
+            inst(LDX_MEM_W, 0, 1, 0, 0),
+            exit,
+            // NULL, which may be returned
+            inst(LDX_MEM_DW, 0, 10, -16, 0),
+            exit,
+        ];
+        assert_eq!(verify_at(&prog, &env), Ok(()));
+        let prog = [
+            inst(ALU64_K_MOV, 1, 0, 0, 0),
+            call(BpfFunc::MapDeleteElem),
+            exit,
+        ];
+        assert_eq!(
+            verify_at(&prog, &env),
+            Err((
+                1,
+                ErrorKind::InvalidArg {
+                    reg: 1,
+                    ty: RegType::Scalar,
+                    expected: ArgType::ConstMapPtr
+                }
+            ))
+        );
+        let prog = [inst(JMP_K_CALL, 0, 0, 0, 1000), exit];
+        assert_eq!(
+            verify_at(&prog, &env),
+            Err((0, ErrorKind::UnknownHelper { id: 1000 }))
+        );
+        let mut helpers = HelperRegistry::new();
+        helpers.register_id(0x1000, |_, _, _, _, _, _| 0);
+        let ret = RetType::PtrToMapValueOrNull;
+        helpers.declare(0x1000, HelperProto::new(ret, &[ArgType::Anything]));
+        let prog = [inst(JMP_K_CALL, 0, 0, 0, 0x1000), mov, exit];
+        let helper_env = Env {
+            helpers: Some(&helpers),
+            ..env
+        };
+        assert_eq!(
+            verify_at(&prog, &helper_env),
+            Err((0, ErrorKind::InvalidProto { id: 0x1000 }))
+        );
+        let kfunc = BPF_PSEUDO_KFUNC_CALL as u8;
+        let prog = [inst(JMP_K_CALL, 0, kfunc, 0, 7), exit];
+        assert_eq!(
+            verify_at(&prog, &env),
+            Err((0, ErrorKind::UnknownKfunc { id: 7 }))
+        );
+
+        // a helper fills stack it is given, up to a bounded size
+        let read = |size| {
+            [
+                inst(ALU64_X_MOV, 1, 10, 0, 0),
+                inst(ALU64_K_ADD, 1, 0, 0, -8),
+                inst(ALU64_K_MOV, 2, 0, 0, size),
+                inst(ALU64_K_MOV, 3, 0, 0, 0),
+                call(BpfFunc::ProbeRead),
+                inst(LDX_MEM_DW, 0, 10, -8, 0),
+                exit,
+            ]
+        };
+        assert_eq!(verify_at(&read(8), &env), Ok(()));
+        assert_eq!(
+            verify_at(&read(0), &env),
+            Err((5, ErrorKind::UninitStack { off: -8, size: 8 }))
+        );
+        assert_eq!(
+            verify_at(&read(16), &env),
+            Err((4, ErrorKind::InvalidStackAccess { off: -8, size: 16 }))
+        );
+        assert_eq!(
+            verify_at(&read(-1), &env),
+            Err((4, ErrorKind::InvalidMemSize { reg: 2 }))
+        );
+
+        // callbacks are verified with the arguments the helper passes
+        let func = BPF_PSEUDO_FUNC as u8;
+        let for_each = |body| {
+            [
+                inst(LD_IMM_DW, 1, map_fd, 0, 0),
+                0,
+                inst(LD_IMM_DW, 2, func, 0, 6),
+                0,
+                inst(ALU64_K_MOV, 3, 0, 0, 0),
+                inst(ALU64_K_MOV, 4, 0, 0, 0),
+                call(BpfFunc::ForEachMapElem),
+                mov,
+                exit,
+                body,
+                mov,
+                exit,
+            ]
+        };
+        let body = inst(LDX_MEM_W, 5, 3, 0, 0);
+        assert_eq!(verify_at(&for_each(body), &env), Ok(()));
+        let body = inst(LDX_MEM_W, 5, 4, 0, 0);
+        assert_eq!(
+            verify_at(&for_each(body), &env),
+            Err((9, ErrorKind::InvalidMemAccess { reg: 4 }))
+        );
+        let body = inst(STX_MEM_W, 2, 1, 0, 0);
+        assert_eq!(
+            verify_at(&for_each(body), &env),
+            Err((9, ErrorKind::InvalidMemAccess { reg: 2 }))
+        );
+        // u64 sum = 0; long n = bpf_loop(10, add, &sum, 0); return sum * 1000 + n;
+        // where add stops after index 4
+        let prog = [
+            inst(ST_MEM_DW, 10, 0, -8, 0),
+            inst(ALU64_K_MOV, 1, 0, 0, 10),
+            inst(LD_IMM_DW, 2, func, 0, 10),
+            0,
+            inst(ALU64_X_MOV, 3, 10, 0, 0),
+            inst(ALU64_K_ADD, 3, 0, 0, -8),
+            inst(ALU64_K_MOV, 4, 0, 0, 0),
+            call(BpfFunc::Loop),
+            inst(ALU64_X_MOV, 6, 0, 0, 0),
+            inst(LDX_MEM_DW, 0, 10, -8, 0),
+            inst(ALU64_K_MUL, 0, 0, 0, 1000),
+            inst(ALU64_X_ADD, 0, 6, 0, 0),
+            exit,
+            inst(LDX_MEM_DW, 3, 2, 0, 0),
+            inst(ALU64_X_ADD, 3, 1, 0, 0),
+            inst(STX_MEM_DW, 2, 3, 0, 0),
+            mov,
+            inst(JMP_K_JNE, 1, 0, 1, 4),
+            inst(ALU64_K_MOV, 0, 0, 0, 1),
+            exit,
+        ];
+        assert_eq!(verify_at(&prog, &env), Ok(()));
+        // the context points into the caller's frame, where the callback
+        // may only load and store data, and which the caller no longer
+        // knows the contents of afterwards
+        let bpf_loop = |init, after, body: &[u64]| {
+            let mut prog = vec![
+                init,
+                inst(ALU64_K_MOV, 1, 0, 0, 10),
+                inst(LD_IMM_DW, 2, func, 0, 9),
+                0,
+                inst(ALU64_X_MOV, 3, 10, 0, 0),
+                inst(ALU64_K_ADD, 3, 0, 0, -8),
+                inst(ALU64_K_MOV, 4, 0, 0, 0),
+                call(BpfFunc::Loop),
+                inst(LDX_MEM_DW, 1, 10, -8, 0),
+                after,
+                mov,
+                exit,
+            ];
+            prog.extend_from_slice(body);
+            prog.extend_from_slice(&[mov, exit]);
+            prog
+        };
+        let spill = |body: &[u64]| {
+            let spill = inst(STX_MEM_DW, 10, 10, -8, 0);
+            bpf_loop(spill, inst(ST_MEM_DW, 1, 0, -16, 0), body)
+        };
+        assert_eq!(verify_at(&spill(&[]), &env), Ok(()));
+        assert_eq!(
+            verify_at(&spill(&[inst(ST_MEM_DW, 2, 0, 0, 0)]), &env),
+            Err((9, ErrorKind::InvalidMemAccess { reg: 1 }))
+        );
+        assert_eq!(
+            verify_at(&spill(&[inst(LDX_MEM_DW, 3, 2, 0, 0)]), &env),
+            Err((12, ErrorKind::InvalidStackAccess { off: -8, size: 8 }))
+        );
+        assert_eq!(
+            verify_at(&spill(&[inst(STX_MEM_DW, 2, 10, 0, 0)]), &env),
+            Err((12, ErrorKind::InvalidStackAccess { off: -8, size: 8 }))
+        );
+        // a later run sees what an earlier one stored
+        let body = [
+            inst(LDX_MEM_DW, 3, 2, 0, 0),
+            inst(ALU64_X_MOV, 4, 10, 0, 0),
+            inst(ALU64_X_ADD, 4, 3, 0, 0),
+            inst(ST_MEM_DW, 4, 0, -8, 0),
+        ];
+        let zero = inst(ST_MEM_DW, 10, 0, -8, 0);
+        let prog = bpf_loop(zero, mov, &body);
+        assert_eq!(verify_at(&prog, &env), Ok(()));
+        let prog = bpf_loop(
+            zero,
+            mov,
+            &[&body[..], &[inst(ST_MEM_DW, 2, 0, 0, 1)]].concat(),
+        );
+        assert_eq!(
+            verify_at(&prog, &env),
+            Err((14, ErrorKind::PointerArithmetic { reg: 4 }))
+        );
+
+        // a callback may not pass itself on
+        let recurse = |target| {
+            [
+                inst(ALU64_K_MOV, 1, 0, 0, 1),
+                inst(LD_IMM_DW, 2, func, 0, target),
+                0,
+                inst(ALU64_K_MOV, 3, 0, 0, 0),
+                inst(ALU64_K_MOV, 4, 0, 0, 0),
+                call(BpfFunc::Loop),
+                exit,
+            ]
+        };
+        let prog = [recurse(5), recurse(-2)].concat();
+        assert_eq!(
+            verify_at(&prog, &env),
+            Err((12, ErrorKind::RecursiveCall { target: 7 }))
+        );
+    }
+
+    #[test]
+    fn log() {
+        let inst = Insn::encode;
+        let exit = inst(JMP_K_EXIT, 0, 0, 0, 0);
+        let env = Env {
+            prog_type: BPF_PROG_TYPE_KPROBE,
+            ..Env::default()
+        };
+        let prog = [
+            inst(LDX_MEM_W, 2, 1, 0, 0),
+            inst(ALU64_K_MOV, 0, 0, 0, 0),
+            inst(JMP_K_JGT, 2, 0, 1, 5),
+            exit,
+            inst(ALU64_X_MOV, 0, 4, 0, 0),
+            exit,
+        ];
+        let error = Error {
+            pc: 4,
+            kind: ErrorKind::UninitRegister { reg: 4 },
+        };
+
+        // only the path that failed is left
+        let mut log = Log::new(LOG_LEVEL1);
+        assert_eq!(verify_with_log(&prog, &env, &mut log), Err(error));
+        assert_eq!(log.processed, 5);
+        assert_eq!(log.error(), Some(&error));
+        let pcs: Vec<_> = log
+            .records
+            .iter()
+            .filter_map(|record| match record {
+                Record::Insn { pc, state, .. } => Some((*pc, state.is_some())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(pcs, [(0, false), (1, false), (2, false), (4, false)]);
+        assert!(matches!(
+            log.records.last(),
+            Some(Record::Error { backtrace, .. }) if backtrace == &[0, 1, 2, 4]
+        ));
+        let text = log.to_string();
+        assert!(text.starts_with("0: (61) r2 = *(u32 *)(r1 +0)\n1: (b7) r0 = 0\n"));
+        assert!(text.contains(
+            "\n2: (25) if r2 > 0x5 goto pc+1\nfrom 2 to 4: R0=0 R1=ctx() R2=scalar(smin=6,smax=4294967295,umin=6,"
+        ));
+        assert!(text.ends_with("\ninsn 4: R4 !read_ok\npath: 0 1 2 4\nprocessed 5 insns\n"));
+
+        // every path, with the state before each instruction
+        let mut log = Log::new(LOG_LEVEL2);
+        assert_eq!(verify_with_log(&prog, &env, &mut log), Err(error));
+        let states = log.records.iter().filter_map(|record| match record {
+            Record::Insn { pc, state, .. } => Some((*pc, state.as_ref().unwrap())),
+            _ => None,
+        });
+        assert_eq!(
+            states.map(|(pc, _)| pc).collect::<Vec<_>>(),
+            [0, 1, 2, 3, 4]
+        );
+        assert!(log.to_string().contains("\n3: R0=0 R1=ctx() R2=scalar("));
+
+        // nothing is recorded without a level
+        let mut log = Log::default();
+        assert_eq!(verify_with_log(&prog, &env, &mut log), Err(error));
+        assert!(log.records.is_empty());
+        assert_eq!(log.processed, 5);
+    }
+}
@@ -0,0 +1,746 @@
+//! Value bounds of scalars and of the variable part of pointer offsets,
+//! after the `*_min_max_vals` functions in kernel/bpf/verifier.c.
+//!
+//! ALU32 operations and JMP32 comparisons are computed on the low 32 bits
+//! zero- or sign-extended to 64, whichever the operation is defined on, or
+//! on both and combined when the low 32 bits of the result do not depend on
+//! the choice.
+
+use super::tnum::Tnum;
+use crate::consts::*;
+use core::cmp::{max, min};
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bounds {
+    /// Known bits.
+    pub var_off: Tnum,
+    pub smin: i64,
+    pub smax: i64,
+    pub umin: u64,
+    pub umax: u64,
+    /// Bounds of the low 32 bits.
+    pub s32_min: i32,
+    pub s32_max: i32,
+    pub u32_min: u32,
+    pub u32_max: u32,
+}
+
+impl Bounds {
+    pub const UNKNOWN: Bounds = Bounds {
+        var_off: Tnum::UNKNOWN,
+        smin: i64::MIN,
+        smax: i64::MAX,
+        umin: 0,
+        umax: u64::MAX,
+        s32_min: i32::MIN,
+        s32_max: i32::MAX,
+        u32_min: 0,
+        u32_max: u32::MAX,
+    };
+
+    pub const fn constant(value: u64) -> Self {
+        Bounds {
+            var_off: Tnum::constant(value),
+            smin: value as i64,
+            smax: value as i64,
+            umin: value,
+            umax: value,
+            s32_min: value as i32,
+            s32_max: value as i32,
+            u32_min: value as u32,
+            u32_max: value as u32,
+        }
+    }
+
+    /// Any zero-extended value of `size` bytes.
+    pub fn zero_extended(size: u32) -> Self {
+        let mut bounds = Bounds {
+            var_off: Tnum::UNKNOWN.cast(size),
+            ..Bounds::UNKNOWN
+        };
+        bounds.sync();
+        bounds
+    }
+
+    pub fn value(&self) -> Option<u64> {
+        self.var_off.is_const().then_some(self.var_off.value)
+    }
+
+    /// Whether any value satisfies the bounds. Those narrowed on a branch
+    /// that cannot be taken may not.
+    pub fn is_sane(&self) -> bool {
+        self.smin <= self.smax
+            && self.umin <= self.umax
+            && self.s32_min <= self.s32_max
+            && self.u32_min <= self.u32_max
+    }
+
+    /// Whether every value `other` allows is one `self` allows, as
+    /// `range_within` and `tnum_in` together decide.
+    pub fn contains(&self, other: &Bounds) -> bool {
+        self.smin <= other.smin
+            && other.smax <= self.smax
+            && self.umin <= other.umin
+            && other.umax <= self.umax
+            && self.s32_min <= other.s32_min
+            && other.s32_max <= self.s32_max
+            && self.u32_min <= other.u32_min
+            && other.u32_max <= self.u32_max
+            && self.var_off.contains(other.var_off)
+    }
+
+    /// Tightens each bound with what the others imply, as `reg_bounds_sync`
+    /// does.
+    pub fn sync(&mut self) {
+        self.update_bounds();
+        self.deduce_bounds();
+        self.bound_offset();
+        self.update_bounds();
+    }
+
+    /// Bounds from the known bits.
+    fn update_bounds(&mut self) {
+        let var32 = self.var_off.subreg();
+        let (value, mask) = (var32.value as u32, var32.mask as u32);
+        self.s32_min = max(self.s32_min, (value | (mask & 0x8000_0000)) as i32);
+        self.s32_max = min(self.s32_max, (value | (mask & 0x7fff_ffff)) as i32);
+        self.u32_min = max(self.u32_min, value);
+        self.u32_max = min(self.u32_max, value | mask);
+
+        let (value, mask) = (self.var_off.value, self.var_off.mask);
+        let sign = 1 << 63;
+        self.smin = max(self.smin, (value | (mask & sign)) as i64);
+        self.smax = min(self.smax, (value | (mask & !sign)) as i64);
+        self.umin = max(self.umin, value);
+        self.umax = min(self.umax, value | mask);
+    }
+
+    /// Signed bounds from unsigned ones and back, and 32-bit bounds from
+    /// 64-bit ones and back.
+    fn deduce_bounds(&mut self) {
+        // the low 32 bits, when the high ones are the same for all values
+        if self.umin >> 32 == self.umax >> 32 {
+            self.u32_min = max(self.u32_min, self.umin as u32);
+            self.u32_max = min(self.u32_max, self.umax as u32);
+            if self.umin as i32 <= self.umax as i32 {
+                self.s32_min = max(self.s32_min, self.umin as i32);
+                self.s32_max = min(self.s32_max, self.umax as i32);
+            }
+        }
+        if self.smin >> 32 == self.smax >> 32 {
+            self.u32_min = max(self.u32_min, self.smin as u32);
+            self.u32_max = min(self.u32_max, self.smax as u32);
+            if self.smin as i32 <= self.smax as i32 {
+                self.s32_min = max(self.s32_min, self.smin as i32);
+                self.s32_max = min(self.s32_max, self.smax as i32);
+            }
+        }
+        if self.smin >= i32::MIN as i64 && self.smax <= i32::MAX as i64 {
+            self.s32_min = max(self.s32_min, self.smin as i32);
+            self.s32_max = min(self.s32_max, self.smax as i32);
+        }
+
+        // where the sign is known, signed and unsigned order agree
+        if self.s32_min >= 0 || self.s32_max < 0 {
+            self.u32_min = max(self.u32_min, self.s32_min as u32);
+            self.u32_max = min(self.u32_max, self.s32_max as u32);
+            self.s32_min = self.u32_min as i32;
+            self.s32_max = self.u32_max as i32;
+        } else if self.u32_max as i32 >= 0 {
+            self.s32_min = self.u32_min as i32;
+            self.u32_max = min(self.u32_max, self.s32_max as u32);
+            self.s32_max = self.u32_max as i32;
+        } else if (self.u32_min as i32) < 0 {
+            self.u32_min = max(self.u32_min, self.s32_min as u32);
+            self.s32_min = self.u32_min as i32;
+            self.s32_max = self.u32_max as i32;
+        }
+
+        // the full value from the low 32 bits, when the high ones are the
+        // same for all values
+        if self.umin >> 32 == self.umax >> 32 {
+            let high = self.umin & !0xffff_ffff;
+            self.umin = max(self.umin, high | self.u32_min as u64);
+            self.umax = min(self.umax, high | self.u32_max as u64);
+        }
+        if self.smin >> 32 == self.smax >> 32 {
+            let high = self.smin & !0xffff_ffff;
+            self.smin = max(self.smin, high | self.u32_min as i64);
+            self.smax = min(self.smax, high | self.u32_max as i64);
+        }
+
+        if self.smin >= 0 || self.smax < 0 {
+            self.umin = max(self.umin, self.smin as u64);
+            self.umax = min(self.umax, self.smax as u64);
+            self.smin = self.umin as i64;
+            self.smax = self.umax as i64;
+        } else if self.umax as i64 >= 0 {
+            self.smin = self.umin as i64;
+            self.umax = min(self.umax, self.smax as u64);
+            self.smax = self.umax as i64;
+        } else if (self.umin as i64) < 0 {
+            self.umin = max(self.umin, self.smin as u64);
+            self.smin = self.umin as i64;
+            self.smax = self.umax as i64;
+        }
+    }
+
+    /// Known bits from the unsigned bounds.
+    fn bound_offset(&mut self) {
+        let var64 = self.var_off.intersect(Tnum::range(self.umin, self.umax));
+        let range32 = Tnum::range(self.u32_min as u64, self.u32_max as u64);
+        let var32 = var64.subreg().intersect(range32);
+        self.var_off = var64.clear_subreg() | var32;
+    }
+
+    /// Combines two bounds known to hold for the same value.
+    pub fn meet(&self, other: &Bounds) -> Self {
+        let mut bounds = Bounds {
+            var_off: self.var_off.intersect(other.var_off),
+            smin: max(self.smin, other.smin),
+            smax: min(self.smax, other.smax),
+            umin: max(self.umin, other.umin),
+            umax: min(self.umax, other.umax),
+            s32_min: max(self.s32_min, other.s32_min),
+            s32_max: min(self.s32_max, other.s32_max),
+            u32_min: max(self.u32_min, other.u32_min),
+            u32_max: min(self.u32_max, other.u32_max),
+        };
+        bounds.sync();
+        bounds
+    }
+
+    /// Narrows the low 32 bits to those of `view`.
+    fn meet32(&mut self, view: &Bounds) {
+        self.s32_min = max(self.s32_min, view.s32_min);
+        self.s32_max = min(self.s32_max, view.s32_max);
+        self.u32_min = max(self.u32_min, view.u32_min);
+        self.u32_max = min(self.u32_max, view.u32_max);
+        let var32 = self.var_off.subreg().intersect(view.var_off.subreg());
+        self.var_off = self.var_off.clear_subreg() | var32;
+        self.sync();
+    }
+
+    /// The low 32 bits, zero-extended.
+    fn zext32(&self) -> Self {
+        let mut view = Bounds {
+            var_off: self.var_off.subreg(),
+            smin: self.u32_min as i64,
+            smax: self.u32_max as i64,
+            umin: self.u32_min as u64,
+            umax: self.u32_max as u64,
+            ..*self
+        };
+        view.sync();
+        view
+    }
+
+    /// The low 32 bits, sign-extended.
+    fn sext32(&self) -> Self {
+        let mut view = Bounds {
+            var_off: self.var_off.sext32(),
+            smin: self.s32_min as i64,
+            smax: self.s32_max as i64,
+            umin: 0,
+            umax: u64::MAX,
+            ..*self
+        };
+        view.sync();
+        view
+    }
+
+    /// The low 32 bits, zero-extended as the results of ALU32 operations
+    /// are.
+    fn trunc32(&self) -> Self {
+        let mut bounds = Bounds {
+            var_off: self.var_off.subreg(),
+            s32_min: self.s32_min,
+            s32_max: self.s32_max,
+            u32_min: self.u32_min,
+            u32_max: self.u32_max,
+            ..Bounds::UNKNOWN
+        };
+        bounds.sync();
+        bounds
+    }
+
+    /// Drops `value` from the ends of the ranges.
+    fn exclude(&mut self, value: u64) {
+        if self.umin == value {
+            self.umin = value.saturating_add(1);
+        }
+        if self.umax == value {
+            self.umax = value.saturating_sub(1);
+        }
+        if self.smin == value as i64 {
+            self.smin = self.smin.saturating_add(1);
+        }
+        if self.smax == value as i64 {
+            self.smax = self.smax.saturating_sub(1);
+        }
+    }
+}
+
+/// Computes ALU opcode `code` on constants, or returns `None` for a shift
+/// by `bits` or more.
+fn eval(code: u32, a: u64, b: u64, bits: u32) -> Option<u64> {
+    Some(match code {
+        BPF_ADD => a.wrapping_add(b),
+        BPF_SUB => a.wrapping_sub(b),
+        BPF_MUL => a.wrapping_mul(b),
+        BPF_DIV => a.checked_div(b).unwrap_or(0),
+        BPF_MOD => a.checked_rem(b).unwrap_or(a),
+        BPF_OR => a | b,
+        BPF_AND => a & b,
+        BPF_XOR => a ^ b,
+        BPF_LSH if b < bits as u64 => a << b,
+        BPF_RSH if b < bits as u64 => a >> b,
+        BPF_ARSH if b < bits as u64 => (a as i64 >> b) as u64,
+        BPF_NEG => a.wrapping_neg(),
+        BPF_MOV => b,
+        _ => return None,
+    })
+}
+
+/// Bounds of the 64-bit result of `dst code src`, where shifts are by less
+/// than `bits`.
+fn compute(code: u32, dst: &Bounds, src: &Bounds, bits: u32) -> Bounds {
+    if let (Some(a), Some(b)) = (dst.value(), src.value()) {
+        if let Some(value) = eval(code, a, b, bits) {
+            return Bounds::constant(value);
+        }
+    }
+    let shift = src
+        .value()
+        .filter(|&shift| shift < bits as u64)
+        .map(|shift| shift as u32);
+    let mut r = Bounds::UNKNOWN;
+    match code {
+        BPF_MOV => return *src,
+        BPF_NEG => return compute(BPF_SUB, &Bounds::constant(0), dst, bits),
+        BPF_ADD => {
+            r.var_off = dst.var_off + src.var_off;
+            if let (Some(lo), Some(hi)) = (
+                dst.smin.checked_add(src.smin),
+                dst.smax.checked_add(src.smax),
+            ) {
+                r.smin = lo;
+                r.smax = hi;
+            }
+            if let (Some(lo), Some(hi)) = (
+                dst.umin.checked_add(src.umin),
+                dst.umax.checked_add(src.umax),
+            ) {
+                r.umin = lo;
+                r.umax = hi;
+            }
+        }
+        BPF_SUB => {
+            r.var_off = dst.var_off - src.var_off;
+            if let (Some(lo), Some(hi)) = (
+                dst.smin.checked_sub(src.smax),
+                dst.smax.checked_sub(src.smin),
+            ) {
+                r.smin = lo;
+                r.smax = hi;
+            }
+            if dst.umin >= src.umax {
+                r.umin = dst.umin - src.umax;
+                r.umax = dst.umax - src.umin;
+            }
+        }
+        BPF_MUL => {
+            r.var_off = dst.var_off * src.var_off;
+            if let Some(hi) = dst.umax.checked_mul(src.umax) {
+                r.umin = dst.umin * src.umin;
+                r.umax = hi;
+            }
+        }
+        BPF_AND => {
+            r.var_off = dst.var_off & src.var_off;
+            r.umax = min(dst.umax, src.umax);
+        }
+        BPF_OR => {
+            r.var_off = dst.var_off | src.var_off;
+            r.umin = max(dst.umin, src.umin);
+        }
+        BPF_XOR => r.var_off = dst.var_off ^ src.var_off,
+        BPF_LSH => {
+            if let Some(shift) = shift {
+                r.var_off = dst.var_off.lshift(shift);
+                if dst.umax.leading_zeros() >= shift {
+                    r.umin = dst.umin << shift;
+                    r.umax = dst.umax << shift;
+                }
+            }
+        }
+        BPF_RSH => {
+            if let Some(shift) = shift {
+                r.var_off = dst.var_off.rshift(shift);
+                r.umin = dst.umin >> shift;
+                r.umax = dst.umax >> shift;
+            }
+        }
+        BPF_ARSH => {
+            if let Some(shift) = shift {
+                r.var_off = dst.var_off.arshift(shift);
+                r.smin = dst.smin >> shift;
+                r.smax = dst.smax >> shift;
+            }
+        }
+        // DIV and MOD
+        _ => {}
+    }
+    r.sync();
+    r
+}
+
+/// Bounds of the result of ALU or ALU64 opcode `code`, other than END.
+pub fn alu(code: u32, alu64: bool, dst: &Bounds, src: &Bounds) -> Bounds {
+    if alu64 {
+        return compute(code, dst, src, 64);
+    }
+    let zext = || compute(code, &dst.zext32(), &src.zext32(), 32).trunc32();
+    let sext = || compute(code, &dst.sext32(), &src.sext32(), 32).trunc32();
+    match code {
+        BPF_RSH | BPF_DIV | BPF_MOD => zext(),
+        BPF_ARSH => sext(),
+        _ => zext().meet(&sext()),
+    }
+}
+
+/// Bounds after `BPF_END` converts the low `bits` of `dst` to or from big
+/// endian order, as the interpreter does on this host.
+pub fn end(dst: &Bounds, big_endian: bool, bits: u32) -> Bounds {
+    if let Some(value) = dst.value() {
+        return Bounds::constant(match (bits, big_endian) {
+            (16, false) => (value as u16).to_le() as u64,
+            (16, true) => (value as u16).to_be() as u64,
+            (32, false) => (value as u32).to_le() as u64,
+            (32, true) => (value as u32).to_be() as u64,
+            (_, false) => value.to_le(),
+            (_, true) => value.to_be(),
+        });
+    }
+    let swap = big_endian == cfg!(target_endian = "little");
+    match (swap, bits) {
+        (false, 64) => *dst,
+        (false, _) => {
+            let mut bounds = Bounds {
+                var_off: dst.var_off.cast(bits / 8),
+                ..Bounds::UNKNOWN
+            };
+            bounds.sync();
+            bounds
+        }
+        (true, _) => Bounds::zero_extended(bits / 8),
+    }
+}
+
+/// The opposite of conditional jump opcode `code`, other than JSET.
+fn negate(code: u32) -> u32 {
+    match code {
+        BPF_JEQ => BPF_JNE,
+        BPF_JNE => BPF_JEQ,
+        BPF_JGT => BPF_JLE,
+        BPF_JLE => BPF_JGT,
+        BPF_JGE => BPF_JLT,
+        BPF_JLT => BPF_JGE,
+        BPF_JSGT => BPF_JSLE,
+        BPF_JSLE => BPF_JSGT,
+        BPF_JSGE => BPF_JSLT,
+        BPF_JSLT => BPF_JSGE,
+        _ => code,
+    }
+}
+
+/// The comparison with the operands swapped, for the less-than opcodes.
+fn swap(code: u32) -> Option<u32> {
+    match code {
+        BPF_JLT => Some(BPF_JGT),
+        BPF_JLE => Some(BPF_JGE),
+        BPF_JSLT => Some(BPF_JSGT),
+        BPF_JSLE => Some(BPF_JSGE),
+        _ => None,
+    }
+}
+
+/// The operands as compared by `code`.
+fn views(code: u32, jmp32: bool, dst: &Bounds, src: &Bounds) -> (Bounds, Bounds) {
+    match (jmp32, code) {
+        (false, _) => (*dst, *src),
+        (true, BPF_JSGT | BPF_JSGE | BPF_JSLT | BPF_JSLE) => (dst.sext32(), src.sext32()),
+        (true, _) => (dst.zext32(), src.zext32()),
+    }
+}
+
+/// Returns whether the conditional jump `dst code src` is always taken,
+/// never taken, or `None` if that depends on the values, as
+/// `is_branch_taken` does.
+pub fn branch_taken(code: u32, jmp32: bool, dst: &Bounds, src: &Bounds) -> Option<bool> {
+    let (a, b) = views(code, jmp32, dst, src);
+    taken(code, &a, &b)
+}
+
+fn taken(code: u32, a: &Bounds, b: &Bounds) -> Option<bool> {
+    if let Some(code) = swap(code) {
+        return taken(code, b, a);
+    }
+    let decide = |always: bool, never: bool| match (always, never) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    };
+    match code {
+        BPF_JEQ => {
+            let (x, y) = (a.var_off, b.var_off);
+            let conflict = (x.value ^ y.value) & !(x.mask | y.mask) != 0;
+            decide(
+                a.value().is_some() && a.value() == b.value(),
+                conflict
+                    || a.umin > b.umax
+                    || a.umax < b.umin
+                    || a.smin > b.smax
+                    || a.smax < b.smin,
+            )
+        }
+        BPF_JNE => taken(BPF_JEQ, a, b).map(|taken| !taken),
+        BPF_JSET => {
+            let (x, y) = (a.var_off, b.var_off);
+            decide(
+                x.value & y.value != 0,
+                (x.value | x.mask) & (y.value | y.mask) == 0,
+            )
+        }
+        BPF_JGT => decide(a.umin > b.umax, a.umax <= b.umin),
+        BPF_JGE => decide(a.umin >= b.umax, a.umax < b.umin),
+        BPF_JSGT => decide(a.smin > b.smax, a.smax <= b.smin),
+        BPF_JSGE => decide(a.smin >= b.smax, a.smax < b.smin),
+        _ => None,
+    }
+}
+
+/// Narrows `dst` and `src` to the values for which the conditional jump
+/// `dst code src` is `taken` or not, as `reg_set_min_max` does.
+pub fn refine(code: u32, jmp32: bool, taken: bool, dst: &mut Bounds, src: &mut Bounds) {
+    let (mut a, mut b) = views(code, jmp32, dst, src);
+    narrow(code, taken, &mut a, &mut b);
+    if jmp32 {
+        dst.meet32(&a);
+        src.meet32(&b);
+    } else {
+        *dst = a;
+        *src = b;
+    }
+}
+
+fn narrow(code: u32, holds: bool, a: &mut Bounds, b: &mut Bounds) {
+    let code = match (code, holds) {
+        (BPF_JSET, _) | (_, true) => code,
+        _ => negate(code),
+    };
+    if let Some(code) = swap(code) {
+        return narrow(code, true, b, a);
+    }
+    match code {
+        BPF_JEQ => {
+            *a = a.meet(b);
+            *b = *a;
+        }
+        BPF_JNE => {
+            if let Some(value) = b.value() {
+                a.exclude(value);
+            }
+            if let Some(value) = a.value() {
+                b.exclude(value);
+            }
+        }
+        BPF_JSET => {
+            if let Some(value) = b.value() {
+                match holds {
+                    true if value.is_power_of_two() => {
+                        a.var_off = a.var_off | Tnum::constant(value)
+                    }
+                    true => {}
+                    false => a.var_off = a.var_off & Tnum::constant(!value),
+                }
+            }
+        }
+        BPF_JGT => {
+            a.umin = max(a.umin, b.umin.saturating_add(1));
+            b.umax = min(b.umax, a.umax.saturating_sub(1));
+        }
+        BPF_JGE => {
+            a.umin = max(a.umin, b.umin);
+            b.umax = min(b.umax, a.umax);
+        }
+        BPF_JSGT => {
+            a.smin = max(a.smin, b.smin.saturating_add(1));
+            b.smax = min(b.smax, a.smax.saturating_sub(1));
+        }
+        BPF_JSGE => {
+            a.smin = max(a.smin, b.smin);
+            b.smax = min(b.smax, a.smax);
+        }
+        _ => {}
+    }
+    a.sync();
+    b.sync();
+}
+
+/// Lists the bounds that say more than the type does, comma-separated.
+impl fmt::Display for Bounds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let any = Bounds::UNKNOWN;
+        let mut sep = "";
+        let mut field = |f: &mut fmt::Formatter, name, value: &dyn fmt::Display, known| {
+            if known {
+                write!(f, "{}{}={}", sep, name, value)?;
+                sep = ",";
+            }
+            Ok(())
+        };
+        field(f, "smin", &self.smin, self.smin != any.smin)?;
+        field(f, "smax", &self.smax, self.smax != any.smax)?;
+        field(f, "umin", &self.umin, self.umin != any.umin)?;
+        field(f, "umax", &self.umax, self.umax != any.umax)?;
+        field(f, "smin32", &self.s32_min, self.s32_min != any.s32_min)?;
+        field(f, "smax32", &self.s32_max, self.s32_max != any.s32_max)?;
+        field(f, "umin32", &self.u32_min, self.u32_min != any.u32_min)?;
+        field(f, "umax32", &self.u32_max, self.u32_max != any.u32_max)?;
+        let var_off = self.var_off;
+        let var_off = format_args!("({:#x}; {:#x})", var_off.value, var_off.mask);
+        field(f, "var_off", &var_off, self.var_off != any.var_off)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn range(min: u64, max: u64) -> Bounds {
+        let mut bounds = Bounds {
+            umin: min,
+            umax: max,
+            ..Bounds::UNKNOWN
+        };
+        bounds.sync();
+        bounds
+    }
+
+    #[test]
+    fn arithmetic() {
+        let c = Bounds::constant;
+        assert_eq!(alu(BPF_ADD, true, &c(3), &c(4)), c(7));
+        assert_eq!(alu(BPF_DIV, true, &c(3), &c(0)), c(0));
+        assert_eq!(alu(BPF_MOD, true, &c(3), &c(0)), c(3));
+        assert_eq!(alu(BPF_ADD, false, &c(u32::MAX as u64), &c(1)), c(0));
+        assert_eq!(
+            alu(BPF_MOV, false, &c(u64::MAX), &c(-1i64 as u64)),
+            c(u32::MAX as u64)
+        );
+        assert_eq!(
+            alu(BPF_ARSH, false, &c(0x8000_0000), &c(31)),
+            c(u32::MAX as u64)
+        );
+
+        let byte = Bounds::zero_extended(1);
+        assert_eq!(
+            (byte.umin, byte.umax, byte.smin, byte.smax),
+            (0, 255, 0, 255)
+        );
+        assert!(Bounds::UNKNOWN.contains(&byte));
+        assert!(byte.contains(&c(255)));
+        assert!(!byte.contains(&c(256)));
+        assert!(!byte.contains(&Bounds::UNKNOWN));
+        let sum = alu(BPF_ADD, true, &byte, &range(10, 20));
+        assert_eq!((sum.umin, sum.umax), (10, 275));
+        let diff = alu(BPF_SUB, true, &byte, &c(1));
+        assert_eq!((diff.smin, diff.smax), (-1, 254));
+        assert_eq!((diff.umin, diff.umax), (0, u64::MAX));
+        let masked = alu(BPF_AND, true, &Bounds::UNKNOWN, &c(0xf0));
+        assert_eq!((masked.umin, masked.umax), (0, 0xf0));
+        assert_eq!(
+            masked.var_off,
+            Tnum {
+                value: 0,
+                mask: 0xf0
+            }
+        );
+        let shifted = alu(BPF_LSH, true, &byte, &c(4));
+        assert_eq!((shifted.umin, shifted.umax), (0, 0xff0));
+        let shifted = alu(BPF_LSH, true, &byte, &c(64));
+        assert_eq!(shifted, Bounds::UNKNOWN);
+        let neg = alu(BPF_NEG, true, &byte, &c(0));
+        assert_eq!((neg.smin, neg.smax), (-255, 0));
+
+        // 32-bit results are zero-extended, and keep their signed bounds
+        let w = alu(BPF_SUB, false, &byte, &c(1));
+        assert_eq!((w.s32_min, w.s32_max), (-1, 254));
+        assert_eq!((w.umin, w.umax), (0, u32::MAX as u64));
+        let w = alu(BPF_MUL, false, &Bounds::UNKNOWN, &c(1 << 31));
+        assert_eq!(
+            w.var_off,
+            Tnum {
+                value: 0,
+                mask: 1 << 31
+            }
+        );
+    }
+
+    #[test]
+    fn branches() {
+        let c = Bounds::constant;
+        let byte = Bounds::zero_extended(1);
+        assert_eq!(branch_taken(BPF_JGT, false, &byte, &c(255)), Some(false));
+        assert_eq!(branch_taken(BPF_JLE, false, &byte, &c(255)), Some(true));
+        assert_eq!(branch_taken(BPF_JGT, false, &byte, &c(100)), None);
+        assert_eq!(branch_taken(BPF_JEQ, false, &byte, &c(256)), Some(false));
+        assert_eq!(branch_taken(BPF_JSET, false, &byte, &c(256)), Some(false));
+        assert_eq!(branch_taken(BPF_JSLT, false, &byte, &c(0)), Some(false));
+        let w = c(0x1_0000_0005);
+        assert_eq!(branch_taken(BPF_JEQ, true, &w, &c(5)), Some(true));
+        assert_eq!(branch_taken(BPF_JEQ, false, &w, &c(5)), Some(false));
+        assert_eq!(
+            branch_taken(BPF_JSLT, true, &c(u32::MAX as u64), &c(0)),
+            Some(true)
+        );
+
+        let (mut dst, mut src) = (byte, c(100));
+        refine(BPF_JGT, false, true, &mut dst, &mut src);
+        assert_eq!((dst.umin, dst.umax), (101, 255));
+        let (mut dst, mut src) = (byte, c(100));
+        refine(BPF_JGT, false, false, &mut dst, &mut src);
+        assert_eq!((dst.umin, dst.umax), (0, 100));
+        let (mut dst, mut src) = (Bounds::UNKNOWN, byte);
+        refine(BPF_JLT, false, true, &mut dst, &mut src);
+        assert_eq!((dst.umin, dst.umax, src.umin), (0, 254, 1));
+        let (mut dst, mut src) = (Bounds::UNKNOWN, c(7));
+        refine(BPF_JEQ, false, true, &mut dst, &mut src);
+        assert_eq!(dst, c(7));
+        let (mut dst, mut src) = (byte, c(0));
+        refine(BPF_JNE, false, true, &mut dst, &mut src);
+        assert_eq!((dst.umin, dst.umax), (1, 255));
+        let (mut dst, mut src) = (Bounds::UNKNOWN, c(0));
+        refine(BPF_JSGE, false, true, &mut dst, &mut src);
+        assert_eq!((dst.smin, dst.umax), (0, i64::MAX as u64));
+        let (mut dst, mut src) = (byte, c(0x80));
+        refine(BPF_JSET, false, false, &mut dst, &mut src);
+        assert_eq!(dst.umax, 0x7f);
+
+        // a 32-bit comparison bounds the low half only
+        let (mut dst, mut src) = (Bounds::UNKNOWN, c(10));
+        refine(BPF_JLT, true, true, &mut dst, &mut src);
+        assert_eq!((dst.u32_min, dst.u32_max), (0, 9));
+        let (mut dst, mut src) = (Bounds::zero_extended(4), c(10));
+        refine(BPF_JLT, true, true, &mut dst, &mut src);
+        assert_eq!((dst.umin, dst.umax), (0, 9));
+        let (mut dst, mut src) = (Bounds::UNKNOWN, c(-3i64 as u64));
+        refine(BPF_JSGT, true, false, &mut dst, &mut src);
+        assert_eq!((dst.s32_min, dst.s32_max), (i32::MIN, -3));
+
+        assert!(range(5, 4).umin > range(5, 4).umax);
+        assert!(!range(5, 4).is_sane());
+    }
+}
@@ -0,0 +1,319 @@
+//! Calls to helpers and kfuncs, checked against their prototypes like
+//! `check_helper_call` and `check_kfunc_call` in the kernel.
+
+use super::bounds::Bounds;
+use super::state::{RegState, RegType, State};
+use super::{
+    check_map_access, check_map_write, check_packet_access, read, stack, ErrorKind, Insn, Verifier,
+    MAX_OFF,
+};
+use crate::consts::*;
+use crate::helpers::proto::{self, ArgType, RetType};
+use crate::helpers::BpfFunc;
+use crate::kfunc::KfuncType;
+
+impl Verifier<'_> {
+    /// Checks the arguments of a call in r1-r5, which it clobbers, and sets
+    /// r0 to what it returns. A callback passed to the helper is queued to
+    /// be verified with the arguments the helper calls it with.
+    pub(super) fn check_call(&mut self, insn: &Insn, state: &mut State) -> Result<(), ErrorKind> {
+        let id = insn.imm as u32;
+        if state.lock.is_some() {
+            let func = match insn.src as u32 {
+                BPF_PSEUDO_KFUNC_CALL => None,
+                _ => BpfFunc::from_id(id),
+            };
+            match func {
+                Some(BpfFunc::SpinUnlock) => {}
+                Some(BpfFunc::SpinLock) => return Err(ErrorKind::NestedLock),
+                _ => return Err(ErrorKind::CallWhileLocked),
+            }
+        }
+        state.regs[0] = match insn.src as u32 {
+            BPF_PSEUDO_KFUNC_CALL => self.check_kfunc(id, state)?,
+            _ => self.check_helper(id, state)?,
+        };
+        state.regs[1..6].fill(RegState::NOT_INIT);
+        Ok(())
+    }
+
+    fn check_kfunc(&self, id: u32, state: &State) -> Result<RegState, ErrorKind> {
+        let proto = self.env.helpers.and_then(|helpers| helpers.kfunc_proto(id));
+        let proto = proto.ok_or(ErrorKind::UnknownKfunc { id })?;
+        for (i, ty) in proto.args.iter().enumerate() {
+            let reg = i as u8 + 1;
+            let value = read(state, reg)?;
+            if matches!(ty, KfuncType::Int { .. }) && value.ty.is_pointer() {
+                return Err(ErrorKind::PointerLeak { reg });
+            }
+        }
+        Ok(match proto.ret {
+            KfuncType::Void => RegState::NOT_INIT,
+            KfuncType::Int {
+                size,
+                signed: false,
+            } if size < 8 => RegState::scalar(Bounds::zero_extended(size as u32)),
+            _ => RegState::SCALAR,
+        })
+    }
+
+    fn check_helper(&mut self, id: u32, state: &mut State) -> Result<RegState, ErrorKind> {
+        let proto = match self.env.helpers {
+            Some(helpers) => helpers.proto(id),
+            None => BpfFunc::from_id(id).and_then(proto::builtin),
+        };
+        let proto = proto.ok_or(ErrorKind::UnknownHelper { id })?;
+        // the map later arguments and the return value refer to
+        let mut map = None;
+        let mut callback = None;
+        // the spin lock passed, as the map and pointer id of its value
+        let mut lock = None;
+        // memory the helper writes, marked once every argument is checked
+        let mut filled = [None; 5];
+        for (i, &arg) in proto.args.iter().enumerate() {
+            let reg = i as u8 + 1;
+            if arg == ArgType::DontCare {
+                continue;
+            }
+            let value = *read(state, reg)?;
+            let err = ErrorKind::InvalidArg {
+                reg,
+                ty: value.ty,
+                expected: arg,
+            };
+            let null = value.ty == RegType::Scalar && value.bounds.value() == Some(0);
+            match arg {
+                ArgType::DontCare | ArgType::Anything => {}
+                // sizes are checked with the memory they belong to
+                ArgType::ConstSize | ArgType::ConstSizeOrZero if value.ty == RegType::Scalar => {}
+                ArgType::ConstMapPtr => match value.ty {
+                    RegType::ConstMapPtr { fd } => {
+                        // as `record_func_map` forbids
+                        let writes = matches!(
+                            BpfFunc::from_id(id),
+                            Some(
+                                BpfFunc::MapUpdateElem
+                                    | BpfFunc::MapDeleteElem
+                                    | BpfFunc::MapPushElem
+                                    | BpfFunc::MapPopElem
+                            )
+                        );
+                        if writes && self.env.maps[fd as usize].map_flags & BPF_F_RDONLY_PROG != 0 {
+                            return Err(ErrorKind::ReadOnlyMap { fd });
+                        }
+                        map = Some(fd);
+                    }
+                    _ => return Err(err),
+                },
+                ArgType::PtrToMapKey | ArgType::PtrToMapValue | ArgType::PtrToUninitMapValue => {
+                    let def = &self.env.maps[map.ok_or(err)? as usize];
+                    let size = match arg {
+                        ArgType::PtrToMapKey => def.key_size,
+                        _ => def.value_size,
+                    };
+                    let init = arg != ArgType::PtrToUninitMapValue;
+                    self.check_helper_mem(state, &value, size, init, err)?;
+                    filled[i] = (!init).then_some((value, size));
+                }
+                // NULL only with a size of 0
+                ArgType::PtrToMemOrNull | ArgType::PtrToUninitMemOrNull if null => {
+                    if mem_size(state, reg + 1, proto.args.get(i + 1).copied())? != 0 {
+                        return Err(ErrorKind::InvalidMemSize { reg: reg + 1 });
+                    }
+                }
+                ArgType::PtrToMem
+                | ArgType::PtrToMemOrNull
+                | ArgType::PtrToUninitMem
+                | ArgType::PtrToUninitMemOrNull => {
+                    let size = mem_size(state, reg + 1, proto.args.get(i + 1).copied())?;
+                    let init = matches!(arg, ArgType::PtrToMem | ArgType::PtrToMemOrNull);
+                    if size > 0 {
+                        self.check_helper_mem(state, &value, size, init, err)?;
+                        filled[i] = (!init).then_some((value, size));
+                    }
+                }
+                ArgType::PtrToLong => {
+                    self.check_helper_mem(state, &value, 8, false, err)?;
+                    filled[i] = Some((value, 8));
+                }
+                ArgType::PtrToCtx
+                    if value.ty == RegType::PtrToCtx
+                        && value.off == 0
+                        && value.bounds.value() == Some(0) => {}
+                ArgType::PtrToStackOrNull if null || value.ty == RegType::PtrToStack => {}
+                ArgType::PtrToFunc => match value.ty {
+                    RegType::PtrToFunc { pc } => callback = Some(pc),
+                    _ => return Err(err),
+                },
+                ArgType::PtrToSpinLock | ArgType::PtrToTimer => {
+                    let fd = match value.ty {
+                        RegType::PtrToMapValue { fd } => fd,
+                        _ => return Err(err),
+                    };
+                    let def = &self.env.maps[fd as usize];
+                    let field = match arg {
+                        ArgType::PtrToSpinLock => def.spin_lock_off,
+                        _ => def.timer_off,
+                    };
+                    // the field itself, at a known offset
+                    let off = value
+                        .bounds
+                        .value()
+                        .map(|off| value.off as i64 + off as i64);
+                    if field.is_none() || off != field.map(|field| field as i64) {
+                        return Err(err);
+                    }
+                    if arg == ArgType::PtrToSpinLock {
+                        lock = Some((fd, value.id));
+                    }
+                    map = Some(fd);
+                }
+                // a string at a known offset into a frozen, read-only map,
+                // ending before the value does, as `check_reg_const_str`
+                // requires
+                ArgType::PtrToConstStr => {
+                    let fd = match value.ty {
+                        RegType::PtrToMapValue { fd } => fd,
+                        _ => return Err(err),
+                    };
+                    let def = &self.env.maps[fd as usize];
+                    let data = self.env.frozen.get(fd as usize).copied().flatten();
+                    let off = value
+                        .bounds
+                        .value()
+                        .map(|off| value.off as i64 + off as i64);
+                    let (data, off) = match (data, off) {
+                        (Some(data), Some(off)) if def.map_flags & BPF_F_RDONLY_PROG != 0 => {
+                            (data, off)
+                        }
+                        _ => return Err(err),
+                    };
+                    check_map_access(self.env.maps, &value, 0, 1)?;
+                    let value_size = def.value_size as usize;
+                    match data.get(off as usize..value_size.min(data.len())) {
+                        Some(s) if s.contains(&0) => {}
+                        _ => return Err(ErrorKind::UnterminatedStr { off }),
+                    }
+                }
+                _ => return Err(err),
+            }
+        }
+        for (ptr, size) in filled.iter().flatten() {
+            if ptr.ty == RegType::PtrToStack {
+                stack::fill(state, ptr, *size)?;
+            }
+        }
+        match BpfFunc::from_id(id) {
+            Some(BpfFunc::SpinLock) => state.lock = lock,
+            Some(BpfFunc::SpinUnlock) if state.lock != lock => {
+                return Err(ErrorKind::UnlockNotHeld)
+            }
+            Some(BpfFunc::SpinUnlock) => state.lock = None,
+            _ => {}
+        }
+
+        if let Some(entry) = callback {
+            let args = callback_args(BpfFunc::from_id(id), map, state);
+            let mut entry_state = State::entry(args);
+            if args.iter().any(|arg| arg.ty == RegType::PtrToCallerStack) {
+                entry_state.caller = state.stack.clone();
+            }
+            self.callbacks.push((entry, entry_state));
+        }
+        Ok(match (proto.ret, map) {
+            (RetType::Integer, _) => RegState::SCALAR,
+            (RetType::Void, _) => RegState::NOT_INIT,
+            (RetType::PtrToMapValueOrNull, Some(fd)) => {
+                self.id_gen += 1;
+                RegState {
+                    id: self.id_gen,
+                    ..RegState::new(RegType::PtrToMapValueOrNull { fd })
+                }
+            }
+            (RetType::PtrToMapValueOrNull, None) => return Err(ErrorKind::InvalidProto { id }),
+        })
+    }
+
+    /// Checks that a helper may access `size` bytes through `ptr`, which
+    /// must be initialized if it reads them, as `check_helper_mem_access`
+    /// does.
+    fn check_helper_mem(
+        &self,
+        state: &State,
+        ptr: &RegState,
+        size: u32,
+        init: bool,
+        err: ErrorKind,
+    ) -> Result<(), ErrorKind> {
+        match ptr.ty {
+            RegType::PtrToStack if init => stack::check_init(state, ptr, size),
+            // checked when filled
+            RegType::PtrToStack => Ok(()),
+            RegType::PtrToMapValue { .. } => {
+                check_map_access(self.env.maps, ptr, 0, size)?;
+                match init {
+                    true => Ok(()),
+                    false => check_map_write(self.env.maps, ptr),
+                }
+            }
+            RegType::PtrToMapKey { .. } if init => check_map_access(self.env.maps, ptr, 0, size),
+            RegType::PtrToPacket => check_packet_access(ptr, 0, size),
+            _ => Err(err),
+        }
+    }
+}
+
+/// Returns the largest size in `reg`, which must be a bounded scalar, and
+/// nonzero unless the prototype allows it.
+fn mem_size(state: &State, reg: u8, arg: Option<ArgType>) -> Result<u32, ErrorKind> {
+    let value = read(state, reg)?;
+    let zero = match arg {
+        Some(ArgType::ConstSize) => false,
+        Some(ArgType::ConstSizeOrZero) => true,
+        _ => return Err(ErrorKind::InvalidMemSize { reg }),
+    };
+    let bounds = &value.bounds;
+    match value.ty == RegType::Scalar
+        && bounds.smin >= 0
+        && bounds.umax < MAX_OFF as u64
+        && (zero || bounds.umin > 0)
+    {
+        true => Ok(bounds.umax as u32),
+        false => Err(ErrorKind::InvalidMemSize { reg }),
+    }
+}
+
+/// Returns the arguments a helper passes its callback, given the caller's
+/// `state` at the call.
+fn callback_args(func: Option<BpfFunc>, map: Option<u32>, state: &State) -> [RegState; 5] {
+    let mut args = [RegState::NOT_INIT; 5];
+    match (func, map) {
+        (Some(BpfFunc::Loop), _) => {
+            args[0] = RegState::scalar(Bounds::zero_extended(4));
+            args[1] = callback_ctx(&state.regs[3]);
+        }
+        (Some(func @ (BpfFunc::ForEachMapElem | BpfFunc::TimerSetCallback)), Some(fd)) => {
+            args[0] = RegState::new(RegType::ConstMapPtr { fd });
+            args[1] = RegState::new(RegType::PtrToMapKey { fd });
+            args[2] = RegState::new(RegType::PtrToMapValue { fd });
+            if func == BpfFunc::ForEachMapElem {
+                args[3] = callback_ctx(&state.regs[4]);
+            }
+        }
+        _ => args = [RegState::SCALAR; 5],
+    }
+    args
+}
+
+/// Returns the context the program passes along to a callback. A pointer
+/// into its own frame points into the callback's caller's; anything else is
+/// only known to be some value.
+fn callback_ctx(ctx: &RegState) -> RegState {
+    match ctx.ty {
+        RegType::PtrToStack => RegState {
+            ty: RegType::PtrToCallerStack,
+            ..*ctx
+        },
+        _ => RegState::SCALAR,
+    }
+}
@@ -0,0 +1,375 @@
+//! Structural checks of the instruction stream and its control-flow graph,
+//! like `check_subprogs` and `check_cfg` in the kernel.
+
+use super::{Error, ErrorKind, Insn};
+use crate::consts::*;
+use crate::types::*;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+fn check_reg(reg: u8) -> Result<(), ErrorKind> {
+    match reg {
+        0..=10 => Ok(()),
+        _ => Err(ErrorKind::InvalidRegister { reg }),
+    }
+}
+
+fn reserved(cond: bool) -> Result<(), ErrorKind> {
+    match cond {
+        true => Err(ErrorKind::ReservedFields),
+        false => Ok(()),
+    }
+}
+
+/// Validates the opcode and fields of the instruction at `pc`.
+fn check_insn(insts: &[u64], pc: usize) -> Result<(), ErrorKind> {
+    let insn = Insn::decode(insts[pc]);
+    let unknown = Err(ErrorKind::UnknownOpcode { op: insn.op });
+    let class = insn.op as u32 & 0x07;
+    let code = insn.op as u32 & 0xf0;
+    let x = insn.op as u32 & BPF_X != 0;
+    match class {
+        BPF_ALU | BPF_ALU64 => {
+            match code {
+                BPF_NEG => reserved(x || insn.src != 0 || insn.off != 0 || insn.imm != 0)?,
+                BPF_END if class == BPF_ALU64 => return unknown,
+                BPF_END => {
+                    reserved(insn.src != 0 || insn.off != 0 || !matches!(insn.imm, 16 | 32 | 64))?
+                }
+                BPF_ADD | BPF_SUB | BPF_MUL | BPF_DIV | BPF_OR | BPF_AND | BPF_LSH | BPF_RSH
+                | BPF_MOD | BPF_XOR | BPF_MOV | BPF_ARSH
+                    if x =>
+                {
+                    reserved(insn.imm != 0 || insn.off != 0)?;
+                    check_reg(insn.src)?;
+                }
+                BPF_ADD | BPF_SUB | BPF_MUL | BPF_DIV | BPF_OR | BPF_AND | BPF_LSH | BPF_RSH
+                | BPF_MOD | BPF_XOR | BPF_MOV | BPF_ARSH => {
+                    reserved(insn.src != 0 || insn.off != 0)?;
+                    let bits = if class == BPF_ALU64 { 64 } else { 32 };
+                    match code {
+                        BPF_DIV | BPF_MOD if insn.imm == 0 => return Err(ErrorKind::DivByZero),
+                        BPF_LSH | BPF_RSH | BPF_ARSH if !(0..bits).contains(&insn.imm) => {
+                            return Err(ErrorKind::InvalidShift { shift: insn.imm })
+                        }
+                        _ => {}
+                    }
+                }
+                _ => return unknown,
+            }
+            check_reg(insn.dst)
+        }
+        BPF_JMP | BPF_JMP32 => match code {
+            BPF_JA | BPF_CALL | BPF_EXIT if class == BPF_JMP32 || x => unknown,
+            BPF_JA => reserved(insn.imm != 0 || insn.src != 0 || insn.dst != 0),
+            BPF_CALL => {
+                reserved(insn.off != 0 || insn.dst != 0)?;
+                match insn.src as u32 {
+                    0 | BPF_PSEUDO_KFUNC_CALL => Ok(()),
+                    _ => Err(ErrorKind::UnsupportedCall),
+                }
+            }
+            BPF_EXIT => reserved(insn.imm != 0 || insn.src != 0 || insn.dst != 0 || insn.off != 0),
+            BPF_JEQ | BPF_JGT | BPF_JGE | BPF_JSET | BPF_JNE | BPF_JSGT | BPF_JSGE | BPF_JLT
+            | BPF_JLE | BPF_JSLT | BPF_JSLE => {
+                if x {
+                    reserved(insn.imm != 0)?;
+                    check_reg(insn.src)?;
+                } else {
+                    reserved(insn.src != 0)?;
+                }
+                check_reg(insn.dst)
+            }
+            _ => unknown,
+        },
+        BPF_LD if insn.op == LD_IMM_DW => {
+            reserved(insn.off != 0)?;
+            match insn.src as u32 {
+                0 | BPF_PSEUDO_MAP_FD | BPF_PSEUDO_FUNC => {}
+                _ => return Err(ErrorKind::ReservedFields),
+            }
+            match insts.get(pc + 1).map(|&next| Insn::decode(next)) {
+                Some(Insn {
+                    op: 0,
+                    dst: 0,
+                    src: 0,
+                    off: 0,
+                    ..
+                }) => {}
+                _ => return Err(ErrorKind::IncompleteLdImm),
+            }
+            check_reg(insn.dst)
+        }
+        BPF_LDX | BPF_ST | BPF_STX if insn.op as u32 & 0xe0 == BPF_MEM => {
+            match class {
+                BPF_ST => reserved(insn.src != 0)?,
+                _ => {
+                    reserved(insn.imm != 0)?;
+                    check_reg(insn.src)?;
+                }
+            }
+            check_reg(insn.dst)
+        }
+        _ => unknown,
+    }
+}
+
+/// Returns the callback loaded by the `LD_IMM_DW` at `pc`, if any.
+fn func_target(insn: &Insn, pc: usize) -> Option<i64> {
+    match insn.op == LD_IMM_DW && insn.src as u32 == BPF_PSEUDO_FUNC {
+        true => Some(pc as i64 + 1 + insn.imm as i64),
+        false => None,
+    }
+}
+
+/// Returns the instructions control can pass to from `pc`, as unchecked
+/// targets.
+fn successors(insn: &Insn, pc: usize) -> [Option<i64>; 2] {
+    let next = pc as i64 + 1;
+    let class = insn.op as u32 & 0x07;
+    match (class, insn.op as u32 & 0xf0) {
+        (BPF_JMP, BPF_EXIT) => [None, None],
+        (BPF_JMP, BPF_JA) => [Some(next + insn.off as i64), None],
+        (BPF_JMP, BPF_CALL) => [Some(next), None],
+        (BPF_JMP, _) | (BPF_JMP32, _) => [Some(next), Some(next + insn.off as i64)],
+        _ if insn.op == LD_IMM_DW => [Some(next + 1), None],
+        _ => [Some(next), None],
+    }
+}
+
+/// Checks every instruction and the control flow between them: jumps stay
+/// within their function and land on instruction boundaries, functions end
+/// in an exit or jump and everything is reachable. Loops are left to path
+/// exploration, which has to show that they end.
+/// Returns the first instruction of each function, the program first.
+pub(crate) fn check(insts: &[u64]) -> Result<Vec<usize>, Error> {
+    let err = |pc, kind| Error { pc, kind };
+    if insts.is_empty() {
+        return Err(err(0, ErrorKind::Empty));
+    }
+    if insts.len() > BPF_MAXINSNS as usize {
+        return Err(err(0, ErrorKind::TooLarge { len: insts.len() }));
+    }
+
+    let mut second_half = vec![false; insts.len()];
+    let mut funcs = vec![0];
+    let mut pc = 0;
+    while pc < insts.len() {
+        check_insn(insts, pc).map_err(|kind| err(pc, kind))?;
+        let insn = Insn::decode(insts[pc]);
+        if insn.op == LD_IMM_DW {
+            second_half[pc + 1] = true;
+            pc += 1;
+        }
+        pc += 1;
+    }
+    for pc in 0..insts.len() {
+        let insn = Insn::decode(insts[pc]);
+        if let Some(target) = func_target(&insn, pc) {
+            match usize::try_from(target) {
+                Ok(target) if target < insts.len() && second_half[target] => {
+                    return Err(err(pc, ErrorKind::JumpIntoLdImm { target }));
+                }
+                Ok(target) if target < insts.len() => funcs.push(target),
+                _ => return Err(err(pc, ErrorKind::JumpOutOfRange { target })),
+            }
+        }
+    }
+    funcs.sort_unstable();
+    funcs.dedup();
+
+    // function bounds, for checking jumps
+    let mut func_end = vec![0; insts.len()];
+    let mut func_start = vec![0; insts.len()];
+    for (i, &start) in funcs.iter().enumerate() {
+        let end = funcs.get(i + 1).copied().unwrap_or(insts.len());
+        let last = Insn::decode(insts[end - 1]);
+        if second_half[end - 1] || !matches!(last.op, JMP_K_EXIT | JMP_K_JA) {
+            return Err(err(end - 1, ErrorKind::FallThrough));
+        }
+        func_start[start..end].fill(start);
+        func_end[start..end].fill(end);
+    }
+
+    // depth-first search from every function, as `check_cfg` does
+    let mut seen = vec![false; insts.len()];
+    for &entry in &funcs {
+        let mut stack = vec![(entry, 0)];
+        seen[entry] = true;
+        while let Some(&(pc, edge)) = stack.last() {
+            let insn = Insn::decode(insts[pc]);
+            let target = match successors(&insn, pc).get(edge) {
+                Some(&Some(target)) => target,
+                _ => {
+                    stack.pop();
+                    continue;
+                }
+            };
+            if let Some((_, edge)) = stack.last_mut() {
+                *edge += 1;
+            }
+            let target = match usize::try_from(target) {
+                Ok(target) if target >= func_start[pc] && target < func_end[pc] => target,
+                _ => return Err(err(pc, ErrorKind::JumpOutOfRange { target })),
+            };
+            if second_half[target] {
+                return Err(err(pc, ErrorKind::JumpIntoLdImm { target }));
+            }
+            if !seen[target] {
+                seen[target] = true;
+                stack.push((target, 0));
+            }
+        }
+    }
+    if let Some(pc) = (0..insts.len()).find(|&pc| !seen[pc] && !second_half[pc]) {
+        return Err(err(pc, ErrorKind::Unreachable));
+    }
+    Ok(funcs)
+}
+
+/// Marks the instructions control can reach other than by falling through,
+/// where path exploration saves states to compare later ones against, like
+/// `mark_prune_point`. `insts` must have passed `check`.
+pub(crate) fn prune_points(insts: &[u64]) -> Vec<bool> {
+    let mut points = vec![false; insts.len()];
+    let mut pc = 0;
+    while pc < insts.len() {
+        let insn = Insn::decode(insts[pc]);
+        let class = insn.op as u32 & 0x07;
+        let jump = matches!(class, BPF_JMP | BPF_JMP32)
+            && !matches!(insn.op as u32 & 0xf0, BPF_CALL | BPF_EXIT);
+        if jump {
+            for target in successors(&insn, pc).iter().flatten() {
+                points[*target as usize] = true;
+            }
+        }
+        pc += if insn.op == LD_IMM_DW { 2 } else { 1 };
+    }
+    points
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check_at(insts: &[u64]) -> Result<(), (usize, ErrorKind)> {
+        check(insts).map(|_| ()).map_err(|err| (err.pc, err.kind))
+    }
+
+    #[test]
+    fn structure() {
+        let inst = Insn::encode;
+        let exit = inst(JMP_K_EXIT, 0, 0, 0, 0);
+        let mov = inst(ALU64_K_MOV, 0, 0, 0, 1);
+        assert_eq!(check_at(&[mov, exit]), Ok(()));
+        assert_eq!(check_at(&[]), Err((0, ErrorKind::Empty)));
+        let long = [exit; BPF_MAXINSNS as usize + 1];
+        assert_eq!(check_at(&long), Err((0, ErrorKind::TooLarge { len: 4097 })));
+
+        let alu64_end = inst(ALU64_K_END, 0, 0, 0, 16);
+        assert_eq!(
+            check_at(&[alu64_end, exit]),
+            Err((0, ErrorKind::UnknownOpcode { op: ALU64_K_END }))
+        );
+        let ld_abs = inst(LD_ABS_B, 0, 0, 0, 0);
+        assert_eq!(
+            check_at(&[mov, ld_abs, exit]),
+            Err((1, ErrorKind::UnknownOpcode { op: LD_ABS_B }))
+        );
+        let bad = [
+            inst(ALU64_K_ADD, 0, 1, 0, 1),
+            inst(ALU64_X_ADD, 0, 1, 0, 1),
+            inst(ALU_K_NEG, 0, 0, 0, 1),
+            inst(ALU_K_END, 0, 0, 0, 8),
+            inst(JMP_K_EXIT, 0, 0, 0, 1),
+            inst(JMP_K_JA, 0, 0, 0, 1),
+            inst(JMP_K_JEQ, 0, 1, 0, 1),
+            inst(STX_MEM_W, 10, 0, -4, 1),
+            inst(LDX_MEM_W, 0, 10, -4, 1),
+        ];
+        for insn in bad {
+            assert_eq!(check_at(&[insn, exit]), Err((0, ErrorKind::ReservedFields)));
+        }
+        assert_eq!(
+            check_at(&[inst(ALU64_K_DIV, 0, 0, 0, 0), exit]),
+            Err((0, ErrorKind::DivByZero))
+        );
+        assert_eq!(
+            check_at(&[inst(ALU_K_MOD, 0, 0, 0, 0), exit]),
+            Err((0, ErrorKind::DivByZero))
+        );
+        // a divisor in a register may be zero, which the VM defines
+        assert_eq!(check_at(&[inst(ALU64_X_DIV, 0, 1, 0, 0), exit]), Ok(()));
+        assert_eq!(check_at(&[inst(ALU64_K_LSH, 0, 0, 0, 63), exit]), Ok(()));
+        for (op, shift) in [(ALU64_K_LSH, 64), (ALU_K_RSH, 32), (ALU_K_ARSH, -1)] {
+            assert_eq!(
+                check_at(&[inst(op, 0, 0, 0, shift), exit]),
+                Err((0, ErrorKind::InvalidShift { shift }))
+            );
+        }
+        assert_eq!(
+            check_at(&[inst(ALU64_X_MOV, 0, 11, 0, 0), exit]),
+            Err((0, ErrorKind::InvalidRegister { reg: 11 }))
+        );
+        assert_eq!(
+            check_at(&[inst(JMP_K_CALL, 0, BPF_PSEUDO_CALL as u8, 0, 1), exit]),
+            Err((0, ErrorKind::UnsupportedCall))
+        );
+        assert_eq!(
+            check_at(&[exit, inst(LD_IMM_DW, 0, 0, 0, 0)]),
+            Err((1, ErrorKind::IncompleteLdImm))
+        );
+    }
+
+    #[test]
+    fn control_flow() {
+        let inst = Insn::encode;
+        let exit = inst(JMP_K_EXIT, 0, 0, 0, 0);
+        let mov = inst(ALU64_K_MOV, 0, 0, 0, 1);
+        let ld_imm = [inst(LD_IMM_DW, 0, 0, 0, 1), 0];
+
+        let jeq = |off| inst(JMP_K_JEQ, 0, 0, off, 0);
+        assert_eq!(check_at(&[mov, jeq(1), mov, exit]), Ok(()));
+        assert_eq!(
+            check_at(&[mov, jeq(5), exit]),
+            Err((1, ErrorKind::JumpOutOfRange { target: 7 }))
+        );
+        assert_eq!(
+            check_at(&[mov, jeq(-3), exit]),
+            Err((1, ErrorKind::JumpOutOfRange { target: -1 }))
+        );
+        assert_eq!(
+            check_at(&[mov, jeq(1), ld_imm[0], ld_imm[1], exit]),
+            Err((1, ErrorKind::JumpIntoLdImm { target: 3 }))
+        );
+        assert_eq!(check_at(&[mov]), Err((0, ErrorKind::FallThrough)));
+        assert_eq!(
+            check_at(&[mov, ld_imm[0], ld_imm[1]]),
+            Err((2, ErrorKind::FallThrough))
+        );
+        assert_eq!(
+            check_at(&[inst(JMP_K_JA, 0, 0, 1, 0), mov, exit]),
+            Err((1, ErrorKind::Unreachable))
+        );
+        assert_eq!(check_at(&[mov, jeq(-2), exit]), Ok(()));
+
+        // a callback is a function of its own, reachable only through its
+        // address, and jumps may not cross into another function
+        let func = BPF_PSEUDO_FUNC as u8;
+        let prog = [inst(LD_IMM_DW, 2, func, 0, 2), 0, exit, mov, exit];
+        assert_eq!(check_at(&prog), Ok(()));
+        let prog = [inst(LD_IMM_DW, 2, func, 0, 2), 0, jeq(0), mov, exit];
+        assert_eq!(check_at(&prog), Err((2, ErrorKind::FallThrough)));
+        let prog = [inst(LD_IMM_DW, 2, func, 0, 2), 0, exit, jeq(-2), exit];
+        assert_eq!(
+            check_at(&prog),
+            Err((3, ErrorKind::JumpOutOfRange { target: 2 }))
+        );
+        let prog = [inst(LD_IMM_DW, 2, func, 0, 9), 0, exit];
+        assert_eq!(
+            check_at(&prog),
+            Err((0, ErrorKind::JumpOutOfRange { target: 10 }))
+        );
+    }
+}
@@ -0,0 +1,176 @@
+//! Layouts of the context each program type receives, after the
+//! `is_valid_access` callbacks of the kernel's `bpf_verifier_ops`.
+
+use super::state::{RegState, RegType};
+use super::{unknown, ErrorKind};
+use crate::consts::*;
+
+/// What a context field holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldKind {
+    Scalar,
+    /// `data`, the start of the packet.
+    PacketData,
+    /// `data_end`, just past the packet.
+    PacketEnd,
+}
+
+/// A run of `count` context fields of `size` bytes each, such as an array.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CtxField {
+    pub off: u32,
+    pub size: u32,
+    pub count: u32,
+    pub kind: FieldKind,
+    pub writable: bool,
+    /// Whether part of a field may be loaded on its own.
+    pub narrow: bool,
+}
+
+const fn run(off: u32, size: u32, count: u32, writable: bool) -> CtxField {
+    CtxField {
+        off,
+        size,
+        count,
+        kind: FieldKind::Scalar,
+        writable,
+        narrow: true,
+    }
+}
+
+const fn read(off: u32, size: u32, count: u32) -> CtxField {
+    run(off, size, count, false)
+}
+
+const fn write(off: u32, size: u32, count: u32) -> CtxField {
+    run(off, size, count, true)
+}
+
+const fn packet(off: u32, kind: FieldKind) -> CtxField {
+    CtxField {
+        kind,
+        narrow: false,
+        ..read(off, 4, 1)
+    }
+}
+
+/// `struct __sk_buff` as socket filters see it: only `cb` may be written,
+/// and the packet is read with `bpf_skb_load_bytes` rather than directly.
+static SOCKET_FILTER: &[CtxField] = &[
+    read(0, 4, 12),
+    write(48, 4, 5),
+    read(68, 4, 2),
+    read(84, 4, 14),
+    read(152, 8, 1),
+    read(160, 4, 2),
+    read(176, 4, 1),
+    read(184, 8, 1),
+];
+
+/// `struct __sk_buff` as traffic control programs see it.
+static SCHED_CLS: &[CtxField] = &[
+    // len, pkt_type
+    read(0, 4, 2),
+    // mark, queue_mapping
+    write(8, 4, 2),
+    // protocol, vlan_present, vlan_tci, vlan_proto
+    read(16, 4, 4),
+    // priority
+    write(32, 4, 1),
+    // ingress_ifindex, ifindex
+    read(36, 4, 2),
+    // tc_index, cb[5]
+    write(44, 4, 6),
+    // hash
+    read(68, 4, 1),
+    // tc_classid
+    write(72, 4, 1),
+    packet(76, FieldKind::PacketData),
+    packet(80, FieldKind::PacketEnd),
+    // napi_id, family, the IPv4 and IPv6 addresses and the ports
+    read(84, 4, 14),
+    // tstamp
+    write(152, 8, 1),
+    // wire_len, gso_segs
+    read(160, 4, 2),
+    // gso_size
+    read(176, 4, 1),
+    // hwtstamp
+    read(184, 8, 1),
+];
+
+/// `struct xdp_md`.
+static XDP: &[CtxField] = &[
+    packet(0, FieldKind::PacketData),
+    packet(4, FieldKind::PacketEnd),
+    // ingress_ifindex, rx_queue_index, egress_ifindex
+    read(12, 4, 3),
+];
+
+/// Size of the host's `struct pt_regs`, which kprobes see the registers
+/// of the machine they run on through.
+#[cfg(target_arch = "aarch64")]
+const PT_REGS_SIZE: u32 = 34 * 8;
+#[cfg(not(target_arch = "aarch64"))]
+const PT_REGS_SIZE: u32 = 21 * 8;
+
+static KPROBE: &[CtxField] = &[read(0, 8, PT_REGS_SIZE / 8)];
+
+/// `struct bpf_perf_event_data`: the registers, then `sample_period` and
+/// `addr`.
+static PERF_EVENT: &[CtxField] = &[read(0, 8, PT_REGS_SIZE / 8 + 2)];
+
+/// The raw record of a tracepoint, up to `PERF_MAX_TRACE_SIZE`.
+static TRACEPOINT: &[CtxField] = &[read(0, 8, 2048 / 8)];
+
+/// The arguments of a raw tracepoint, up to `MAX_BPF_FUNC_ARGS` of them.
+static RAW_TRACEPOINT: &[CtxField] = &[CtxField {
+    narrow: false,
+    ..read(0, 8, 12)
+}];
+
+/// Returns the context fields of programs of `prog_type`, `BPF_PROG_TYPE_*`,
+/// or `None` for `BPF_PROG_TYPE_UNSPEC` and types without a description,
+/// whose context may not be accessed.
+pub fn fields(prog_type: u32) -> Option<&'static [CtxField]> {
+    Some(match prog_type {
+        BPF_PROG_TYPE_SOCKET_FILTER => SOCKET_FILTER,
+        BPF_PROG_TYPE_SCHED_CLS | BPF_PROG_TYPE_SCHED_ACT => SCHED_CLS,
+        BPF_PROG_TYPE_XDP => XDP,
+        BPF_PROG_TYPE_KPROBE => KPROBE,
+        BPF_PROG_TYPE_PERF_EVENT => PERF_EVENT,
+        BPF_PROG_TYPE_TRACEPOINT => TRACEPOINT,
+        BPF_PROG_TYPE_RAW_TRACEPOINT => RAW_TRACEPOINT,
+        _ => return None,
+    })
+}
+
+/// Checks an access of `size` bytes at `off` into a context of `fields`,
+/// which must lie within one field, aligned, and not write a read-only one,
+/// and returns what a load gives.
+pub fn access(
+    fields: &[CtxField],
+    off: i64,
+    size: u32,
+    write: bool,
+) -> Result<RegState, ErrorKind> {
+    let err = ErrorKind::InvalidCtxAccess { off, size };
+    let field = fields
+        .iter()
+        .find(|field| {
+            let end = field.off + field.size * field.count;
+            off >= field.off as i64 && off < end as i64
+        })
+        .ok_or(err)?;
+    let start = off - (off - field.off as i64) % field.size as i64;
+    let fits = off % size as i64 == 0 && off + size as i64 <= start + field.size as i64;
+    let whole = size == field.size;
+    if !fits || (!whole && (write || !field.narrow)) || (write && !field.writable) {
+        return Err(err);
+    }
+    Ok(match field.kind {
+        FieldKind::Scalar => unknown(size),
+        FieldKind::PacketData => RegState::new(RegType::PtrToPacket),
+        FieldKind::PacketEnd => RegState::new(RegType::PtrToPacketEnd),
+    })
+}
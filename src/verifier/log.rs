@@ -0,0 +1,239 @@
+//! What the verifier did, after the kernel's verifier log.
+
+use super::state::State;
+use super::{size, Error, Insn};
+use crate::consts::*;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Records instructions and the branches they are on, `BPF_LOG_LEVEL1`.
+/// Once a path ends its records are dropped, so only those of the path that
+/// failed are left.
+pub const LOG_LEVEL1: u32 = 1;
+/// Also records the state before each instruction, and keeps the records of
+/// every path, `BPF_LOG_LEVEL2`.
+pub const LOG_LEVEL2: u32 = 2;
+
+/// One entry of a [`Log`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Record {
+    /// A path that was set aside at the branch at `from` is taken up at
+    /// `to`, in `state`.
+    Branch {
+        from: usize,
+        to: usize,
+        state: State,
+    },
+    /// The instruction at `pc` is processed, with `state` before it at
+    /// `LOG_LEVEL2`.
+    Insn {
+        pc: usize,
+        op: u8,
+        text: String,
+        state: Option<State>,
+    },
+    /// The path reaches `pc` in a state already shown safe there.
+    Pruned { pc: usize },
+    /// The program is rejected, after following the instructions in
+    /// `backtrace` from its entry.
+    Error { error: Error, backtrace: Vec<usize> },
+}
+
+/// Buffer the verifier writes to as it goes. It prints like the kernel's
+/// log, and its records can be looked at one by one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Log {
+    /// `LOG_LEVEL1` or `LOG_LEVEL2`, or 0 to record nothing.
+    pub level: u32,
+    pub records: Vec<Record>,
+    /// Instructions processed over all paths.
+    pub processed: usize,
+}
+
+impl Log {
+    pub fn new(level: u32) -> Self {
+        Log {
+            level,
+            ..Log::default()
+        }
+    }
+
+    /// Returns the error the program was rejected with, if any.
+    pub fn error(&self) -> Option<&Error> {
+        self.records.iter().rev().find_map(|record| match record {
+            Record::Error { error, .. } => Some(error),
+            _ => None,
+        })
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Record::Branch { from, to, state } => writeln!(f, "from {} to {}: {}", from, to, state),
+            Record::Insn {
+                pc,
+                op,
+                text,
+                state,
+            } => {
+                if let Some(state) = state {
+                    writeln!(f, "{}: {}", pc, state)?;
+                }
+                writeln!(f, "{}: ({:02x}) {}", pc, op, text)
+            }
+            Record::Pruned { pc } => writeln!(f, "{}: safe", pc),
+            Record::Error { error, backtrace } => {
+                writeln!(f, "{}", error)?;
+                write!(f, "path:")?;
+                for pc in backtrace {
+                    write!(f, " {}", pc)?;
+                }
+                writeln!(f)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Log {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for record in &self.records {
+            write!(f, "{}", record)?;
+        }
+        writeln!(f, "processed {} insns", self.processed)
+    }
+}
+
+/// Writes the instruction at `pc` as the kernel's `print_bpf_insn` does,
+/// such as `r0 = *(u32 *)(r1 +4)` or `if w1 > 0x5 goto pc+2`. The program
+/// must have passed `cfg::check`.
+pub fn disasm(insts: &[u64], pc: usize) -> String {
+    let insn = Insn::decode(insts[pc]);
+    let class = insn.op as u32 & 0x07;
+    let code = insn.op as u32 & 0xf0;
+    let x = insn.op as u32 & BPF_X != 0;
+    let r = match class {
+        BPF_ALU | BPF_JMP32 => 'w',
+        _ => 'r',
+    };
+    let (dst, src) = (insn.dst, insn.src);
+    match class {
+        BPF_ALU | BPF_ALU64 => {
+            let op = match code {
+                BPF_NEG => return format!("{}{} = -{}{}", r, dst, r, dst),
+                BPF_END => {
+                    let order = match insn.op as u32 & BPF_TO_BE {
+                        BPF_TO_BE => "be",
+                        _ => "le",
+                    };
+                    return format!("r{} = {}{} r{}", dst, order, insn.imm, dst);
+                }
+                BPF_ADD => "+=",
+                BPF_SUB => "-=",
+                BPF_MUL => "*=",
+                BPF_DIV => "/=",
+                BPF_OR => "|=",
+                BPF_AND => "&=",
+                BPF_LSH => "<<=",
+                BPF_RSH => ">>=",
+                BPF_MOD => "%=",
+                BPF_XOR => "^=",
+                BPF_MOV => "=",
+                _ => "s>>=",
+            };
+            match x {
+                true => format!("{}{} {} {}{}", r, dst, op, r, src),
+                false => format!("{}{} {} {}", r, dst, op, insn.imm),
+            }
+        }
+        BPF_JMP | BPF_JMP32 => {
+            let off = insn.off;
+            let op = match code {
+                BPF_JA => return format!("goto pc{:+}", off),
+                BPF_EXIT => return "exit".into(),
+                BPF_CALL if src as u32 == BPF_PSEUDO_KFUNC_CALL => {
+                    return format!("call kfunc#{}", insn.imm)
+                }
+                BPF_CALL => return format!("call {}", insn.imm),
+                BPF_JEQ => "==",
+                BPF_JGT => ">",
+                BPF_JGE => ">=",
+                BPF_JSET => "&",
+                BPF_JNE => "!=",
+                BPF_JSGT => "s>",
+                BPF_JSGE => "s>=",
+                BPF_JLT => "<",
+                BPF_JLE => "<=",
+                BPF_JSLT => "s<",
+                _ => "s<=",
+            };
+            match x {
+                true => format!("if {}{} {} {}{} goto pc{:+}", r, dst, op, r, src, off),
+                false => format!("if {}{} {} {:#x} goto pc{:+}", r, dst, op, insn.imm, off),
+            }
+        }
+        BPF_LD => match src as u32 {
+            BPF_PSEUDO_MAP_FD => format!("r{} = map[fd:{}]", dst, insn.imm),
+            BPF_PSEUDO_FUNC => {
+                let target = pc as i64 + 1 + insn.imm as i64;
+                format!("r{} = func[insn:{}]", dst, target)
+            }
+            _ => {
+                let high = Insn::decode(insts[pc + 1]).imm as u32 as u64;
+                format!("r{} = {:#x} ll", dst, insn.imm as u32 as u64 | high << 32)
+            }
+        },
+        _ => {
+            let bits = size(insn.op) * 8;
+            let mem = |reg| format!("*(u{} *)(r{} {:+})", bits, reg, insn.off);
+            match class {
+                BPF_LDX => format!("r{} = {}", dst, mem(src)),
+                BPF_ST => format!("{} = {}", mem(dst), insn.imm),
+                _ => format!("{} = r{}", mem(dst), src),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::*;
+
+    #[test]
+    fn disassemble() {
+        let inst = Insn::encode;
+        let text = |insts: &[u64]| disasm(insts, 0);
+        assert_eq!(text(&[inst(ALU64_K_MOV, 0, 0, 0, -1)]), "r0 = -1");
+        assert_eq!(text(&[inst(ALU_X_ADD, 1, 2, 0, 0)]), "w1 += w2");
+        assert_eq!(text(&[inst(ALU64_K_ARSH, 3, 0, 0, 4)]), "r3 s>>= 4");
+        assert_eq!(text(&[inst(ALU64_K_NEG, 0, 0, 0, 0)]), "r0 = -r0");
+        assert_eq!(text(&[inst(ALU_X_END, 0, 0, 0, 16)]), "r0 = be16 r0");
+        assert_eq!(
+            text(&[inst(JMP_K_JGT, 1, 0, 2, 5)]),
+            "if r1 > 0x5 goto pc+2"
+        );
+        assert_eq!(
+            text(&[inst(JMP32_X_JSLE, 1, 2, -3, 0)]),
+            "if w1 s<= w2 goto pc-3"
+        );
+        assert_eq!(text(&[inst(JMP_K_JA, 0, 0, 1, 0)]), "goto pc+1");
+        assert_eq!(text(&[inst(JMP_K_CALL, 0, 0, 0, 5)]), "call 5");
+        assert_eq!(text(&[inst(JMP_K_EXIT, 0, 0, 0, 0)]), "exit");
+        let high = inst(0, 0, 0, 0, 1);
+        assert_eq!(
+            text(&[inst(LD_IMM_DW, 1, 0, 0, 2), high]),
+            "r1 = 0x100000002 ll"
+        );
+        let map = BPF_PSEUDO_MAP_FD as u8;
+        assert_eq!(text(&[inst(LD_IMM_DW, 1, map, 0, 3), 0]), "r1 = map[fd:3]");
+        assert_eq!(text(&[inst(LDX_MEM_W, 0, 1, 4, 0)]), "r0 = *(u32 *)(r1 +4)");
+        assert_eq!(
+            text(&[inst(STX_MEM_DW, 10, 1, -8, 0)]),
+            "*(u64 *)(r10 -8) = r1"
+        );
+        assert_eq!(text(&[inst(ST_MEM_B, 10, 0, -1, 7)]), "*(u8 *)(r10 -1) = 7");
+    }
+}
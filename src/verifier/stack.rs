@@ -0,0 +1,212 @@
+//! Loads and stores through stack pointers, after `check_stack_write` and
+//! `check_stack_read` in kernel/bpf/verifier.c.
+
+use super::bounds::Bounds;
+use super::state::{RegState, SlotType, StackSlot, State};
+use super::tnum::Tnum;
+use super::ErrorKind;
+use core::mem;
+
+/// Size of the stack frame, `MAX_BPF_STACK` in the kernel.
+pub const STACK_SIZE: i64 = 512;
+
+/// Returns the slot and byte within it of the stack byte at `off` from r10.
+fn index(off: i64) -> (usize, usize) {
+    let pos = (-off - 1) as usize;
+    (pos / 8, pos % 8)
+}
+
+fn slot(state: &State, off: i64) -> (StackSlot, usize) {
+    let (slot, byte) = index(off);
+    let slot = state.stack.get(slot).copied();
+    (slot.unwrap_or(StackSlot::INVALID), byte)
+}
+
+fn slot_mut(state: &mut State, off: i64) -> (&mut StackSlot, usize) {
+    let (slot, byte) = index(off);
+    if state.stack.len() <= slot {
+        state.stack.resize(slot + 1, StackSlot::INVALID);
+    }
+    (&mut state.stack[slot], byte)
+}
+
+/// Returns the offsets from r10 an access of `size` bytes at `off` from
+/// `ptr` may touch, after checking that they are within the frame.
+fn extent(ptr: &RegState, off: i16, size: u32) -> Result<(i64, i64), ErrorKind> {
+    let start = ptr.off as i64 + off as i64;
+    let (lo, hi) = (
+        start + ptr.bounds.smin,
+        start + ptr.bounds.smax + size as i64,
+    );
+    match lo >= -STACK_SIZE && hi <= 0 {
+        true => Ok((lo, hi)),
+        false => Err(ErrorKind::InvalidStackAccess { off: start, size }),
+    }
+}
+
+/// As `extent`, also checking that the access is aligned to `size`.
+fn range(ptr: &RegState, off: i16, size: u32) -> Result<(i64, i64), ErrorKind> {
+    let start = ptr.off as i64 + off as i64;
+    let addr = ptr.bounds.var_off + Tnum::constant(start as u64);
+    if (addr.value | addr.mask) & (size as u64 - 1) != 0 {
+        return Err(ErrorKind::InvalidStackAccess { off: start, size });
+    }
+    extent(ptr, off, size)
+}
+
+/// Marks the bytes in `lo..hi` as holding data, zero if `zero`. Where the
+/// offset is not `fixed` any of them may be left as they were, so ones
+/// never written stay so.
+fn mark(state: &mut State, lo: i64, hi: i64, fixed: bool, zero: bool) {
+    for off in lo..hi {
+        let (slot, byte) = slot_mut(state, off);
+        // what is left of a spilled register is just data
+        if slot.is_spill() {
+            slot.types = [SlotType::Misc; 8];
+            slot.spilled = RegState::NOT_INIT;
+        }
+        let ty = &mut slot.types[byte];
+        *ty = match (fixed, *ty) {
+            (true, _) if zero => SlotType::Zero,
+            (false, SlotType::Invalid) => SlotType::Invalid,
+            (false, SlotType::Zero) if zero => SlotType::Zero,
+            _ => SlotType::Misc,
+        };
+    }
+}
+
+/// Stores `size` bytes of `value` at `off` from the stack pointer `ptr`. An
+/// 8-byte store to a known slot spills the register, keeping its state.
+pub fn write(
+    state: &mut State,
+    ptr: &RegState,
+    off: i16,
+    size: u32,
+    value: &RegState,
+) -> Result<(), ErrorKind> {
+    let (lo, hi) = range(ptr, off, size)?;
+    let fixed = ptr.bounds.value().is_some();
+    if fixed && size == 8 {
+        let (slot, _) = slot_mut(state, lo);
+        slot.types = [SlotType::Spill; 8];
+        slot.spilled = *value;
+        return Ok(());
+    }
+    if value.ty.is_pointer() {
+        return Err(ErrorKind::InvalidStackAccess { off: lo, size });
+    }
+    let zero = value.bounds.var_off.cast(size) == Tnum::constant(0);
+    mark(state, lo, hi, fixed, zero);
+    Ok(())
+}
+
+/// Checks that the `size` bytes a helper reads through `ptr` have all been
+/// written, as `check_stack_range_initialized` does. Helpers need no
+/// alignment, and may not read spilled pointers.
+pub fn check_init(state: &State, ptr: &RegState, size: u32) -> Result<(), ErrorKind> {
+    let (lo, hi) = extent(ptr, 0, size)?;
+    for off in lo..hi {
+        let (slot, byte) = slot(state, off);
+        match slot.types[byte] {
+            SlotType::Invalid => return Err(ErrorKind::UninitStack { off: lo, size }),
+            SlotType::Spill if slot.spilled.ty.is_pointer() => {
+                return Err(ErrorKind::InvalidStackAccess { off: lo, size })
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Marks the `size` bytes a helper fills through `ptr` as holding data.
+pub fn fill(state: &mut State, ptr: &RegState, size: u32) -> Result<(), ErrorKind> {
+    let (lo, hi) = extent(ptr, 0, size)?;
+    mark(state, lo, hi, ptr.bounds.value().is_some(), false);
+    Ok(())
+}
+
+/// Loads `size` bytes at `off` from the stack pointer `ptr`, all of which
+/// must have been written. An 8-byte load from a spilled slot restores the
+/// register.
+pub fn read(state: &State, ptr: &RegState, off: i16, size: u32) -> Result<RegState, ErrorKind> {
+    let (lo, hi) = range(ptr, off, size)?;
+    let mut zero = true;
+    for off in lo..hi {
+        let (slot, byte) = slot(state, off);
+        if slot.is_spill() {
+            if slot.spilled.ty.is_pointer() && (size != 8 || ptr.bounds.value().is_none()) {
+                return Err(ErrorKind::InvalidStackAccess { off: lo, size });
+            }
+            if size == 8 && ptr.bounds.value().is_some() {
+                return Ok(slot.spilled);
+            }
+            zero &= slot.spilled.bounds.value() == Some(0);
+            continue;
+        }
+        match slot.types[byte] {
+            SlotType::Invalid => return Err(ErrorKind::UninitStack { off: lo, size }),
+            SlotType::Zero => {}
+            _ => zero = false,
+        }
+    }
+    let value = match (zero, size) {
+        (true, _) => Bounds::constant(0),
+        (false, 8) => Bounds::UNKNOWN,
+        (false, size) => Bounds::zero_extended(size),
+    };
+    Ok(RegState::scalar(value))
+}
+
+/// Runs `f` with the frame of the caller in place of the frame of `state`.
+fn in_caller<T>(state: &mut State, f: impl FnOnce(&mut State) -> T) -> T {
+    mem::swap(&mut state.stack, &mut state.caller);
+    let ret = f(state);
+    mem::swap(&mut state.stack, &mut state.caller);
+    ret
+}
+
+/// As `read`, through a callback's context into its caller's frame. The
+/// registers the caller spilled there are only its own, so just data may be
+/// loaded.
+pub fn read_caller(
+    state: &mut State,
+    ptr: &RegState,
+    off: i16,
+    size: u32,
+) -> Result<RegState, ErrorKind> {
+    let value = in_caller(state, |state| read(state, ptr, off, size))?;
+    match value.ty.is_pointer() {
+        true => Err(ErrorKind::InvalidStackAccess {
+            off: ptr.off as i64 + off as i64,
+            size,
+        }),
+        false => Ok(value),
+    }
+}
+
+/// As `write`, through a callback's context into its caller's frame, which
+/// only takes data. Returns the offsets the store may touch, which the
+/// caller must give up what it knows about once the helper returns.
+pub fn write_caller(
+    state: &mut State,
+    ptr: &RegState,
+    off: i16,
+    size: u32,
+    value: &RegState,
+) -> Result<(i64, i64), ErrorKind> {
+    let (lo, hi) = range(ptr, off, size)?;
+    if value.ty.is_pointer() {
+        return Err(ErrorKind::InvalidStackAccess { off: lo, size });
+    }
+    in_caller(state, |state| write(state, ptr, off, size, value))?;
+    Ok((lo, hi))
+}
+
+/// Marks the bytes in `lo..hi` of the frame of `state`, or of its caller's
+/// with `caller`, as possibly overwritten with data by a callback.
+pub fn clobber(state: &mut State, (lo, hi): (i64, i64), caller: bool) {
+    match caller {
+        true => in_caller(state, |state| mark(state, lo, hi, false, false)),
+        false => mark(state, lo, hi, false, false),
+    }
+}
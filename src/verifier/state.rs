@@ -0,0 +1,476 @@
+//! Abstract register state tracked along each path, after `struct
+//! bpf_reg_state` in include/linux/bpf_verifier.h.
+
+use super::bounds::Bounds;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// What a register holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegType {
+    NotInit,
+    Scalar,
+    PtrToCtx,
+    /// Into the stack frame, based at r10.
+    PtrToStack,
+    /// Into the frame of the program that passed a callback its context,
+    /// based at that program's r10.
+    PtrToCallerStack,
+    /// A map loaded with `BPF_PSEUDO_MAP_FD`.
+    ConstMapPtr {
+        fd: u32,
+    },
+    PtrToMapValue {
+        fd: u32,
+    },
+    /// A key passed to a callback, which may only be read.
+    PtrToMapKey {
+        fd: u32,
+    },
+    /// A map value that must be compared with NULL before being used.
+    PtrToMapValueOrNull {
+        fd: u32,
+    },
+    PtrToPacket,
+    PtrToPacketEnd,
+    /// A callback loaded with `BPF_PSEUDO_FUNC`.
+    PtrToFunc {
+        pc: usize,
+    },
+}
+
+impl RegType {
+    pub fn is_pointer(self) -> bool {
+        !matches!(self, RegType::NotInit | RegType::Scalar)
+    }
+
+    /// Whether memory may be accessed through the pointer.
+    pub fn is_dereferenceable(self) -> bool {
+        matches!(
+            self,
+            RegType::PtrToCtx
+                | RegType::PtrToStack
+                | RegType::PtrToCallerStack
+                | RegType::PtrToMapValue { .. }
+                | RegType::PtrToMapKey { .. }
+                | RegType::PtrToPacket
+        )
+    }
+
+    /// Whether scalars may be added to or subtracted from the pointer.
+    pub fn allows_arithmetic(self) -> bool {
+        self.is_dereferenceable()
+    }
+
+    /// Whether pointers of the two types point into the same object, so
+    /// their difference is meaningful.
+    pub fn same_base(self, other: RegType) -> bool {
+        match (self, other) {
+            (RegType::PtrToMapValue { fd }, RegType::PtrToMapValue { fd: other })
+            | (RegType::PtrToMapKey { fd }, RegType::PtrToMapKey { fd: other }) => fd == other,
+            (RegType::PtrToPacket, RegType::PtrToPacketEnd)
+            | (RegType::PtrToPacketEnd, RegType::PtrToPacket) => true,
+            _ => self == other && self.allows_arithmetic(),
+        }
+    }
+}
+
+impl RegType {
+    /// The name of the type and what it refers to, if anything.
+    fn parts(self) -> (&'static str, Option<(&'static str, u64)>) {
+        match self {
+            RegType::NotInit => ("?", None),
+            RegType::Scalar => ("scalar", None),
+            RegType::PtrToCtx => ("ctx", None),
+            RegType::PtrToStack => ("fp", None),
+            RegType::PtrToCallerStack => ("caller_fp", None),
+            RegType::ConstMapPtr { fd } => ("map_ptr", Some(("fd", fd as u64))),
+            RegType::PtrToMapValue { fd } => ("map_value", Some(("fd", fd as u64))),
+            RegType::PtrToMapKey { fd } => ("map_key", Some(("fd", fd as u64))),
+            RegType::PtrToMapValueOrNull { fd } => ("map_value_or_null", Some(("fd", fd as u64))),
+            RegType::PtrToPacket => ("pkt", None),
+            RegType::PtrToPacketEnd => ("pkt_end", None),
+            RegType::PtrToFunc { pc } => ("func", Some(("insn", pc as u64))),
+        }
+    }
+}
+
+impl fmt::Display for RegType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.parts() {
+            (name, Some((key, value))) => write!(f, "{}({}={})", name, key, value),
+            (name, None) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegState {
+    pub ty: RegType,
+    /// Constant offset of a pointer from its base.
+    pub off: i32,
+    /// Shared by copies of a scalar, so that bounds learned for one apply
+    /// to all, or of a pointer that may be NULL, so that checking one of them
+    /// settles all. A map value with a spin lock keeps it once checked.
+    pub id: u32,
+    /// The value of a scalar, or the variable part of a pointer's offset.
+    pub bounds: Bounds,
+    /// Bytes after a packet pointer's variable part known to be within the
+    /// packet, shared by the pointers with its id.
+    pub range: u32,
+}
+
+impl RegState {
+    pub const NOT_INIT: RegState = RegState {
+        ty: RegType::NotInit,
+        off: 0,
+        id: 0,
+        bounds: Bounds::UNKNOWN,
+        range: 0,
+    };
+    pub const SCALAR: RegState = RegState::scalar(Bounds::UNKNOWN);
+
+    /// A pointer of type `ty`, or the scalar 0.
+    pub const fn new(ty: RegType) -> Self {
+        RegState {
+            ty,
+            off: 0,
+            id: 0,
+            bounds: Bounds::constant(0),
+            range: 0,
+        }
+    }
+
+    pub const fn scalar(bounds: Bounds) -> Self {
+        RegState {
+            ty: RegType::Scalar,
+            off: 0,
+            id: 0,
+            bounds,
+            range: 0,
+        }
+    }
+
+    /// Whether the register may hold anything `cur` may, as `regsafe`
+    /// decides. A register the old path never initialized was never read
+    /// there, so it may hold anything.
+    fn subsumes(&self, cur: &RegState, ids: &mut IdMap) -> bool {
+        match self.ty {
+            RegType::NotInit => true,
+            ty if ty != cur.ty => false,
+            _ => {
+                self.off == cur.off
+                    && self.range <= cur.range
+                    && self.bounds.contains(&cur.bounds)
+                    && ids.check(self.id, cur.id)
+            }
+        }
+    }
+}
+
+/// Ids met together while comparing two states, as `check_ids` keeps them.
+/// What the old path learned through a shared id only holds if the same
+/// registers share one in the new path.
+#[derive(Default)]
+struct IdMap(Vec<(u32, u32)>);
+
+impl IdMap {
+    fn check(&mut self, old: u32, cur: u32) -> bool {
+        if old == 0 {
+            return true;
+        }
+        // an unshared register may only stand for one of a shared id
+        let seen = self
+            .0
+            .iter()
+            .find(|&&(o, c)| o == old || (cur != 0 && c == cur));
+        match seen {
+            Some(&pair) => cur != 0 && pair == (old, cur),
+            None => {
+                self.0.push((old, cur));
+                true
+            }
+        }
+    }
+}
+
+/// Writes a register as the kernel's log does: a known scalar as its value,
+/// a stack pointer at a known offset as `fp-8`, and otherwise the type with
+/// whatever else is known about the register in parentheses.
+impl fmt::Display for RegState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let fixed = self.bounds.value() == Some(0);
+        match self.ty {
+            RegType::Scalar => match self.bounds.value() {
+                Some(value) => return write!(f, "{}", value as i64),
+                None => write!(f, "scalar(")?,
+            },
+            RegType::PtrToStack if fixed => return write!(f, "fp{}", self.off),
+            RegType::NotInit => return write!(f, "?"),
+            ty => write!(f, "{}(", ty.parts().0)?,
+        }
+        let mut sep = "";
+        if let (_, Some((key, value))) = self.ty.parts() {
+            write!(f, "{}={}", key, value)?;
+            sep = ",";
+        }
+        for (key, value) in [
+            ("id", self.id as i64),
+            ("off", self.off as i64),
+            ("r", self.range as i64),
+        ] {
+            if value != 0 {
+                write!(f, "{}{}={}", sep, key, value)?;
+                sep = ",";
+            }
+        }
+        if self.ty == RegType::Scalar || !fixed {
+            write!(f, "{}{}", sep, self.bounds)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// What a byte of stack holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotType {
+    Invalid,
+    Misc,
+    Zero,
+    /// Part of the register spilled to the slot.
+    Spill,
+}
+
+/// Eight bytes of stack, after `struct bpf_stack_state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackSlot {
+    pub types: [SlotType; 8],
+    /// The register saved by an 8-byte store, if `types` are `Spill`.
+    pub spilled: RegState,
+}
+
+impl StackSlot {
+    pub const INVALID: StackSlot = StackSlot {
+        types: [SlotType::Invalid; 8],
+        spilled: RegState::NOT_INIT,
+    };
+
+    pub fn is_spill(&self) -> bool {
+        self.types[0] == SlotType::Spill
+    }
+
+    /// Whether every load from the slot gives anything the same load from
+    /// `cur` may, as `stacksafe` decides.
+    fn subsumes(&self, cur: &StackSlot, ids: &mut IdMap) -> bool {
+        if self.is_spill() {
+            return cur.is_spill() && self.spilled.subsumes(&cur.spilled, ids);
+        }
+        let spilled = &cur.spilled;
+        self.types
+            .iter()
+            .zip(&cur.types)
+            .all(|(old, ty)| match old {
+                SlotType::Invalid => true,
+                SlotType::Misc => match ty {
+                    SlotType::Misc | SlotType::Zero => true,
+                    SlotType::Spill => spilled.ty == RegType::Scalar,
+                    SlotType::Invalid => false,
+                },
+                SlotType::Zero => match ty {
+                    SlotType::Zero => true,
+                    SlotType::Spill => {
+                        spilled.ty == RegType::Scalar && spilled.bounds.value() == Some(0)
+                    }
+                    _ => false,
+                },
+                SlotType::Spill => unreachable!(),
+            })
+    }
+}
+
+/// Writes a spilled register's state, or a character for each byte as the
+/// kernel's log does: `m` for data, `0` for zero and `?` for unwritten.
+impl fmt::Display for StackSlot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_spill() {
+            return write!(f, "{}", self.spilled);
+        }
+        for ty in &self.types {
+            let c = match ty {
+                SlotType::Invalid => '?',
+                SlotType::Misc => 'm',
+                SlotType::Zero => '0',
+                SlotType::Spill => 's',
+            };
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+/// State of the registers and stack at one point of one path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    pub regs: [RegState; 11],
+    /// Slot `i` holds the bytes at `-8 * (i + 1)..-8 * i` from r10, up to
+    /// the deepest one written.
+    pub stack: Vec<StackSlot>,
+    /// In a callback passed a pointer into its caller's frame, that frame's
+    /// slots, laid out as `stack`.
+    pub caller: Vec<StackSlot>,
+    /// The map and pointer id of the value whose spin lock is held, as
+    /// `active_lock` records them.
+    pub lock: Option<(u32, u32)>,
+}
+impl State {
+    /// State on entry to the program, with r1 pointing to the context, or
+    /// to a callback, with r1-r5 holding its arguments.
+    pub fn entry(args: [RegState; 5]) -> Self {
+        let mut regs = [RegState::NOT_INIT; 11];
+        regs[1..6].copy_from_slice(&args);
+        regs[10] = RegState::new(RegType::PtrToStack);
+        State {
+            regs,
+            stack: Vec::new(),
+            caller: Vec::new(),
+            lock: None,
+        }
+    }
+
+    /// Whether every path that is safe from `self` is safe from `cur`, at the
+    /// same instruction, so `cur` need not be explored, as `states_equal`
+    /// decides.
+    pub fn subsumes(&self, cur: &State) -> bool {
+        let mut ids = IdMap::default();
+        self.regs
+            .iter()
+            .zip(&cur.regs)
+            .all(|(old, cur)| old.subsumes(cur, &mut ids))
+            && [(&self.stack, &cur.stack), (&self.caller, &cur.caller)]
+                .iter()
+                .all(|(old, cur)| {
+                    old.iter().enumerate().all(|(i, old)| {
+                        let slot = cur.get(i).unwrap_or(&StackSlot::INVALID);
+                        old.subsumes(slot, &mut ids)
+                    })
+                })
+            && match (self.lock, cur.lock) {
+                (None, None) => true,
+                (Some((fd, id)), Some((cur_fd, cur_id))) => fd == cur_fd && ids.check(id, cur_id),
+                _ => false,
+            }
+    }
+}
+
+/// Writes the initialized registers and the written stack slots.
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut sep = "";
+        for (i, reg) in self.regs.iter().enumerate() {
+            if reg.ty != RegType::NotInit {
+                write!(f, "{}R{}={}", sep, i, reg)?;
+                sep = " ";
+            }
+        }
+        for (i, slot) in self.stack.iter().enumerate() {
+            if *slot != StackSlot::INVALID {
+                write!(f, "{}fp{}={}", sep, -8 * (i as i64 + 1), slot)?;
+                sep = " ";
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subsumes() {
+        let byte = RegState::scalar(Bounds::zero_extended(1));
+        let seven = RegState::scalar(Bounds::constant(7));
+        let mut old = State::entry([RegState::NOT_INIT; 5]);
+        let mut cur = old.clone();
+        old.regs[1] = byte;
+        cur.regs[1] = seven;
+        assert!(old.subsumes(&cur));
+        assert!(!cur.subsumes(&old));
+        // what the old path did not initialize it did not rely on
+        cur.regs[2] = seven;
+        assert!(old.subsumes(&cur));
+        old.regs[2] = RegState::new(RegType::PtrToCtx);
+        assert!(!old.subsumes(&cur));
+        old.regs[2] = RegState::NOT_INIT;
+
+        // copies the old path could refine together must still be copies
+        old.regs[1].id = 1;
+        old.regs[2] = old.regs[1];
+        cur.regs[2] = seven;
+        assert!(!old.subsumes(&cur));
+        cur.regs[1].id = 2;
+        cur.regs[2].id = 2;
+        assert!(old.subsumes(&cur));
+
+        let misc = StackSlot {
+            types: [SlotType::Misc; 8],
+            spilled: RegState::NOT_INIT,
+        };
+        let spill = StackSlot {
+            types: [SlotType::Spill; 8],
+            spilled: seven,
+        };
+        old.stack = vec![StackSlot::INVALID, misc];
+        cur.stack = vec![misc];
+        assert!(!old.subsumes(&cur));
+        cur.stack.push(spill);
+        assert!(old.subsumes(&cur));
+        old.stack[1] = spill;
+        cur.stack[1] = misc;
+        assert!(!old.subsumes(&cur));
+        cur.stack[1] = spill;
+
+        // a path holding a spin lock only covers one holding the same
+        old.lock = Some((0, 3));
+        assert!(!old.subsumes(&cur));
+        cur.lock = Some((1, 3));
+        assert!(!old.subsumes(&cur));
+        cur.lock = Some((0, 4));
+        assert!(old.subsumes(&cur));
+    }
+
+    #[test]
+    fn display() {
+        let mut state = State::entry([RegState::new(RegType::PtrToCtx); 5]);
+        state.regs[2] = RegState::scalar(Bounds::zero_extended(1));
+        state.regs[3] = RegState::scalar(Bounds::constant(-4i64 as u64));
+        state.regs[4] = RegState {
+            id: 2,
+            off: 8,
+            range: 14,
+            ..RegState::new(RegType::PtrToPacket)
+        };
+        state.regs[5] = RegState::new(RegType::PtrToMapValueOrNull { fd: 1 });
+        state.stack.push(StackSlot {
+            types: [SlotType::Spill; 8],
+            spilled: RegState {
+                off: -16,
+                ..RegState::new(RegType::PtrToStack)
+            },
+        });
+        let mut types = [SlotType::Misc; 8];
+        types[..4].fill(SlotType::Invalid);
+        types[7] = SlotType::Zero;
+        state.stack.push(StackSlot {
+            types,
+            spilled: RegState::NOT_INIT,
+        });
+        assert_eq!(
+            state.to_string(),
+            "R1=ctx() R2=scalar(smin=0,smax=255,umax=255,smin32=0,smax32=255,umax32=255,\
+             var_off=(0x0; 0xff)) R3=-4 \
+             R4=pkt(id=2,off=8,r=14) R5=map_value_or_null(fd=1) R10=fp0 \
+             fp-8=fp-16 fp-16=????mmm0"
+        );
+    }
+}
@@ -0,0 +1,242 @@
+//! Tristate numbers, after kernel/bpf/tnum.c: each bit is known to be 0,
+//! known to be 1, or unknown, where it is set in `mask`.
+
+use core::ops::{Add, BitAnd, BitOr, BitXor, Mul, Sub};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tnum {
+    pub value: u64,
+    pub mask: u64,
+}
+
+impl Tnum {
+    pub const UNKNOWN: Tnum = Tnum {
+        value: 0,
+        mask: u64::MAX,
+    };
+
+    pub const fn constant(value: u64) -> Self {
+        Tnum { value, mask: 0 }
+    }
+
+    /// The smallest tnum covering every value in `min..=max`.
+    pub fn range(min: u64, max: u64) -> Self {
+        let bits = 64 - (min ^ max).leading_zeros();
+        if bits > 63 {
+            return Tnum::UNKNOWN;
+        }
+        let delta = (1u64 << bits) - 1;
+        Tnum {
+            value: min & !delta,
+            mask: delta,
+        }
+    }
+
+    pub fn is_const(self) -> bool {
+        self.mask == 0
+    }
+
+    pub fn lshift(self, shift: u32) -> Self {
+        Tnum {
+            value: self.value << shift,
+            mask: self.mask << shift,
+        }
+    }
+
+    pub fn rshift(self, shift: u32) -> Self {
+        Tnum {
+            value: self.value >> shift,
+            mask: self.mask >> shift,
+        }
+    }
+
+    pub fn arshift(self, shift: u32) -> Self {
+        Tnum {
+            value: (self.value as i64 >> shift) as u64,
+            mask: (self.mask as i64 >> shift) as u64,
+        }
+    }
+
+    /// Combines two tnums known to describe the same value.
+    pub fn intersect(self, other: Tnum) -> Self {
+        let value = self.value | other.value;
+        let mu = self.mask & other.mask;
+        Tnum {
+            value: value & !mu,
+            mask: mu,
+        }
+    }
+
+    /// Whether every value `other` may hold is one `self` may hold, as
+    /// `tnum_in` decides.
+    pub fn contains(self, other: Tnum) -> bool {
+        other.mask & !self.mask == 0 && other.value & !self.mask == self.value
+    }
+
+    /// Truncates to the low `size` bytes.
+    pub fn cast(self, size: u32) -> Self {
+        let keep = match size {
+            8 => u64::MAX,
+            _ => (1u64 << (size * 8)) - 1,
+        };
+        Tnum {
+            value: self.value & keep,
+            mask: self.mask & keep,
+        }
+    }
+
+    /// The low 32 bits.
+    pub fn subreg(self) -> Self {
+        self.cast(4)
+    }
+
+    /// The high 32 bits, with the low ones known to be 0.
+    pub fn clear_subreg(self) -> Self {
+        self.rshift(32).lshift(32)
+    }
+
+    /// Sign-extends the low 32 bits.
+    pub fn sext32(self) -> Self {
+        self.lshift(32).arshift(32)
+    }
+}
+
+impl Add for Tnum {
+    type Output = Tnum;
+
+    fn add(self, other: Tnum) -> Tnum {
+        let sm = self.mask.wrapping_add(other.mask);
+        let sv = self.value.wrapping_add(other.value);
+        let sigma = sm.wrapping_add(sv);
+        let chi = sigma ^ sv;
+        let mu = chi | self.mask | other.mask;
+        Tnum {
+            value: sv & !mu,
+            mask: mu,
+        }
+    }
+}
+
+impl Sub for Tnum {
+    type Output = Tnum;
+
+    fn sub(self, other: Tnum) -> Tnum {
+        let dv = self.value.wrapping_sub(other.value);
+        let alpha = dv.wrapping_add(self.mask);
+        let beta = dv.wrapping_sub(other.mask);
+        let chi = alpha ^ beta;
+        let mu = chi | self.mask | other.mask;
+        Tnum {
+            value: dv & !mu,
+            mask: mu,
+        }
+    }
+}
+
+impl BitAnd for Tnum {
+    type Output = Tnum;
+
+    fn bitand(self, other: Tnum) -> Tnum {
+        let alpha = self.value | self.mask;
+        let beta = other.value | other.mask;
+        let value = self.value & other.value;
+        Tnum {
+            value,
+            mask: alpha & beta & !value,
+        }
+    }
+}
+
+impl BitOr for Tnum {
+    type Output = Tnum;
+
+    fn bitor(self, other: Tnum) -> Tnum {
+        let value = self.value | other.value;
+        let mu = self.mask | other.mask;
+        Tnum {
+            value,
+            mask: mu & !value,
+        }
+    }
+}
+
+impl BitXor for Tnum {
+    type Output = Tnum;
+
+    fn bitxor(self, other: Tnum) -> Tnum {
+        let value = self.value ^ other.value;
+        let mu = self.mask | other.mask;
+        Tnum {
+            value: value & !mu,
+            mask: mu,
+        }
+    }
+}
+
+impl Mul for Tnum {
+    type Output = Tnum;
+
+    /// Long multiplication, summing the partial products that have unknown
+    /// bits separately from the known ones.
+    fn mul(self, other: Tnum) -> Tnum {
+        let acc_v = self.value.wrapping_mul(other.value);
+        let mut acc_m = Tnum::constant(0);
+        let (mut a, mut b) = (self, other);
+        while a.value != 0 || a.mask != 0 {
+            if a.value & 1 != 0 {
+                acc_m = acc_m
+                    + Tnum {
+                        value: 0,
+                        mask: b.mask,
+                    };
+            } else if a.mask & 1 != 0 {
+                acc_m = acc_m
+                    + Tnum {
+                        value: 0,
+                        mask: b.value | b.mask,
+                    };
+            }
+            a = a.rshift(1);
+            b = b.lshift(1);
+        }
+        Tnum::constant(acc_v) + acc_m
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn arithmetic() {
+        let c = Tnum::constant;
+        assert_eq!(c(3) + c(4), c(7));
+        assert_eq!(c(3) - c(4), c(u64::MAX));
+        assert_eq!(c(6) * c(7), c(42));
+        assert_eq!(Tnum::range(0, 7), Tnum { value: 0, mask: 7 });
+        assert_eq!(Tnum::range(8, 9), Tnum { value: 8, mask: 1 });
+        assert_eq!(Tnum::range(0, u64::MAX), Tnum::UNKNOWN);
+
+        // x in {0, 1} plus 1 is in {1, 2}, which shares no known bit
+        let bit = Tnum { value: 0, mask: 1 };
+        assert_eq!(bit + c(1), Tnum { value: 0, mask: 3 });
+        assert_eq!(bit * c(4), Tnum { value: 0, mask: 4 });
+        assert_eq!(
+            Tnum::UNKNOWN & c(0xff),
+            Tnum {
+                value: 0,
+                mask: 0xff
+            }
+        );
+        assert_eq!(bit | c(2), Tnum { value: 2, mask: 1 });
+        assert_eq!(bit ^ c(3), Tnum { value: 2, mask: 1 });
+        assert_eq!(Tnum::UNKNOWN.intersect(c(5)), c(5));
+        assert!(Tnum::UNKNOWN.contains(bit));
+        assert!(bit.contains(c(1)));
+        assert!(!bit.contains(c(2)));
+        assert!(!c(1).contains(bit));
+        assert_eq!(c(0x8000_0000).sext32(), c(0xffff_ffff_8000_0000));
+        assert_eq!(c(0x1_0000_0001).subreg(), c(1));
+        assert_eq!(c(0x1_0000_0001).clear_subreg(), c(0x1_0000_0000));
+    }
+}
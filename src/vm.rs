@@ -0,0 +1,76 @@
+use crate::helpers::printk::TraceSink;
+use crate::helpers::probe::MemoryProbe;
+use crate::helpers::services::HostServices;
+use crate::helpers::stack::Unwinder;
+use crate::helpers::task::CurrentTask;
+use crate::helpers::timer::{TimerHost, Timers};
+use crate::map::Map;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::any::Any;
+
+/// State of a program run that helpers can access.
+#[derive(Default)]
+pub struct Vm {
+    /// Map table, indexed by the fd of `BPF_PSEUDO_MAP_FD` loads.
+    pub maps: Vec<Box<dyn Map>>,
+    pub unwinder: Option<Box<dyn Unwinder>>,
+    pub trace: Option<Box<dyn TraceSink>>,
+    pub services: Option<Box<dyn HostServices>>,
+    pub task: Option<Box<dyn CurrentTask>>,
+    pub probe: Option<Box<dyn MemoryProbe>>,
+    pub timer_host: Option<Box<dyn TimerHost>>,
+    /// Timers initialized by `bpf_timer_init`.
+    pub timers: Timers,
+    /// Arbitrary host state for custom helpers.
+    pub host: Option<Box<dyn Any>>,
+}
+
+impl Vm {
+    /// Returns the value a `BPF_PSEUDO_MAP_FD` load of `fd` puts in its
+    /// register: the address of the map, standing in for the kernel's
+    /// `struct bpf_map *`. It stays the same as maps are added to or removed
+    /// from the table.
+    pub fn map_handle(&self, fd: u32) -> Option<u64> {
+        self.maps.get(fd as usize).map(|map| handle(&**map))
+    }
+
+    pub fn map(&mut self, handle: u64) -> Option<&mut (dyn Map + 'static)> {
+        let map = self
+            .maps
+            .iter_mut()
+            .find(|map| self::handle(&***map) == handle)?;
+        Some(&mut **map)
+    }
+
+    /// Frees map elements deleted while programs ran, once none is running,
+    /// along with their timers and those of maps no longer in the table.
+    pub fn reclaim(&mut self) {
+        let Vm {
+            maps,
+            timers,
+            timer_host,
+            ..
+        } = self;
+        for map in maps.iter_mut() {
+            let timer_off = map.def().timer_off;
+            map.reclaim(&mut |value| {
+                if let Some(off) = timer_off {
+                    let addr = value[off as usize..].as_ptr() as u64;
+                    unsafe { timers.free(addr, timer_host.as_deref_mut()) };
+                }
+            });
+        }
+        timers.retain(timer_host.as_deref_mut(), |map| {
+            maps.iter().any(|live| handle(&**live) == map)
+        });
+    }
+
+    pub fn host_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.host.as_mut()?.downcast_mut()
+    }
+}
+
+fn handle(map: &dyn Map) -> u64 {
+    map as *const dyn Map as *const u8 as u64
+}